@@ -0,0 +1,64 @@
+#import bevy_pbr::mesh_view_bind_group
+#import bevy_pbr::mesh_struct
+
+struct TerrainMaterial {
+    base_color: vec4<f32>;
+};
+[[group(1), binding(0)]]
+var<uniform> material: TerrainMaterial;
+[[group(1), binding(1)]]
+var base_color_texture: texture_2d<f32>;
+[[group(1), binding(2)]]
+var base_color_sampler: sampler;
+
+[[group(2), binding(0)]]
+var<uniform> mesh: Mesh;
+
+struct Vertex {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] normal: vec3<f32>;
+    [[location(2)]] uv: vec2<f32>;
+    // ambient occlusion and voxel light baked by the mesher
+    [[location(3)]] light: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] world_position: vec4<f32>;
+    [[location(1)]] world_normal: vec3<f32>;
+    [[location(2)]] uv: vec2<f32>;
+    [[location(3)]] light: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.world_position = mesh.model * vec4<f32>(vertex.position, 1.0);
+    out.world_normal = mat3x3<f32>(
+        mesh.inverse_transpose_model[0].xyz,
+        mesh.inverse_transpose_model[1].xyz,
+        mesh.inverse_transpose_model[2].xyz
+    ) * vertex.normal;
+    out.uv = vertex.uv;
+    out.light = vertex.light;
+    out.clip_position = view.view_proj * out.world_position;
+    return out;
+}
+
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let base = material.base_color * textureSample(base_color_texture, base_color_sampler, in.uv);
+
+    // diffuse only: the ambient light plus the sun, if there is one
+    var incoming = lights.ambient_color.rgb;
+    if (lights.n_directional_lights > 0u) {
+        let sun = lights.directional_lights[0];
+        let n_dot_l = max(dot(normalize(in.world_normal), sun.direction_to_light), 0.0);
+        incoming = incoming + sun.color.rgb * n_dot_l / 3.14159265359;
+    }
+    let color = base.rgb * in.light.rgb * incoming;
+
+    // reinhard on luminance, like the standard material
+    let luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    return vec4<f32>(color / (1.0 + luminance), base.a);
+}
@@ -0,0 +1,81 @@
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use voxel_terrain::{greedy_mesh, surface_nets_mesh, ChunkVoxelData, VoxelType};
+
+// same dimensions as the default layout: 50 voxel half-length, 50 high, 1 voxel padding
+fn chunk() -> (RuntimeShape<u32, 3>, Vec<f32>, Vec<VoxelType>) {
+  let shape = RuntimeShape::<u32, 3>::new([103, 52, 103]);
+  let mut sdf = Vec::with_capacity(shape.usize());
+  let mut materials = Vec::with_capacity(shape.usize());
+  for i in 0..shape.size() {
+    let [x, y, z] = shape.delinearize(i);
+    let height = 20.0 + (x as f32 * 0.1).sin() * 6.0 + (z as f32 * 0.07).cos() * 4.0;
+    let value = y as f32 - height;
+    sdf.push(value);
+    materials.push(if value <= 0.0 {
+      VoxelType::Dirt
+    } else {
+      VoxelType::Air
+    });
+  }
+  (shape, sdf, materials)
+}
+
+fn compression(c: &mut Criterion) {
+  let (shape, sdf, materials) = chunk();
+  let compressed = ChunkVoxelData::new(&sdf, &materials);
+
+  let dense_bytes =
+    sdf.len() * std::mem::size_of::<f32>() + materials.len() * std::mem::size_of::<VoxelType>();
+  println!(
+    "memory per chunk: dense {} bytes, compressed {} bytes ({:.1}x)",
+    dense_bytes,
+    compressed.memory_footprint(),
+    dense_bytes as f32 / compressed.memory_footprint() as f32
+  );
+
+  c.bench_function("compress", |b| {
+    b.iter(|| ChunkVoxelData::new(black_box(&sdf), black_box(&materials)))
+  });
+
+  c.bench_function("random access", |b| {
+    b.iter(|| {
+      let mut sum = 0.0;
+      for i in (0..compressed.len()).step_by(97) {
+        sum += compressed.sdf(black_box(i));
+      }
+      sum
+    })
+  });
+
+  let mut group = c.benchmark_group("greedy mesh");
+  group.sample_size(20);
+  group.bench_function("dense", |b| {
+    b.iter(|| greedy_mesh(black_box(&materials), &shape))
+  });
+  group.bench_function("compressed", |b| {
+    b.iter(|| {
+      let mut v = Vec::new();
+      compressed.decompress_materials(&mut v);
+      greedy_mesh(&v, &shape)
+    })
+  });
+  group.finish();
+
+  let mut group = c.benchmark_group("surface nets mesh");
+  group.sample_size(20);
+  group.bench_function("dense", |b| {
+    b.iter(|| surface_nets_mesh(black_box(&sdf), &shape))
+  });
+  group.bench_function("compressed", |b| {
+    b.iter(|| {
+      let mut v = Vec::new();
+      compressed.decompress_sdf(&mut v);
+      surface_nets_mesh(&v, &shape)
+    })
+  });
+  group.finish();
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use voxel_terrain::{greedy_mesh, surface_nets_mesh, ChunkVoxelData, MesherScratch, VoxelType};
+
+// a handful of different chunks with the default layout dimensions so the buffers see some variety
+fn chunks() -> (RuntimeShape<u32, 3>, Vec<ChunkVoxelData>) {
+  let shape = RuntimeShape::<u32, 3>::new([103, 52, 103]);
+  let chunks = (0..8)
+    .map(|c| {
+      let mut sdf = Vec::with_capacity(shape.usize());
+      let mut materials = Vec::with_capacity(shape.usize());
+      for i in 0..shape.size() {
+        let [x, y, z] = shape.delinearize(i);
+        let (x, z) = ((x + c * 103) as f32, z as f32);
+        let height = 20.0 + (x * 0.1).sin() * 6.0 + (z * 0.07).cos() * 4.0;
+        let value = y as f32 - height;
+        sdf.push(value);
+        materials.push(if value <= 0.0 {
+          VoxelType::Dirt
+        } else {
+          VoxelType::Air
+        });
+      }
+      ChunkVoxelData::new(&sdf, &materials)
+    })
+    .collect();
+  (shape, chunks)
+}
+
+fn meshing(c: &mut Criterion) {
+  let (shape, chunks) = chunks();
+
+  let mut group = c.benchmark_group("meshing");
+  group.sample_size(20);
+  group.throughput(Throughput::Elements(chunks.len() as u64));
+
+  // before: every chunk allocates its own dense copy and mesher buffers
+  group.bench_function(BenchmarkId::new("greedy", "fresh buffers"), |b| {
+    b.iter(|| {
+      for chunk in chunks.iter() {
+        let mut v = Vec::new();
+        chunk.decompress_materials(&mut v);
+        greedy_mesh(&v, &shape);
+      }
+    })
+  });
+  group.bench_function(BenchmarkId::new("greedy", "scratch buffers"), |b| {
+    b.iter(|| {
+      MesherScratch::with(|scratch| {
+        for chunk in chunks.iter() {
+          scratch.greedy_mesh(chunk, &shape);
+        }
+      })
+    })
+  });
+
+  group.bench_function(BenchmarkId::new("surface nets", "fresh buffers"), |b| {
+    b.iter(|| {
+      for chunk in chunks.iter() {
+        let mut v = Vec::new();
+        chunk.decompress_sdf(&mut v);
+        surface_nets_mesh(&v, &shape);
+      }
+    })
+  });
+  group.bench_function(BenchmarkId::new("surface nets", "scratch buffers"), |b| {
+    b.iter(|| {
+      MesherScratch::with(|scratch| {
+        for chunk in chunks.iter() {
+          scratch.surface_nets_mesh(chunk, &shape);
+        }
+      })
+    })
+  });
+  group.finish();
+}
+
+criterion_group!(benches, meshing);
+criterion_main!(benches);
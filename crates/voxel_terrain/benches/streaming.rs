@@ -0,0 +1,72 @@
+use bevy::prelude::*;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use voxel_terrain::{
+  testing::StreamingHarness, CubicVoxelLayout, LayoutSettings, MeshStyle, VoxelTerrainSettings,
+};
+
+// cargo bench --features testing --bench streaming
+fn settings(mesh_style: MeshStyle) -> VoxelTerrainSettings {
+  VoxelTerrainSettings {
+    layout: LayoutSettings {
+      chunk_voxel_length: 16,
+      chunk_voxel_height: 48,
+      ..Default::default()
+    },
+    load_radius: 3,
+    unload_radius: 5,
+    floating_origin_threshold: None,
+    mesh_style,
+    ..Default::default()
+  }
+}
+
+fn streaming(c: &mut Criterion) {
+  let mut group = c.benchmark_group("streaming");
+  group.sample_size(10);
+
+  for (name, mesh_style) in [("blocky", MeshStyle::Blocky), ("smooth", MeshStyle::Smooth)] {
+    // every chunk around a fresh spawner, from spawning to meshed
+    let chunks = (2 * 3 + 1) * (2 * 3 + 1);
+    group.throughput(Throughput::Elements(chunks));
+    group.bench_function(BenchmarkId::new("initial load", name), |b| {
+      b.iter_batched(
+        || StreamingHarness::<CubicVoxelLayout>::new(settings(mesh_style)).without_checks(),
+        |mut harness| {
+          harness
+            .run_until_meshed(100_000)
+            .expect("chunks never finished")
+        },
+        BatchSize::PerIteration,
+      )
+    });
+
+    // walking in a straight line, a new row of chunks every time a chunk border is crossed
+    let steps = 8;
+    group.throughput(Throughput::Elements(steps * (2 * 3 + 1)));
+    group.bench_function(BenchmarkId::new("walking", name), |b| {
+      b.iter_batched(
+        || {
+          let mut harness =
+            StreamingHarness::<CubicVoxelLayout>::new(settings(mesh_style)).without_checks();
+          harness
+            .run_until_meshed(100_000)
+            .expect("chunks never finished");
+          harness
+        },
+        |mut harness| {
+          for step in 1..=steps {
+            harness.move_spawner(Vec3::new(step as f32 * 33.0, 0.0, 0.0));
+            harness
+              .run_until_meshed(100_000)
+              .expect("chunks never finished");
+          }
+        },
+        BatchSize::PerIteration,
+      )
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, streaming);
+criterion_main!(benches);
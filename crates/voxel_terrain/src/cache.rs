@@ -0,0 +1,107 @@
+use std::{
+  collections::HashMap,
+  hash::Hash,
+  sync::{Arc, Mutex},
+};
+
+// Values computed for a region of the world, shared between the generation tasks of every chunk
+// in it. When full, the region that was used the longest time ago is dropped, so the regions
+// around the spawners stay cached while the spawners move.
+pub struct LruCache<K, V> {
+  regions: Arc<Mutex<Regions<K, V>>>,
+}
+
+struct Regions<K, V> {
+  values: HashMap<K, (Arc<V>, u64)>,
+  clock: u64,
+}
+
+impl<K, V> Clone for LruCache<K, V> {
+  fn clone(&self) -> Self {
+    Self {
+      regions: self.regions.clone(),
+    }
+  }
+}
+
+impl<K, V> Default for LruCache<K, V> {
+  fn default() -> Self {
+    Self {
+      regions: Arc::new(Mutex::new(Regions {
+        values: HashMap::new(),
+        clock: 0,
+      })),
+    }
+  }
+}
+
+impl<K: Copy + Eq + Hash, V> LruCache<K, V> {
+  pub fn get_or_insert_with(&self, key: K, capacity: usize, f: impl FnOnce() -> V) -> Arc<V> {
+    if let Some(value) = self.regions.lock().unwrap().get(&key) {
+      return value;
+    }
+
+    // compute without holding the lock, two tasks racing for the same region get the same value
+    let value = Arc::new(f());
+    let mut regions = self.regions.lock().unwrap();
+    if let Some(value) = regions.get(&key) {
+      return value;
+    }
+    while !regions.values.is_empty() && regions.values.len() >= capacity.max(1) {
+      regions.evict_oldest();
+    }
+    regions.clock += 1;
+    let clock = regions.clock;
+    regions.values.insert(key, (value.clone(), clock));
+    value
+  }
+}
+
+impl<K: Copy + Eq + Hash, V> Regions<K, V> {
+  fn get(&mut self, key: &K) -> Option<Arc<V>> {
+    self.clock += 1;
+    let clock = self.clock;
+    self.values.get_mut(key).map(|(value, used)| {
+      *used = clock;
+      value.clone()
+    })
+  }
+
+  // linear in the number of regions, which is small next to the cost of computing one
+  fn evict_oldest(&mut self) {
+    let oldest = self
+      .values
+      .iter()
+      .min_by_key(|(_, (_, used))| *used)
+      .map(|(key, _)| *key);
+    if let Some(key) = oldest {
+      self.values.remove(&key);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cache_should_drop_the_least_recently_used_region() {
+    let cache = LruCache::default();
+    for key in 0..3 {
+      cache.get_or_insert_with(key, 3, || key);
+    }
+    // touch the oldest so the second one is dropped instead
+    assert_eq!(*cache.get_or_insert_with(0, 3, || unreachable!()), 0);
+    cache.get_or_insert_with(3, 3, || 3);
+    let mut keys: Vec<_> = cache
+      .regions
+      .lock()
+      .unwrap()
+      .values
+      .keys()
+      .copied()
+      .collect();
+    keys.sort_unstable();
+    assert_eq!(keys, vec![0, 2, 3]);
+  }
+}
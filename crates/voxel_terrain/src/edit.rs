@@ -0,0 +1,119 @@
+use super::{
+  generator::VoxelType,
+  light::{self, ChunkLight},
+  mesher::{MeshBuffers, WaterMesh},
+  navigation::{ChunkNavigable, NavChunk},
+  navmesh::{ChunkNavMeshed, NavMeshTile},
+  padding,
+  visibility::ChunkConnectivity,
+  vox::placed_sdf,
+  ChunkId, ChunkLayout, ChunkMeshed, ChunkTracker, ChunkVoxelData, VoxelId, VoxelTerrainSettings,
+};
+use bevy::{
+  ecs::system::EntityCommands,
+  prelude::*,
+  tasks::{AsyncComputeTaskPool, Task},
+};
+use std::collections::{HashMap, HashSet};
+
+/// Sets a single voxel, relighting and remeshing the chunk it belongs to and the neighbors that
+/// have it in their padding.
+///
+/// Edits to chunks that aren't loaded or haven't finished generating are dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelEdit {
+  pub voxel: VoxelId,
+  pub material: VoxelType,
+}
+
+pub fn apply_voxel_edits<L: ChunkLayout>(
+  mut commands: Commands,
+  mut edits: EventReader<VoxelEdit>,
+  layout: Res<L>,
+  thread_pool: Res<AsyncComputeTaskPool>,
+  settings: Res<VoxelTerrainSettings>,
+  tracker: Res<ChunkTracker>,
+  mut chunks: Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
+) {
+  let shape = layout.shape();
+  let mut edited = HashSet::new();
+  let mut relit = HashSet::new();
+  for edit in edits.iter() {
+    // the chunk the voxel belongs to has to be there, the neighbors with the voxel in their padding
+    // follow along
+    let owner = layout.voxel_to_chunk(&edit.voxel);
+    let containing = padding::chunks_containing(&*layout, &edit.voxel);
+    let owner_loaded = containing.first().map(|(chunk, _)| *chunk) == Some(owner)
+      && tracker
+        .entity(&owner)
+        .map_or(false, |entity| chunks.get(entity).is_ok());
+    if !owner_loaded {
+      continue;
+    }
+
+    for (chunk, i) in containing {
+      let entity = match tracker.entity(&chunk) {
+        Some(entity) => entity,
+        None => continue,
+      };
+      if let Ok((mut data, light)) = chunks.get_mut(entity) {
+        data.set(i, placed_sdf(edit.material), edit.material);
+        if let Some(mut light) = light {
+          light.update(&data, shape, i);
+          relit.insert(chunk);
+        }
+        edited.insert(entity);
+      }
+    }
+  }
+  // light reaching into the neighbors
+  edited.extend(light::sync_light(
+    &*layout,
+    &tracker,
+    &mut HashMap::new(),
+    &mut chunks,
+    relit,
+  ));
+
+  for entity in edited {
+    let (data, light) = chunks.get(entity).expect("edited chunks are still there");
+    let id = tracker.chunk(entity).expect("edited chunks are tracked");
+    let mut chunk = commands.entity(entity);
+    invalidate_chunk(
+      &mut chunk,
+      &id,
+      data,
+      light.is_some(),
+      &settings,
+      &thread_pool,
+      &*layout,
+    );
+  }
+}
+
+// drops everything built from the chunk's voxel data so it gets built again
+pub(crate) fn invalidate_chunk<L: ChunkLayout>(
+  chunk: &mut EntityCommands,
+  id: &ChunkId,
+  data: &ChunkVoxelData,
+  has_light: bool,
+  settings: &VoxelTerrainSettings,
+  thread_pool: &Res<AsyncComputeTaskPool>,
+  layout: &L,
+) {
+  // dropping the mesh tasks cancels them, the chunk gets meshed again from the edited data
+  chunk
+    .remove::<Task<MeshBuffers>>()
+    .remove::<Task<WaterMesh>>()
+    .remove::<ChunkMeshed>()
+    .remove::<ChunkConnectivity>()
+    .remove::<Task<ChunkConnectivity>>()
+    .remove::<ChunkNavigable>()
+    .remove::<Task<NavChunk>>()
+    .remove::<ChunkNavMeshed>()
+    .remove::<Task<NavMeshTile>>();
+  // the light being computed is already out of date
+  if settings.lighting && !has_light {
+    chunk.insert(light::generate_light(thread_pool, data, layout, *id));
+  }
+}
@@ -0,0 +1,472 @@
+use super::{cache::LruCache, heightmap::Heightmap, settings::SettingsError};
+use bevy::prelude::*;
+use std::{
+  collections::{hash_map::DefaultHasher, HashMap},
+  hash::{Hash, Hasher},
+  sync::Arc,
+};
+
+// Erosion needs to see past the chunk it is generating for, otherwise rivers stop at chunk borders.
+// The world is split into square regions which are eroded independently with an extra border on
+// each side. Regions overlap by `2 * border` columns and the eroded heights are cross-faded in the
+// overlap, so every column resolves to the same height no matter which chunk asks for it.
+#[derive(Clone, Debug)]
+pub struct ErosionSettings {
+  pub region_size: u32,
+  pub border: u32,
+  // hydraulic erosion
+  pub droplets_per_column: f32,
+  pub droplet_lifetime: u32,
+  pub inertia: f32,
+  pub sediment_capacity: f32,
+  pub min_sediment_capacity: f32,
+  pub erode_speed: f32,
+  pub deposit_speed: f32,
+  pub evaporate_speed: f32,
+  pub gravity: f32,
+  // thermal erosion
+  pub thermal_iterations: u32,
+  pub talus: f32,
+  pub thermal_rate: f32,
+  // eroded regions kept around for chunks that need them later, the least recently used region
+  // is dropped first
+  pub max_cached_regions: usize,
+}
+impl Default for ErosionSettings {
+  fn default() -> Self {
+    Self {
+      region_size: 256,
+      border: 32,
+      droplets_per_column: 0.5,
+      droplet_lifetime: 48,
+      inertia: 0.05,
+      sediment_capacity: 4.0,
+      min_sediment_capacity: 0.01,
+      erode_speed: 0.3,
+      deposit_speed: 0.3,
+      evaporate_speed: 0.02,
+      gravity: 4.0,
+      thermal_iterations: 20,
+      talus: 0.8,
+      thermal_rate: 0.5,
+      max_cached_regions: 16,
+    }
+  }
+}
+impl ErosionSettings {
+  pub fn validate(&self) -> Result<(), SettingsError> {
+    // regions are scaled to i32 coordinates and eroded with a margin of two borders on each side
+    if self.region_size == 0 || self.region_size > i32::MAX as u32 / 4 {
+      return Err(SettingsError::ErosionRegionSize(self.region_size));
+    }
+    // the overlaps on both sides of a region must not meet, or the weights stop summing to one
+    if self.border > self.region_size / 2 {
+      return Err(SettingsError::ErosionBorder(self.border));
+    }
+    Ok(())
+  }
+
+  // identifies the settings in cache keys, regions eroded with other settings can't be reused
+  fn fingerprint(&self) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (self.region_size, self.border, self.droplet_lifetime).hash(&mut hasher);
+    for value in [
+      self.droplets_per_column,
+      self.inertia,
+      self.sediment_capacity,
+      self.min_sediment_capacity,
+      self.erode_speed,
+      self.deposit_speed,
+      self.evaporate_speed,
+      self.gravity,
+      self.talus,
+      self.thermal_rate,
+    ] {
+      value.to_bits().hash(&mut hasher);
+    }
+    self.thermal_iterations.hash(&mut hasher);
+    hasher.finish()
+  }
+}
+
+// eroded regions by seed, settings fingerprint and region coordinates, shared by the generation
+// tasks. The seed and settings are public on the generator and can change between chunks
+pub type RegionCache = LruCache<(u32, u64, i32, i32), Heightmap>;
+
+pub struct Eroder<'a> {
+  pub seed: u32,
+  pub settings: &'a ErosionSettings,
+  pub cache: &'a RegionCache,
+}
+impl<'a> Eroder<'a> {
+  // eroded heights for a rectangle of columns. `height` samples the un-eroded terrain
+  pub fn heightmap(
+    &self,
+    min_x: i32,
+    min_z: i32,
+    width: u32,
+    depth: u32,
+    height: &impl Fn(i32, i32) -> f32,
+  ) -> Heightmap {
+    let fingerprint = self.settings.fingerprint();
+    let mut regions = HashMap::new();
+
+    Heightmap::from_fn(min_x, min_z, width, depth, |x, z| {
+      self.blend(x, z, |rx, rz| {
+        regions
+          .entry((rx, rz))
+          .or_insert_with(|| self.region(fingerprint, rx, rz, height))
+          .clone()
+      })
+    })
+  }
+
+  // eroded height of a single column, for callers that only look at a few columns
+  pub fn height(&self, x: i32, z: i32, height: &impl Fn(i32, i32) -> f32) -> f32 {
+    let fingerprint = self.settings.fingerprint();
+    self.blend(x, z, |rx, rz| self.region(fingerprint, rx, rz, height))
+  }
+
+  // cross-fades the regions covering a column, `region` looks them up by region coordinates
+  fn blend(&self, x: i32, z: i32, mut region: impl FnMut(i32, i32) -> Arc<Heightmap>) -> f32 {
+    let size = self.settings.region_size as i32;
+    let border = self.settings.border as i32;
+
+    // a column is covered by at most two regions along each axis
+    let (rx0, rx1) = ((x - border).div_euclid(size), (x + border).div_euclid(size));
+    let (rz0, rz1) = ((z - border).div_euclid(size), (z + border).div_euclid(size));
+
+    let mut total = 0.0;
+    for rx in rx0..=rx1 {
+      for rz in rz0..=rz1 {
+        let weight = self.region_weight(rx, x) * self.region_weight(rz, z);
+        if weight <= 0.0 {
+          continue;
+        }
+        total += weight * region(rx, rz).get(x, z);
+      }
+    }
+    total
+  }
+
+  // weight of region `r` for a column along one axis. Ramps linearly across the overlap so that
+  // the weights of neighboring regions always sum to one
+  fn region_weight(&self, r: i32, v: i32) -> f32 {
+    let size = self.settings.region_size as i32;
+    let border = self.settings.border as f32;
+    let start = (r * size) as f32;
+    let end = ((r + 1) * size) as f32;
+    let v = v as f32 + 0.5;
+
+    let ramp_in = ((v - start + border) / (2.0 * border)).clamp(0.0, 1.0);
+    let ramp_out = ((end + border - v) / (2.0 * border)).clamp(0.0, 1.0);
+    ramp_in.min(ramp_out)
+  }
+
+  fn region(
+    &self,
+    fingerprint: u64,
+    rx: i32,
+    rz: i32,
+    height: &impl Fn(i32, i32) -> f32,
+  ) -> Arc<Heightmap> {
+    let key = (self.seed, fingerprint, rx, rz);
+    self
+      .cache
+      .get_or_insert_with(key, self.settings.max_cached_regions, || {
+        // erode a window wider than the blended area so that the map edges, where droplets
+        // leave and talus can't settle, are never visible
+        let size = self.settings.region_size;
+        let margin = self.settings.border * 2;
+        let mut heightmap = Heightmap::from_fn(
+          rx * size as i32 - margin as i32,
+          rz * size as i32 - margin as i32,
+          size + margin * 2,
+          size + margin * 2,
+          height,
+        );
+        let mut rng = Rng::new(self.seed, rx, rz);
+        hydraulic_erosion(&mut heightmap, self.settings, &mut rng);
+        thermal_erosion(&mut heightmap, self.settings);
+        heightmap
+      })
+  }
+}
+
+// simulates water droplets running downhill, picking up sediment on steep slopes and depositing
+// it when they slow down
+pub fn hydraulic_erosion(heightmap: &mut Heightmap, settings: &ErosionSettings, rng: &mut Rng) {
+  let max_x = (heightmap.width - 1) as f32;
+  let max_z = (heightmap.depth - 1) as f32;
+  let droplets = (heightmap.heights.len() as f32 * settings.droplets_per_column) as u32;
+
+  for _ in 0..droplets {
+    let mut pos = Vec2::new(rng.next_f32() * max_x, rng.next_f32() * max_z);
+    let mut dir = Vec2::ZERO;
+    let mut speed = 1.0;
+    let mut water = 1.0;
+    let mut sediment = 0.0;
+
+    for _ in 0..settings.droplet_lifetime {
+      let height = heightmap.sample_local(pos.x, pos.y);
+      let gradient = heightmap.gradient_local(pos.x, pos.y);
+
+      dir = dir * settings.inertia - gradient * (1.0 - settings.inertia);
+      if dir.length_squared() < f32::EPSILON {
+        break;
+      }
+      dir = dir.normalize();
+
+      let old_pos = pos;
+      pos += dir;
+      if pos.x < 0.0 || pos.y < 0.0 || pos.x >= max_x || pos.y >= max_z {
+        break;
+      }
+
+      let delta = heightmap.sample_local(pos.x, pos.y) - height;
+      let capacity =
+        (-delta * speed * water * settings.sediment_capacity).max(settings.min_sediment_capacity);
+
+      if sediment > capacity || delta > 0.0 {
+        // fill the pit we just left, or drop what we can't carry anymore
+        let deposit = if delta > 0.0 {
+          delta.min(sediment)
+        } else {
+          (sediment - capacity) * settings.deposit_speed
+        };
+        sediment -= deposit;
+        add_bilinear(heightmap, old_pos, deposit);
+      } else {
+        let erode = ((capacity - sediment) * settings.erode_speed).min(-delta);
+        sediment += erode;
+        add_bilinear(heightmap, old_pos, -erode);
+      }
+
+      speed = (speed * speed - delta * settings.gravity).max(0.0).sqrt();
+      water *= 1.0 - settings.evaporate_speed;
+    }
+  }
+}
+
+// moves material from columns that are steeper than the talus angle down to their neighbors
+pub fn thermal_erosion(heightmap: &mut Heightmap, settings: &ErosionSettings) {
+  let (width, depth) = (heightmap.width, heightmap.depth);
+  let mut delta = vec![0.0; heightmap.heights.len()];
+
+  for _ in 0..settings.thermal_iterations {
+    delta.fill(0.0);
+
+    for z in 0..depth {
+      for x in 0..width {
+        let i = heightmap.local_index(x, z);
+        let h = heightmap.heights[i];
+        // each pair is only visited once, from the cell with the lower index
+        for (nx, nz) in [(x + 1, z), (x, z + 1)] {
+          if nx >= width || nz >= depth {
+            continue;
+          }
+          let j = heightmap.local_index(nx, nz);
+          let diff = h - heightmap.heights[j];
+          if diff.abs() > settings.talus {
+            let moved =
+              (diff.abs() - settings.talus) * settings.thermal_rate * 0.25 * diff.signum();
+            delta[i] -= moved;
+            delta[j] += moved;
+          }
+        }
+      }
+    }
+
+    for (h, d) in heightmap.heights.iter_mut().zip(delta.iter()) {
+      *h += d;
+    }
+  }
+}
+
+fn add_bilinear(heightmap: &mut Heightmap, pos: Vec2, amount: f32) {
+  let (x, z) = (pos.x.floor() as u32, pos.y.floor() as u32);
+  let (fx, fz) = (pos.x - x as f32, pos.y - z as f32);
+  for (dx, dz, w) in [
+    (0, 0, (1.0 - fx) * (1.0 - fz)),
+    (1, 0, fx * (1.0 - fz)),
+    (0, 1, (1.0 - fx) * fz),
+    (1, 1, fx * fz),
+  ] {
+    let i = heightmap.local_index(x + dx, z + dz);
+    heightmap.heights[i] += amount * w;
+  }
+}
+
+// splitmix64, good enough for droplet placement and reproducible on every platform
+pub struct Rng(u64);
+impl Rng {
+  pub fn new(seed: u32, rx: i32, rz: i32) -> Self {
+    // hash the inputs one after the other instead of packing their bits, so neighboring regions
+    // and seeds start from unrelated states
+    let mut rng = Self(seed as u64);
+    for v in [rx, rz] {
+      rng = Self(rng.next_u64() ^ v as u32 as u64);
+    }
+    Self(rng.next_u64())
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+  }
+
+  pub fn next_f32(&mut self) -> f32 {
+    (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn settings() -> ErosionSettings {
+    ErosionSettings {
+      region_size: 32,
+      border: 8,
+      thermal_iterations: 4,
+      ..Default::default()
+    }
+  }
+
+  fn height(x: i32, z: i32) -> f32 {
+    ((x as f32 * 0.3).sin() + (z as f32 * 0.2).cos()) * 10.0 + 20.0
+  }
+
+  #[test]
+  fn erosion_should_be_deterministic() {
+    let settings = settings();
+    let a = Eroder {
+      seed: 7,
+      settings: &settings,
+      cache: &RegionCache::default(),
+    }
+    .heightmap(-40, -40, 80, 80, &height);
+    let b = Eroder {
+      seed: 7,
+      settings: &settings,
+      cache: &RegionCache::default(),
+    }
+    .heightmap(-40, -40, 80, 80, &height);
+    assert_eq!(a.heights, b.heights);
+  }
+
+  #[test]
+  fn overlapping_requests_should_agree_on_shared_columns() {
+    let settings = settings();
+    let eroder = Eroder {
+      seed: 3,
+      settings: &settings,
+      cache: &RegionCache::default(),
+    };
+    let a = eroder.heightmap(-50, 0, 60, 20, &height);
+    let b = Eroder {
+      seed: 3,
+      settings: &settings,
+      cache: &RegionCache::default(),
+    }
+    .heightmap(0, 0, 60, 20, &height);
+    for z in 0..20 {
+      for x in 0..10 {
+        assert_eq!(a.get(x, z), b.get(x, z), "column {}, {}", x, z);
+      }
+    }
+  }
+
+  #[test]
+  fn cached_regions_should_not_be_shared_between_seeds_or_settings() {
+    let cache = RegionCache::default();
+    let settings = settings();
+    let eroded = |seed, settings: &ErosionSettings| {
+      Eroder {
+        seed,
+        settings,
+        cache: &cache,
+      }
+      .heightmap(0, 0, 16, 16, &height)
+    };
+    let fresh = |seed, settings: &ErosionSettings| {
+      Eroder {
+        seed,
+        settings,
+        cache: &RegionCache::default(),
+      }
+      .heightmap(0, 0, 16, 16, &height)
+    };
+
+    eroded(1, &settings);
+    assert_eq!(eroded(2, &settings).heights, fresh(2, &settings).heights);
+    let steeper = ErosionSettings {
+      talus: 0.2,
+      ..settings.clone()
+    };
+    assert_eq!(eroded(2, &steeper).heights, fresh(2, &steeper).heights);
+    // a single column comes from the same regions as a whole heightmap
+    let heightmap = eroded(2, &settings);
+    for (x, z) in [(0, 0), (7, 3), (15, 15)] {
+      let eroder = Eroder {
+        seed: 2,
+        settings: &settings,
+        cache: &cache,
+      };
+      assert_eq!(eroder.height(x, z, &height), heightmap.get(x, z));
+    }
+  }
+
+  #[test]
+  fn invalid_settings_should_be_rejected() {
+    assert!(settings().validate().is_ok());
+    let region_size = |region_size| ErosionSettings {
+      region_size,
+      ..settings()
+    };
+    assert_eq!(
+      region_size(0).validate(),
+      Err(SettingsError::ErosionRegionSize(0))
+    );
+    assert_eq!(
+      region_size(u32::MAX).validate(),
+      Err(SettingsError::ErosionRegionSize(u32::MAX))
+    );
+    assert_eq!(
+      region_size(15).validate(),
+      Err(SettingsError::ErosionBorder(8))
+    );
+    assert!(region_size(16).validate().is_ok());
+  }
+
+  #[test]
+  fn rng_should_differ_between_nearby_regions_and_seeds() {
+    let mut firsts = std::collections::HashSet::new();
+    for seed in 0..4 {
+      for rx in -4..4 {
+        for rz in -4..4 {
+          assert!(firsts.insert(Rng::new(seed, rx, rz).next_u64()));
+        }
+      }
+    }
+    // swapping the coordinates gives a different region
+    assert_ne!(Rng::new(0, 1, 2).next_u64(), Rng::new(0, 2, 1).next_u64());
+  }
+
+  #[test]
+  fn region_weights_should_sum_to_one() {
+    let settings = settings();
+    let eroder = Eroder {
+      seed: 0,
+      settings: &settings,
+      cache: &RegionCache::default(),
+    };
+    for v in -100..100 {
+      let total: f32 = (-5..5).map(|r| eroder.region_weight(r, v)).sum();
+      assert!((total - 1.0).abs() < 1e-5, "column {} total {}", v, total);
+    }
+  }
+}
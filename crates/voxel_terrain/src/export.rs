@@ -0,0 +1,430 @@
+use super::{
+  layout::ChunkLayout,
+  mesher::{MeshBuffers, ATTRIBUTE_LIGHT},
+  Chunk, ChunkId, ChunkMesh,
+};
+use bevy::{
+  ecs::system::Command,
+  prelude::*,
+  render::mesh::{Indices, VertexAttributeValues},
+};
+use std::{
+  collections::HashMap,
+  fs::File,
+  io::{self, BufWriter, Write},
+  marker::PhantomData,
+  path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+  Obj,
+  /// A single `.gltf` file with the vertex data embedded as a base64 buffer.
+  Gltf,
+}
+impl ExportFormat {
+  /// Picks the format from the file extension, `.obj` or `.gltf`. Anything else, binary `.glb`
+  /// included, is an error.
+  pub fn from_path(path: &Path) -> io::Result<Self> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some(ext) if ext.eq_ignore_ascii_case("obj") => Ok(ExportFormat::Obj),
+      Some(ext) if ext.eq_ignore_ascii_case("gltf") => Ok(ExportFormat::Gltf),
+      _ => Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("can't export to {}, use .obj or .gltf", path.display()),
+      )),
+    }
+  }
+}
+
+/// Writes the meshes of every meshed chunk, drawn or not, into a single file in the world space of
+/// the current floating origin.
+///
+/// Push it with `commands.add(..)` from any system, or call [`ExportTerrain::run`] on the
+/// `World` of a headless app to get the result back. Chunks without a surface have no mesh and
+/// are skipped, if none are left nothing is written and the export fails.
+///
+/// ```no_run
+/// use bevy::prelude::*;
+/// use voxel_terrain::{ChunkId, CubicVoxelLayout, ExportTerrain};
+///
+/// fn export(mut commands: Commands) {
+///   commands.add(
+///     ExportTerrain::<CubicVoxelLayout>::new("terrain.gltf")
+///       .with_chunks(ChunkId::new(-2, -2), ChunkId::new(2, 2))
+///       .welded(),
+///   );
+/// }
+/// ```
+pub struct ExportTerrain<L> {
+  pub path: PathBuf,
+  /// Picked from the extension of `path` when `None`, see [`ExportFormat::from_path`].
+  pub format: Option<ExportFormat>,
+  /// Corners of the box of chunks to export, inclusive on every axis. Everything when `None`.
+  pub chunks: Option<(ChunkId, ChunkId)>,
+  /// Merge vertices that share a position and normal, which stitches chunks together.
+  pub weld: bool,
+  layout: PhantomData<L>,
+}
+
+impl<L: ChunkLayout> ExportTerrain<L> {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into(),
+      format: None,
+      chunks: None,
+      weld: false,
+      layout: PhantomData,
+    }
+  }
+
+  pub fn with_chunks(mut self, min: ChunkId, max: ChunkId) -> Self {
+    self.chunks = Some((min, max));
+    self
+  }
+
+  pub fn welded(mut self) -> Self {
+    self.weld = true;
+    self
+  }
+
+  /// Exports the chunks and returns how many were written.
+  pub fn run(&self, world: &mut World) -> io::Result<usize> {
+    let format = match self.format {
+      Some(format) => format,
+      None => ExportFormat::from_path(&self.path)?,
+    };
+    // chunks that haven't been drawn yet still have their buffers, the renderer moves them into
+    // a mesh asset
+    let mut buffers = world.query::<(&Chunk, &ChunkMesh)>();
+    let mut handles = world.query::<(&Chunk, &Handle<Mesh>)>();
+    let layout = world
+      .get_resource::<L>()
+      .expect("the voxel terrain plugin should be added before exporting");
+    let assets = world.get_resource::<Assets<Mesh>>();
+
+    let mut chunks: Vec<(ChunkId, MeshBuffers)> = buffers
+      .iter(world)
+      .map(|(chunk, mesh)| (chunk.id, mesh.0.clone()))
+      .chain(handles.iter(world).filter_map(|(chunk, handle)| {
+        let mesh = assets?.get(handle)?;
+        Some((chunk.id, mesh_buffers(mesh)?))
+      }))
+      .filter(|(id, _)| self.contains(id))
+      .collect();
+    // same file for the same terrain, no matter the order chunks were loaded in
+    chunks.sort_by_key(|(id, _)| (id.layer(), id.y(), id.x()));
+
+    let mut merged = merge_meshes(chunks.iter().map(|(id, mesh)| {
+      // meshes start at the chunk's voxel data, which is where the chunk entity is placed
+      (layout.voxel_to_space(&layout.get_origin(id)), mesh)
+    }));
+    if self.weld {
+      merged = weld(&merged);
+    }
+    // don't leave an empty file behind
+    if merged.positions.is_empty() {
+      return Err(nothing_to_export());
+    }
+
+    let mut writer = BufWriter::new(File::create(&self.path)?);
+    match format {
+      ExportFormat::Obj => write_obj(&merged, &mut writer)?,
+      ExportFormat::Gltf => write_gltf(&merged, &mut writer)?,
+    }
+    writer.flush()?;
+    Ok(chunks.len())
+  }
+
+  fn contains(&self, chunk: &ChunkId) -> bool {
+    match &self.chunks {
+      Some((min, max)) => {
+        (min.x()..=max.x()).contains(&chunk.x())
+          && (min.y()..=max.y()).contains(&chunk.y())
+          && (min.layer()..=max.layer()).contains(&chunk.layer())
+      }
+      None => true,
+    }
+  }
+}
+impl<L: ChunkLayout> Command for ExportTerrain<L> {
+  fn write(self, world: &mut World) {
+    match self.run(world) {
+      Ok(count) => info!("exported {} chunks to {}", count, self.path.display()),
+      Err(error) => error!(
+        "could not export terrain to {}: {}",
+        self.path.display(),
+        error
+      ),
+    }
+  }
+}
+
+// the vertex data of a mesh asset made from MeshBuffers
+fn mesh_buffers(mesh: &Mesh) -> Option<MeshBuffers> {
+  let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+    VertexAttributeValues::Float32x3(positions) => positions.clone(),
+    _ => return None,
+  };
+  let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL)? {
+    VertexAttributeValues::Float32x3(normals) => normals.clone(),
+    _ => return None,
+  };
+  let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0)? {
+    VertexAttributeValues::Float32x2(uvs) => uvs.clone(),
+    _ => return None,
+  };
+  // meshes without baked lighting are fully lit
+  let colors = match mesh.attribute(ATTRIBUTE_LIGHT) {
+    Some(VertexAttributeValues::Float32x4(colors)) => colors.clone(),
+    _ => vec![[1.0; 4]; positions.len()],
+  };
+  let indices = match mesh.indices()? {
+    Indices::U32(indices) => indices.clone(),
+    Indices::U16(indices) => indices.iter().map(|i| *i as u32).collect(),
+  };
+  Some(MeshBuffers {
+    positions,
+    normals,
+    uvs,
+    colors,
+    indices,
+  })
+}
+
+/// Concatenates meshes into one, moving each by its offset.
+pub fn merge_meshes<'a>(meshes: impl IntoIterator<Item = (Vec3, &'a MeshBuffers)>) -> MeshBuffers {
+  let mut merged = MeshBuffers::default();
+  for (offset, mesh) in meshes {
+    let start = merged.positions.len() as u32;
+    merged.positions.extend(
+      mesh
+        .positions
+        .iter()
+        .map(|p| (Vec3::from(*p) + offset).to_array()),
+    );
+    merged.normals.extend_from_slice(&mesh.normals);
+    merged.uvs.extend_from_slice(&mesh.uvs);
+    merged.colors.extend_from_slice(&mesh.colors);
+    merged
+      .indices
+      .extend(mesh.indices.iter().map(|i| i + start));
+  }
+  merged
+}
+
+/// Merges vertices with the same position and normal, keeping the uv of the first one. Blocky
+/// faces pointing different ways keep their own vertices, so they stay flat shaded.
+pub fn weld(mesh: &MeshBuffers) -> MeshBuffers {
+  // chunks are meshed separately, so shared vertices can be off by float noise
+  let key = |v: [f32; 3]| v.map(|c| (c * 1024.0).round() as i64);
+
+  let mut welded = MeshBuffers::default();
+  let mut seen = HashMap::new();
+  let remap: Vec<u32> = (0..mesh.positions.len())
+    .map(|i| {
+      let position = mesh.positions[i];
+      let normal = mesh.normals.get(i).copied().unwrap_or_default();
+      *seen.entry((key(position), key(normal))).or_insert_with(|| {
+        welded.positions.push(position);
+        welded.normals.push(normal);
+        welded
+          .uvs
+          .push(mesh.uvs.get(i).copied().unwrap_or_default());
+        welded
+          .colors
+          .push(mesh.colors.get(i).copied().unwrap_or([1.0; 4]));
+        welded.positions.len() as u32 - 1
+      })
+    })
+    .collect();
+  welded.indices = mesh.indices.iter().map(|i| remap[*i as usize]).collect();
+  welded
+}
+
+pub fn write_obj(mesh: &MeshBuffers, writer: &mut impl Write) -> io::Result<()> {
+  writeln!(writer, "o terrain")?;
+  for [x, y, z] in &mesh.positions {
+    writeln!(writer, "v {} {} {}", x, y, z)?;
+  }
+  for [x, y, z] in &mesh.normals {
+    writeln!(writer, "vn {} {} {}", x, y, z)?;
+  }
+  for [u, v] in &mesh.uvs {
+    writeln!(writer, "vt {} {}", u, v)?;
+  }
+  // obj indices start at 1, every vertex has a position, uv and normal with the same index
+  for triangle in mesh.indices.chunks_exact(3) {
+    let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+    writeln!(writer, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", a, b, c)?;
+  }
+  Ok(())
+}
+
+fn nothing_to_export() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, "no chunk meshes to export")
+}
+
+/// Writes the mesh as a single glTF file. Empty meshes are an error, glTF accessors can't be
+/// empty.
+pub fn write_gltf(mesh: &MeshBuffers, writer: &mut impl Write) -> io::Result<()> {
+  if mesh.positions.is_empty() || mesh.indices.is_empty() {
+    return Err(nothing_to_export());
+  }
+  let mut buffer = Vec::new();
+  for vertex in mesh.positions.iter().chain(&mesh.normals) {
+    vertex.iter().for_each(|c| buffer.extend(c.to_le_bytes()));
+  }
+  for uv in &mesh.uvs {
+    uv.iter().for_each(|c| buffer.extend(c.to_le_bytes()));
+  }
+  for i in 0..mesh.positions.len() {
+    let color = mesh.colors.get(i).unwrap_or(&[1.0; 4]);
+    color.iter().for_each(|c| buffer.extend(c.to_le_bytes()));
+  }
+  for index in &mesh.indices {
+    buffer.extend(index.to_le_bytes());
+  }
+
+  let count = mesh.positions.len();
+  let (min, max) = mesh.positions.iter().fold(
+    (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+    |(min, max), p| (min.min(Vec3::from(*p)), max.max(Vec3::from(*p))),
+  );
+  // offset, length and target (vertex or index data) of each attribute in the buffer
+  let views = [
+    (0, count * 12, 34962),
+    (count * 12, count * 12, 34962),
+    (count * 24, count * 8, 34962),
+    (count * 32, count * 16, 34962),
+    (count * 48, mesh.indices.len() * 4, 34963),
+  ];
+
+  write!(
+    writer,
+    concat!(
+      r#"{{"asset":{{"version":"2.0","generator":"voxel_terrain"}},"#,
+      r#""scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0,"name":"terrain"}}],"#,
+      r#""meshes":[{{"primitives":[{{"attributes":{{"POSITION":0,"NORMAL":1,"TEXCOORD_0":2,"#,
+      r#""COLOR_0":3}},"indices":4}}]}}],"#,
+      r#""buffers":[{{"byteLength":{},"uri":"data:application/octet-stream;base64,{}"}}],"#,
+      r#""bufferViews":["#
+    ),
+    buffer.len(),
+    base64(&buffer)
+  )?;
+  for (i, (offset, length, target)) in views.iter().enumerate() {
+    let separator = if i == 0 { "" } else { "," };
+    write!(
+      writer,
+      r#"{}{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+      separator, offset, length, target
+    )?;
+  }
+  write!(
+    writer,
+    concat!(
+      r#"],"accessors":["#,
+      r#"{{"bufferView":0,"componentType":5126,"count":{0},"type":"VEC3","#,
+      r#""min":[{2},{3},{4}],"max":[{5},{6},{7}]}},"#,
+      r#"{{"bufferView":1,"componentType":5126,"count":{0},"type":"VEC3"}},"#,
+      r#"{{"bufferView":2,"componentType":5126,"count":{0},"type":"VEC2"}},"#,
+      r#"{{"bufferView":3,"componentType":5126,"count":{0},"type":"VEC4"}},"#,
+      r#"{{"bufferView":4,"componentType":5125,"count":{1},"type":"SCALAR"}}]}}"#
+    ),
+    count,
+    mesh.indices.len(),
+    min.x,
+    min.y,
+    min.z,
+    max.x,
+    max.y,
+    max.z
+  )
+}
+
+fn base64(bytes: &[u8]) -> String {
+  const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+  for group in bytes.chunks(3) {
+    let b = [
+      group[0],
+      *group.get(1).unwrap_or(&0),
+      *group.get(2).unwrap_or(&0),
+    ];
+    let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+    for i in 0..4 {
+      if i <= group.len() {
+        encoded.push(ALPHABET[(n >> (18 - i * 6)) as usize & 63] as char);
+      } else {
+        encoded.push('=');
+      }
+    }
+  }
+  encoded
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  fn quad(x: f32) -> MeshBuffers {
+    MeshBuffers {
+      positions: vec![
+        [x, 0.0, 0.0],
+        [x + 1.0, 0.0, 0.0],
+        [x, 0.0, 1.0],
+        [x + 1.0, 0.0, 1.0],
+      ],
+      normals: vec![[0.0, 1.0, 0.0]; 4],
+      uvs: vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0]],
+      colors: vec![[1.0; 4]; 4],
+      indices: vec![0, 2, 1, 1, 2, 3],
+    }
+  }
+
+  #[test]
+  fn only_obj_and_gltf_should_be_exported() {
+    let format = |path: &str| ExportFormat::from_path(Path::new(path)).ok();
+    assert_eq!(format("terrain.obj"), Some(ExportFormat::Obj));
+    assert_eq!(format("terrain.GLTF"), Some(ExportFormat::Gltf));
+    assert_eq!(format("terrain.glb"), None);
+    assert_eq!(format("terrain"), None);
+  }
+
+  #[test]
+  fn empty_meshes_should_not_be_written_as_gltf() {
+    let mut out = Vec::new();
+    assert!(write_gltf(&MeshBuffers::default(), &mut out).is_err());
+    assert!(out.is_empty());
+    assert!(write_gltf(&quad(0.0), &mut out).is_ok());
+    assert!(!out.is_empty());
+  }
+
+  #[test]
+  fn base64_should_pad_partial_groups() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+  }
+
+  proptest! {
+      #[test]
+      fn welding_should_stitch_neighboring_chunks(x in -1000i32..1000, chunks in 1usize..8) {
+          let quads: Vec<_> = (0..chunks).map(|i| quad(i as f32)).collect();
+          let merged = merge_meshes(quads.iter().map(|q| (Vec3::new(x as f32, 0.0, 0.0), q)));
+          assert_eq!(merged.positions.len(), chunks * 4);
+
+          let welded = weld(&merged);
+          // each quad after the first shares an edge with the one before it
+          assert_eq!(welded.positions.len(), chunks * 2 + 2);
+          assert_eq!(welded.indices.len(), merged.indices.len());
+          for (a, b) in welded.indices.iter().zip(&merged.indices) {
+              assert_eq!(welded.positions[*a as usize], merged.positions[*b as usize]);
+          }
+      }
+  }
+}
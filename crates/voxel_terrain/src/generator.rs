@@ -1,4 +1,13 @@
-use super::VoxelId;
+use super::{
+  erosion::{Eroder, ErosionSettings, RegionCache},
+  height_image::HeightmapImage,
+  heightmap::Heightmap,
+  planet::PlanetSettings,
+  settings::SettingsError,
+  structures::Structures,
+  vox::VoxStamp,
+  VoxelId,
+};
 use bevy::{
   prelude::*,
   tasks::{AsyncComputeTaskPool, Task},
@@ -7,27 +16,57 @@ use block_mesh::{
   ndshape::{RuntimeShape, Shape},
   MergeVoxel, Voxel, VoxelVisibility,
 };
-use noise::{utils::*, *};
+use noise::*;
 
 #[derive(Debug, PartialEq, Clone, Eq, Copy)]
 pub enum VoxelType {
   Air,
   Dirt,
+  Water,
+  // a solid block that gives off light
+  Lamp,
 }
 impl VoxelType {
   pub fn to_mat_id(&self) -> u8 {
     match self {
       VoxelType::Air => 0,
       VoxelType::Dirt => 1,
+      VoxelType::Water => 2,
+      VoxelType::Lamp => 3,
+    }
+  }
+
+  pub fn from_mat_id(id: u8) -> Option<Self> {
+    match id {
+      0 => Some(VoxelType::Air),
+      1 => Some(VoxelType::Dirt),
+      2 => Some(VoxelType::Water),
+      3 => Some(VoxelType::Lamp),
+      _ => None,
+    }
+  }
+
+  // solid voxels are inside the terrain surface and block light
+  #[inline]
+  pub fn is_solid(&self) -> bool {
+    matches!(self, VoxelType::Dirt | VoxelType::Lamp)
+  }
+
+  // block light level given off by the voxel, up to light::MAX_LIGHT
+  #[inline]
+  pub fn emission(&self) -> u8 {
+    match self {
+      VoxelType::Lamp => 14,
+      _ => 0,
     }
   }
 }
 impl Voxel for VoxelType {
   fn get_visibility(&self) -> VoxelVisibility {
-    if let VoxelType::Air = self {
-      VoxelVisibility::Empty
-    } else {
-      VoxelVisibility::Opaque
+    // water is meshed separately, the terrain mesh treats it like air
+    match self {
+      VoxelType::Air | VoxelType::Water => VoxelVisibility::Empty,
+      VoxelType::Dirt | VoxelType::Lamp => VoxelVisibility::Opaque,
     }
   }
 }
@@ -40,55 +79,189 @@ impl MergeVoxel for VoxelType {
   }
 }
 
-#[derive(Default)]
-pub struct VoxelGenerator;
+#[derive(Clone)]
+pub struct VoxelGenerator {
+  pub seed: u32,
+  // voxels that are not solid and below this height are filled with water
+  pub sea_level: f32,
+  // erosion is expensive, it is only applied when configured
+  pub erosion: Option<ErosionSettings>,
+  // heights come from an image instead of noise
+  pub height_image: Option<HeightmapImage>,
+  // generates a planet instead of an infinite heightfield, seed is the only other setting used
+  pub planet: Option<PlanetSettings>,
+  // handmade models written over the generated terrain, in order
+  pub stamps: Vec<VoxStamp>,
+  // villages, roads and anything else that spans more than one chunk
+  pub structures: Option<Structures>,
+  erosion_cache: RegionCache,
+}
+impl Default for VoxelGenerator {
+  fn default() -> Self {
+    Self::new(0, 25.0, None)
+  }
+}
 
 impl VoxelGenerator {
+  pub fn new(seed: u32, sea_level: f32, erosion: Option<ErosionSettings>) -> Self {
+    Self {
+      seed,
+      sea_level,
+      erosion,
+      height_image: None,
+      planet: None,
+      stamps: Vec::new(),
+      structures: None,
+      erosion_cache: RegionCache::default(),
+    }
+  }
+
+  /// Checks the settings of the configured erosion, if any.
+  pub fn validate(&self) -> Result<(), SettingsError> {
+    match &self.erosion {
+      Some(erosion) => erosion.validate(),
+      None => Ok(()),
+    }
+  }
+
+  pub fn with_height_image(mut self, height_image: HeightmapImage) -> Self {
+    self.height_image = Some(height_image);
+    self
+  }
+
+  pub fn with_planet(mut self, planet: PlanetSettings) -> Self {
+    self.planet = Some(planet);
+    self
+  }
+
+  pub fn with_stamp(mut self, stamp: VoxStamp) -> Self {
+    self.stamps.push(stamp);
+    self
+  }
+
+  pub fn with_structures(mut self, structures: Structures) -> Self {
+    self.structures = Some(structures);
+    self
+  }
+
   pub fn load_voxel_data(
     &self,
     thread_pool: &Res<AsyncComputeTaskPool>,
     origin: VoxelId,
     shape: RuntimeShape<u32, 3>,
   ) -> Task<super::ChunkVoxelData> {
-    thread_pool.spawn(async move {
-      let bias = 0.0;
-      let scale = [0.01, 0.01, 1.0];
-      let bg = Perlin::new();
-      // let bg_scaled = ScaleBias::new(&bg).set_bias(bias).set_scale(0.1);
-      let ridged = RidgedMulti::new()
-        .set_frequency(2.0)
-        .set_lacunarity(2.20703125)
-        .set_octaves(3);
-      let ridged_scaled = ScalePoint::new(&ridged).set_all_scales(0.5, 0.5, 1.0, 1.0);
-      let fbm = Fbm::new();
-      let t = Billow::new();
-
-      let baseContinentDef_fb0 = Fbm::new()
-        .set_frequency(1.0)
-        .set_persistence(0.5)
-        .set_lacunarity(2.208984375)
-        .set_octaves(14);
-
-      let baseContinentDef_cu = Curve::new(&baseContinentDef_fb0)
-        .add_control_point(-2.0, -2.0)
-        .add_control_point(-1.0, -1.0)
-        .add_control_point(0.0, 0.0)
-        .add_control_point(0.5, 0.01)
-        .add_control_point(1.0, 0.02)
-        .add_control_point(2.0, 0.03);
-
-      let scaled_conti = ScalePoint::new(&baseContinentDef_cu).set_all_scales(0.1, 0.1, 1.0, 1.0);
-      let generator =
-        ScalePoint::new(&scaled_conti).set_all_scales(scale[0], scale[1], scale[2], 1.0);
-
-      let mut buffer = Vec::with_capacity(shape.usize());
-      for i in 0..shape.size() {
-        let [x, y, z] = shape.delinearize(i);
-        let height = generator.get([x as f64 + origin.x() as f64, z as f64 + origin.z() as f64]);
-        let sdf = y as f32 - ((height + 1.0) * 25.) as f32;
-        buffer.push(sdf);
+    let generator = self.clone();
+    thread_pool.spawn(async move { generator.generate(origin, &shape) })
+  }
+
+  pub fn generate(&self, origin: VoxelId, shape: &RuntimeShape<u32, 3>) -> super::ChunkVoxelData {
+    let mut data = match &self.planet {
+      Some(planet) => planet.generate(self.seed, origin, shape),
+      None => self.generate_heightfield(origin, shape),
+    };
+    for stamp in &self.stamps {
+      stamp.stamp(&mut data, origin, shape);
+    }
+    if let Some(structures) = &self.structures {
+      let [width, height, depth] = shape.as_array();
+      let max = origin + VoxelId::new(width as i32 - 1, height as i32 - 1, depth as i32 - 1);
+      let pieces = self.with_terrain_height(|terrain_height| {
+        structures.pieces_in(self.seed, origin, max, terrain_height)
+      });
+      for piece in pieces {
+        piece.apply(&mut data, origin, shape);
+      }
+    }
+    data
+  }
+
+  // height of the terrain surface at single columns, for planners that look at a few columns
+  // rather than a whole rectangle. The noise is only set up once for all of them
+  fn with_terrain_height<R>(&self, f: impl FnOnce(&dyn Fn(i32, i32) -> f32) -> R) -> R {
+    if let Some(planet) = &self.planet {
+      let noise = planet.noise(self.seed);
+      return f(&|x, z| planet.surface_height(&noise, x, z));
+    }
+    self.with_height_fn(|height| match &self.erosion {
+      Some(settings) => {
+        let eroder = Eroder {
+          seed: self.seed,
+          settings,
+          cache: &self.erosion_cache,
+        };
+        f(&|x, z| eroder.height(x, z, &height))
       }
-      super::ChunkVoxelData { voxels: buffer }
+      None => f(height),
     })
   }
+
+  fn generate_heightfield(
+    &self,
+    origin: VoxelId,
+    shape: &RuntimeShape<u32, 3>,
+  ) -> super::ChunkVoxelData {
+    let [width, _, depth] = shape.as_array();
+    let heightmap = self.sample_heightmap(origin.x(), origin.z(), width, depth);
+
+    let mut buffer = Vec::with_capacity(shape.usize());
+    let mut materials = Vec::with_capacity(shape.usize());
+    for i in 0..shape.size() {
+      let [x, y, z] = shape.delinearize(i);
+      let height = heightmap.get(origin.x() + x as i32, origin.z() + z as i32);
+      let y = (origin.y() + y as i32) as f32;
+      let sdf = y - height;
+      buffer.push(sdf);
+      materials.push(if sdf <= 0.0 {
+        VoxelType::Dirt
+      } else if y < self.sea_level {
+        VoxelType::Water
+      } else {
+        VoxelType::Air
+      });
+    }
+    super::ChunkVoxelData::new(&buffer, &materials)
+  }
+
+  // terrain heights (in voxels) for a rectangle of columns
+  pub fn sample_heightmap(&self, min_x: i32, min_z: i32, width: u32, depth: u32) -> Heightmap {
+    self.with_height_fn(|height| match &self.erosion {
+      Some(settings) => Eroder {
+        seed: self.seed,
+        settings,
+        cache: &self.erosion_cache,
+      }
+      .heightmap(min_x, min_z, width, depth, &height),
+      None => Heightmap::from_fn(min_x, min_z, width, depth, height),
+    })
+  }
+
+  // the noise modules borrow each other, so the chain can only be handed out to a closure
+  fn with_height_fn<R>(&self, f: impl FnOnce(&dyn Fn(i32, i32) -> f32) -> R) -> R {
+    if let Some(image) = &self.height_image {
+      return f(&|x, z| image.height_at(x as f32, z as f32));
+    }
+
+    let scale = [0.01, 0.01, 1.0];
+
+    let base_continent_def_fb0 = Fbm::new()
+      .set_seed(self.seed)
+      .set_frequency(1.0)
+      .set_persistence(0.5)
+      .set_lacunarity(2.208984375)
+      .set_octaves(14);
+
+    let base_continent_def_cu = Curve::new(&base_continent_def_fb0)
+      .add_control_point(-2.0, -2.0)
+      .add_control_point(-1.0, -1.0)
+      .add_control_point(0.0, 0.0)
+      .add_control_point(0.5, 0.01)
+      .add_control_point(1.0, 0.02)
+      .add_control_point(2.0, 0.03);
+
+    let scaled_conti = ScalePoint::new(&base_continent_def_cu).set_all_scales(0.1, 0.1, 1.0, 1.0);
+    let generator =
+      ScalePoint::new(&scaled_conti).set_all_scales(scale[0], scale[1], scale[2], 1.0);
+
+    f(&|x, z| ((generator.get([x as f64, z as f64]) + 1.0) * 25.) as f32)
+  }
 }
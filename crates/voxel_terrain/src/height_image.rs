@@ -0,0 +1,206 @@
+use image::{ImageBuffer, Luma};
+use std::{fmt, path::Path, sync::Arc};
+
+/// What a [`HeightmapImage`] returns for columns outside the image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageEdge {
+  /// Repeat the edge pixels forever.
+  #[default]
+  Clamp,
+  /// Repeat the whole image, for heightmaps that wrap around.
+  Tile,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeightmapImageSettings {
+  /// Voxels per pixel along x and z.
+  pub horizontal_scale: f32,
+  /// Height in voxels of a white pixel above a black one.
+  pub vertical_scale: f32,
+  /// Height in voxels of a black pixel.
+  pub base_height: f32,
+  pub edge: ImageEdge,
+}
+impl Default for HeightmapImageSettings {
+  fn default() -> Self {
+    Self {
+      horizontal_scale: 1.0,
+      vertical_scale: 100.0,
+      base_height: 0.0,
+      edge: ImageEdge::Clamp,
+    }
+  }
+}
+
+/// Terrain heights from a grayscale image, for
+/// [`VoxelGenerator::with_height_image`](super::VoxelGenerator::with_height_image).
+///
+/// Pixel `(0, 0)` sits on voxel column `(0, 0)`, the image's x and y axes run along the world x
+/// and z axes. Heights between pixels are interpolated bilinearly. 16 bit images keep their full
+/// precision, anything else is converted.
+///
+/// ```
+/// use image::{ImageBuffer, Luma};
+/// use voxel_terrain::{HeightmapImage, HeightmapImageSettings};
+///
+/// let pixels = ImageBuffer::from_fn(2, 1, |x, _| Luma([if x == 0 { 0 } else { u16::MAX }]));
+/// let heights = HeightmapImage::new(
+///   &pixels,
+///   HeightmapImageSettings {
+///     horizontal_scale: 10.0,
+///     ..Default::default()
+///   },
+/// )
+/// .unwrap();
+/// assert_eq!(heights.height_at(5.0, 0.0), 50.0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct HeightmapImage {
+  width: u32,
+  height: u32,
+  // 0..1, shared between the clones handed to generator tasks
+  pixels: Arc<[f32]>,
+  settings: HeightmapImageSettings,
+}
+
+impl HeightmapImage {
+  pub fn new(
+    image: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    settings: HeightmapImageSettings,
+  ) -> Result<Self, HeightmapImageError> {
+    if image.width() == 0 || image.height() == 0 {
+      return Err(HeightmapImageError::Empty);
+    }
+    if !(settings.horizontal_scale.is_finite() && settings.horizontal_scale > 0.0) {
+      return Err(HeightmapImageError::HorizontalScale(
+        settings.horizontal_scale,
+      ));
+    }
+    Ok(Self {
+      width: image.width(),
+      height: image.height(),
+      pixels: image
+        .pixels()
+        .map(|p| p.0[0] as f32 / u16::MAX as f32)
+        .collect(),
+      settings,
+    })
+  }
+
+  pub fn open(
+    path: impl AsRef<Path>,
+    settings: HeightmapImageSettings,
+  ) -> Result<Self, HeightmapImageError> {
+    let image = image::open(path).map_err(|e| HeightmapImageError::Open(e.to_string()))?;
+    Self::new(&image.into_luma16(), settings)
+  }
+
+  #[inline]
+  pub fn settings(&self) -> &HeightmapImageSettings {
+    &self.settings
+  }
+
+  /// Height in voxels of the terrain at a point on the voxel grid.
+  pub fn height_at(&self, x: f32, z: f32) -> f32 {
+    let u = x / self.settings.horizontal_scale;
+    let v = z / self.settings.horizontal_scale;
+    let (u0, v0) = (u.floor(), v.floor());
+    let (fu, fv) = (u - u0, v - v0);
+    let (u0, v0) = (u0 as i64, v0 as i64);
+
+    let top = self.pixel(u0, v0) * (1.0 - fu) + self.pixel(u0 + 1, v0) * fu;
+    let bottom = self.pixel(u0, v0 + 1) * (1.0 - fu) + self.pixel(u0 + 1, v0 + 1) * fu;
+    let sample = top * (1.0 - fv) + bottom * fv;
+    self.settings.base_height + sample * self.settings.vertical_scale
+  }
+
+  #[inline]
+  fn pixel(&self, u: i64, v: i64) -> f32 {
+    let (width, height) = (self.width as i64, self.height as i64);
+    let (u, v) = match self.settings.edge {
+      ImageEdge::Clamp => (u.clamp(0, width - 1), v.clamp(0, height - 1)),
+      ImageEdge::Tile => (u.rem_euclid(width), v.rem_euclid(height)),
+    };
+    self.pixels[(v * width + u) as usize]
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeightmapImageError {
+  Open(String),
+  Empty,
+  HorizontalScale(f32),
+}
+impl fmt::Display for HeightmapImageError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      HeightmapImageError::Open(error) => write!(f, "could not open heightmap image: {}", error),
+      HeightmapImageError::Empty => write!(f, "heightmap image has no pixels"),
+      HeightmapImageError::HorizontalScale(scale) => {
+        write!(f, "horizontal scale must be positive, got {}", scale)
+      }
+    }
+  }
+}
+impl std::error::Error for HeightmapImageError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  fn image(
+    width: u32,
+    height: u32,
+    pixels: &[u16],
+    settings: HeightmapImageSettings,
+  ) -> HeightmapImage {
+    let buffer = ImageBuffer::from_fn(width, height, |x, y| {
+      Luma([pixels[(y * width + x) as usize % pixels.len()]])
+    });
+    HeightmapImage::new(&buffer, settings).unwrap()
+  }
+
+  proptest! {
+      #[test]
+      fn pixels_should_be_sampled_exactly(pixels in prop::collection::vec(any::<u16>(), 1..64), width in 1u32..8, height in 1u32..8, scale in 0.5f32..8.0) {
+          let settings = HeightmapImageSettings { horizontal_scale: scale, vertical_scale: 10.0, base_height: -3.0, ..Default::default() };
+          let heights = image(width, height, &pixels, settings);
+          for y in 0..height {
+              for x in 0..width {
+                  let expected = -3.0 + heights.pixel(x as i64, y as i64) * 10.0;
+                  let actual = heights.height_at(x as f32 * scale, y as f32 * scale);
+                  assert!((actual - expected).abs() < 1e-3, "{} vs {}", actual, expected);
+              }
+          }
+      }
+
+      #[test]
+      fn samples_should_stay_between_neighboring_pixels(pixels in prop::collection::vec(any::<u16>(), 1..64), width in 1u32..8, height in 1u32..8, x in -20.0f32..20.0, z in -20.0f32..20.0, tile in any::<bool>()) {
+          let edge = if tile { ImageEdge::Tile } else { ImageEdge::Clamp };
+          let heights = image(width, height, &pixels, HeightmapImageSettings { edge, ..Default::default() });
+          let (u, v) = (x.floor() as i64, z.floor() as i64);
+          let corners = [heights.pixel(u, v), heights.pixel(u + 1, v), heights.pixel(u, v + 1), heights.pixel(u + 1, v + 1)];
+          let min = corners.iter().cloned().fold(f32::MAX, f32::min) * 100.0;
+          let max = corners.iter().cloned().fold(f32::MIN, f32::max) * 100.0;
+          let sample = heights.height_at(x, z);
+          assert!(sample >= min - 1e-3 && sample <= max + 1e-3);
+      }
+
+      #[test]
+      fn tiled_images_should_repeat(pixels in prop::collection::vec(any::<u16>(), 1..64), width in 1u32..8, height in 1u32..8, x in -20i32..20, z in -20i32..20, tiles_x in -3i32..3, tiles_z in -3i32..3) {
+          let heights = image(width, height, &pixels, HeightmapImageSettings { edge: ImageEdge::Tile, ..Default::default() });
+          let shifted = heights.height_at((x + tiles_x * width as i32) as f32, (z + tiles_z * height as i32) as f32);
+          assert_eq!(heights.height_at(x as f32, z as f32), shifted);
+      }
+
+      #[test]
+      fn clamped_images_should_extend_their_edges(pixels in prop::collection::vec(any::<u16>(), 1..64), width in 1u32..8, height in 1u32..8, z in 0i32..8, beyond in 1i32..100) {
+          let heights = image(width, height, &pixels, HeightmapImageSettings::default());
+          let z = (z % height as i32) as f32;
+          assert_eq!(heights.height_at(-(beyond as f32), z), heights.height_at(0.0, z));
+          let last = (width - 1) as f32;
+          assert_eq!(heights.height_at(last + beyond as f32, z), heights.height_at(last, z));
+      }
+  }
+}
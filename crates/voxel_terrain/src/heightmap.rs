@@ -0,0 +1,83 @@
+use bevy::prelude::*;
+
+// a rectangle of terrain column heights (in voxels), addressed by world voxel x/z
+#[derive(Clone, Debug, Default)]
+pub struct Heightmap {
+  pub min_x: i32,
+  pub min_z: i32,
+  pub width: u32,
+  pub depth: u32,
+  pub heights: Vec<f32>,
+}
+
+impl Heightmap {
+  pub fn from_fn(
+    min_x: i32,
+    min_z: i32,
+    width: u32,
+    depth: u32,
+    mut f: impl FnMut(i32, i32) -> f32,
+  ) -> Self {
+    let mut heights = Vec::with_capacity((width * depth) as usize);
+    for z in 0..depth as i32 {
+      for x in 0..width as i32 {
+        heights.push(f(min_x + x, min_z + z));
+      }
+    }
+    Self {
+      min_x,
+      min_z,
+      width,
+      depth,
+      heights,
+    }
+  }
+
+  #[inline]
+  pub fn contains(&self, x: i32, z: i32) -> bool {
+    x >= self.min_x
+      && z >= self.min_z
+      && x < self.min_x + self.width as i32
+      && z < self.min_z + self.depth as i32
+  }
+
+  #[inline]
+  pub fn local_index(&self, x: u32, z: u32) -> usize {
+    (z * self.width + x) as usize
+  }
+
+  #[inline]
+  pub fn get(&self, x: i32, z: i32) -> f32 {
+    debug_assert!(self.contains(x, z));
+    self.heights[self.local_index((x - self.min_x) as u32, (z - self.min_z) as u32)]
+  }
+
+  // bilinear sample in local coordinates, clamped to the edges of the map
+  pub fn sample_local(&self, x: f32, z: f32) -> f32 {
+    let x = x.clamp(0.0, (self.width - 1) as f32);
+    let z = z.clamp(0.0, (self.depth - 1) as f32);
+    let (x0, z0) = (x.floor() as u32, z.floor() as u32);
+    let (x1, z1) = ((x0 + 1).min(self.width - 1), (z0 + 1).min(self.depth - 1));
+    let (fx, fz) = (x - x0 as f32, z - z0 as f32);
+
+    let top = self.heights[self.local_index(x0, z0)] * (1.0 - fx)
+      + self.heights[self.local_index(x1, z0)] * fx;
+    let bottom = self.heights[self.local_index(x0, z1)] * (1.0 - fx)
+      + self.heights[self.local_index(x1, z1)] * fx;
+    top * (1.0 - fz) + bottom * fz
+  }
+
+  // height gradient at a local point, using the cell the point is in
+  pub fn gradient_local(&self, x: f32, z: f32) -> Vec2 {
+    let (x0, z0) = (x.floor() as u32, z.floor() as u32);
+    let (fx, fz) = (x - x0 as f32, z - z0 as f32);
+    let h00 = self.heights[self.local_index(x0, z0)];
+    let h10 = self.heights[self.local_index(x0 + 1, z0)];
+    let h01 = self.heights[self.local_index(x0, z0 + 1)];
+    let h11 = self.heights[self.local_index(x0 + 1, z0 + 1)];
+    Vec2::new(
+      (h10 - h00) * (1.0 - fz) + (h11 - h01) * fz,
+      (h01 - h00) * (1.0 - fx) + (h11 - h10) * fx,
+    )
+  }
+}
@@ -0,0 +1,244 @@
+use super::layout::{ChunkId, ChunkLayout, VoxelId};
+use bevy::prelude::*;
+use block_mesh::ndshape::{RuntimeShape, Shape};
+
+// axial offsets of the 6 neighbors of a hex, going around counter clockwise
+const HEX_DIRECTIONS: [(i32, i32); 6] = [(1, 0), (1, -1), (0, -1), (-1, 0), (-1, 1), (0, 1)];
+const SQRT_3: f64 = 1.732_050_807_568_877_2;
+
+/// Maps between chunks, voxels and world space for a grid of pointy-top hexagonal chunks.
+///
+/// Chunks are identified by their axial coordinates, `x` is the column and `y` the row. Each
+/// chunk is a hexagon with a circumradius of `chunk_voxel_radius` voxels, a voxel belongs to the
+/// hexagon its center falls in. World space is relative to the center voxel of the `origin`
+/// chunk.
+///
+/// ```
+/// use voxel_terrain::{ChunkId, ChunkLayout, HexVoxelLayout};
+///
+/// let layout = HexVoxelLayout::new(ChunkId::new(0, 0), 1.0, 20, 50);
+///
+/// let chunk = ChunkId::new(2, -1);
+/// let center = layout.chunk_to_space(&chunk);
+/// assert_eq!(layout.space_to_chunk(&center), chunk);
+/// assert_eq!(layout.get_chunk_neighbors(&chunk, 1).len(), 6);
+/// assert_eq!(layout.chunk_ring_distance(&chunk, &layout.origin()), 2);
+/// ```
+#[derive(Clone)]
+pub struct HexVoxelLayout {
+  origin: ChunkId,
+  voxel_side_length: f32,
+  chunk_voxel_radius: u32,
+  chunk_voxel_height: u32,
+  shape: RuntimeShape<u32, 3>,
+}
+
+impl HexVoxelLayout {
+  pub fn new(
+    origin: ChunkId,
+    voxel_side_length: f32,
+    chunk_voxel_radius: u32,
+    chunk_voxel_height: u32,
+  ) -> Self {
+    assert!(
+      chunk_voxel_radius > 0,
+      "hex chunks need a radius of at least 1 voxel"
+    );
+    let (extent_x, extent_z) = chunk_extent(chunk_voxel_radius);
+    Self {
+      origin,
+      voxel_side_length,
+      chunk_voxel_radius,
+      chunk_voxel_height,
+      shape: RuntimeShape::<u32, 3>::new([
+        extent_x as u32 * 2 + 3,
+        chunk_voxel_height + 2,
+        extent_z as u32 * 2 + 3,
+      ]),
+    }
+  }
+
+  /// Number of voxels between a chunk's center and its corners.
+  #[inline]
+  pub fn chunk_voxel_radius(&self) -> u32 {
+    self.chunk_voxel_radius
+  }
+
+  #[inline]
+  pub fn chunk_voxel_height(&self) -> u32 {
+    self.chunk_voxel_height
+  }
+
+  // exact center of the hexagon in voxel units, not necessarily on a voxel boundary
+  fn chunk_center(&self, chunk: &ChunkId) -> (f64, f64) {
+    let radius = self.chunk_voxel_radius as f64;
+    let q = chunk.x() as f64;
+    let r = chunk.y() as f64;
+    (radius * SQRT_3 * (q + r / 2.0), radius * 1.5 * r)
+  }
+}
+
+// how far the voxels of a chunk reach from its center voxel along x and z, a voxel belongs to a
+// chunk when its center is inside the hexagon
+fn chunk_extent(chunk_voxel_radius: u32) -> (i32, i32) {
+  let radius = chunk_voxel_radius as f64;
+  (
+    (radius * SQRT_3 / 2.0).ceil() as i32,
+    chunk_voxel_radius as i32,
+  )
+}
+
+impl ChunkLayout for HexVoxelLayout {
+  #[inline]
+  fn origin(&self) -> ChunkId {
+    self.origin
+  }
+
+  #[inline]
+  fn set_origin(&mut self, origin: ChunkId) {
+    self.origin = origin;
+  }
+
+  #[inline]
+  fn shape(&self) -> &RuntimeShape<u32, 3> {
+    &self.shape
+  }
+
+  // the box around the hexagon, with a voxel of padding
+  fn get_origin(&self, chunk: &ChunkId) -> VoxelId {
+    let (extent_x, extent_z) = chunk_extent(self.chunk_voxel_radius);
+    self.get_center_voxel(chunk) - VoxelId::new(extent_x + 1, 1, extent_z + 1)
+  }
+
+  fn chunk_column_mask(&self, chunk: &ChunkId) -> Option<Vec<bool>> {
+    let origin = self.get_origin(chunk);
+    let [width, _, depth] = self.shape.as_array();
+    Some(
+      (0..width * depth)
+        .map(|i| {
+          let column = VoxelId::new((i % width) as i32, 0, (i / width) as i32);
+          self.voxel_to_chunk(&(origin + column)) == *chunk
+        })
+        .collect(),
+    )
+  }
+
+  #[inline]
+  fn voxel_side_length(&self) -> f32 {
+    self.voxel_side_length
+  }
+
+  #[inline]
+  fn chunk_side_length(&self) -> f32 {
+    (self.chunk_voxel_radius as f64 * SQRT_3) as f32 * self.voxel_side_length
+  }
+
+  fn get_center_voxel(&self, chunk: &ChunkId) -> VoxelId {
+    let (x, z) = self.chunk_center(chunk);
+    VoxelId::new(x.floor() as i32, 0, z.floor() as i32)
+  }
+
+  fn get_chunk_neighbors(&self, chunk: &ChunkId, distance: i32) -> Vec<ChunkId> {
+    let mut neighbors = Vec::with_capacity((3 * distance * (distance + 1)).max(0) as usize);
+    for ring in 1..=distance {
+      // start at the corner of the ring and walk along its 6 sides
+      let (dq, dr) = HEX_DIRECTIONS[4];
+      let mut current = *chunk + ChunkId::new(dq * ring, dr * ring);
+      for (dq, dr) in HEX_DIRECTIONS {
+        for _ in 0..ring {
+          neighbors.push(current);
+          current = current + ChunkId::new(dq, dr);
+        }
+      }
+    }
+    neighbors
+  }
+
+  fn chunk_ring_distance(&self, a: &ChunkId, b: &ChunkId) -> i32 {
+    let diff = *a - *b;
+    (diff.x().abs() + diff.y().abs() + (diff.x() + diff.y()).abs()) / 2
+  }
+
+  fn get_chunk_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId> {
+    // check every voxel in the box around the hexagon
+    let center = self.get_center_voxel(chunk);
+    let (extent_x, extent_z) = chunk_extent(self.chunk_voxel_radius);
+    (-extent_x..=extent_x)
+      .flat_map(|x| (-extent_z..=extent_z).map(move |z| center + VoxelId::new(x, 0, z)))
+      .filter(|voxel| self.voxel_to_chunk(voxel) == *chunk)
+      .flat_map(|voxel| {
+        (0..self.chunk_voxel_height).map(move |y| voxel + VoxelId::new(0, y as i32, 0))
+      })
+      .collect()
+  }
+
+  fn voxel_to_chunk(&self, voxel: &VoxelId) -> ChunkId {
+    let radius = self.chunk_voxel_radius as f64;
+    let x = voxel.x() as f64 + 0.5;
+    let z = voxel.z() as f64 + 0.5;
+    let q = (SQRT_3 / 3.0 * x - z / 3.0) / radius;
+    let r = (2.0 / 3.0 * z) / radius;
+
+    // round in cube coordinates, the component that moved the most is recomputed from the others
+    let s = -q - r;
+    let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+    let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+    if dq > dr && dq > ds {
+      rq = -rr - rs;
+    } else if dr > ds {
+      rr = -rq - rs;
+    }
+    ChunkId::new(rq as i32, rr as i32)
+  }
+
+  fn voxel_to_space(&self, voxel: &VoxelId) -> Vec3 {
+    let transposed = *voxel - self.get_center_voxel(&self.origin);
+    Vec3::new(
+      transposed.x() as f32,
+      transposed.y() as f32,
+      transposed.z() as f32,
+    ) * self.voxel_side_length
+  }
+
+  fn space_to_voxel(&self, space: &Vec3) -> VoxelId {
+    let divisor = self.voxel_side_length;
+    let x = space.x.div_euclid(divisor) as i32;
+    let y = space.y.div_euclid(divisor) as i32;
+    let z = space.z.div_euclid(divisor) as i32;
+    VoxelId::new(x, y, z) + self.get_center_voxel(&self.origin)
+  }
+}
+impl Default for HexVoxelLayout {
+  fn default() -> Self {
+    Self::new(ChunkId::default(), 1.0, 50, 50)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  proptest! {
+      #[test]
+      fn voxels_should_be_within_radius_of_chunk_center(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, radius in 1u32..=50) {
+          let layout = HexVoxelLayout::new(ChunkId::new(x1, y1), 1.0, radius, radius);
+          let voxel = VoxelId::new(x2, 0, z2);
+          let (cx, cz) = layout.chunk_center(&layout.voxel_to_chunk(&voxel));
+          let dx = voxel.x() as f64 + 0.5 - cx;
+          let dz = voxel.z() as f64 + 0.5 - cz;
+          assert!((dx * dx + dz * dz).sqrt() <= radius as f64 + 1e-6);
+      }
+
+      #[test]
+      fn chunk_should_have_about_a_hexagon_of_voxels(x1 in -100i32..=100, y1 in -100i32..=100, radius in 1u32..=50, height in 0u32..=10) {
+          let layout = HexVoxelLayout::new(ChunkId::default(), 1.0, radius, height);
+          let voxel_count = layout.get_chunk_voxels(&ChunkId::new(x1, y1)).len() as f64;
+          // hexagons tile the plane, only the voxels cut by the perimeter can go either way
+          let radius = radius as f64;
+          let area = 1.5 * SQRT_3 * radius * radius;
+          let perimeter = 6.0 * radius;
+          assert!((voxel_count - area * height as f64).abs() <= (perimeter + 1.0) * height as f64);
+      }
+  }
+}
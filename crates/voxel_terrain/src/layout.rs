@@ -1,5 +1,5 @@
 use bevy::{math::Mat2, prelude::*};
-use block_mesh::ndshape::RuntimeShape;
+use block_mesh::ndshape::{RuntimeShape, Shape};
 use lazy_static::*;
 use std::{
   hash::Hash,
@@ -15,11 +15,29 @@ lazy_static! {
   ];
 }
 
+/// Identifies a chunk in the chunk grid. Most layouts use columns that span the full height of
+/// the terrain, `x` and `y` are the chunk's position along the world x and z axes, or the axial
+/// coordinates of a hexagonal chunk. Layouts that stack chunks vertically put the vertical
+/// position in `layer`, which is always 0 for columns.
+///
+/// ```
+/// use voxel_terrain::ChunkId;
+///
+/// let chunk = ChunkId::new(2, -1) + ChunkId::new(1, 1);
+/// assert_eq!(chunk, ChunkId::new(3, 0));
+/// assert_eq!((chunk.x(), chunk.y(), chunk.layer()), (3, 0, 0));
+/// assert_eq!(chunk.with_layer(-2).layer(), -2);
+/// ```
 #[derive(Clone, Copy, PartialEq, PartialOrd, Debug, Default, Eq, Hash)]
-pub struct ChunkId(i32, i32);
+pub struct ChunkId(i32, i32, i32);
 impl ChunkId {
   pub fn new(x: i32, y: i32) -> Self {
-    Self(x, y)
+    Self(x, y, 0)
+  }
+
+  #[inline]
+  pub fn with_layer(self, layer: i32) -> Self {
+    Self(self.0, self.1, layer)
   }
 
   #[inline]
@@ -31,13 +49,22 @@ impl ChunkId {
   pub fn y(&self) -> i32 {
     self.1
   }
+
+  #[inline]
+  pub fn layer(&self) -> i32 {
+    self.2
+  }
 }
 impl Add for ChunkId {
   type Output = Self;
 
   #[inline]
   fn add(self, other: Self) -> Self {
-    Self(self.x() + other.x(), self.y() + other.y())
+    Self(
+      self.x() + other.x(),
+      self.y() + other.y(),
+      self.layer() + other.layer(),
+    )
   }
 }
 impl Sub for ChunkId {
@@ -45,13 +72,29 @@ impl Sub for ChunkId {
 
   #[inline]
   fn sub(self, other: Self) -> Self {
-    Self(self.x() - other.x(), self.y() - other.y())
+    Self(
+      self.x() - other.x(),
+      self.y() - other.y(),
+      self.layer() - other.layer(),
+    )
   }
 }
 
+/// A single voxel in world voxel coordinates, `y` is up.
+///
+/// ```
+/// use voxel_terrain::VoxelId;
+///
+/// let voxel = VoxelId::new(1, 2, 3) - VoxelId::new(1, 1, 1);
+/// assert_eq!((voxel.x(), voxel.y(), voxel.z()), (0, 1, 2));
+/// ```
 #[derive(Clone, Copy, PartialEq, PartialOrd, Debug, Default, Eq, Hash)]
 pub struct VoxelId(i32, i32, i32);
 impl VoxelId {
+  pub fn new(x: i32, y: i32, z: i32) -> Self {
+    Self(x, y, z)
+  }
+
   #[inline]
   pub fn x(&self) -> i32 {
     self.0
@@ -92,20 +135,141 @@ impl Sub for VoxelId {
   }
 }
 
+/// Conversions between chunks, voxels and world space shared by every chunk layout.
+///
+/// World space is relative to the `origin` chunk, voxel and chunk ids are absolute. A chunk's
+/// voxel data is a box of `shape()` voxels starting at `get_origin(chunk)`, with at least one
+/// voxel of padding around every voxel of the chunk. Implementations can be checked with the
+/// helpers in `voxel_terrain::testing`. Layouts are cloned into the tasks that generate chunks.
+pub trait ChunkLayout: Clone + Send + Sync + 'static {
+  /// The chunk at the center of world space.
+  fn origin(&self) -> ChunkId;
+
+  /// Moves the center of world space. Everything already placed in space has to be moved along,
+  /// the floating origin system takes care of that for root transforms.
+  fn set_origin(&mut self, origin: ChunkId);
+
+  /// Dimensions of a chunk's voxel data, including padding.
+  fn shape(&self) -> &RuntimeShape<u32, 3>;
+
+  /// The first voxel of the chunk's voxel data, padding included.
+  fn get_origin(&self, chunk: &ChunkId) -> VoxelId;
+
+  /// Which columns of the chunk's voxel data belong to the chunk, indexed by `x + z * width`.
+  /// `None` when the chunk fills its voxel data apart from the padding.
+  fn chunk_column_mask(&self, _chunk: &ChunkId) -> Option<Vec<bool>> {
+    None
+  }
+
+  /// World space box around the chunk's voxels, padding excluded.
+  fn chunk_bounds(&self, chunk: &ChunkId) -> (Vec3, Vec3) {
+    let [width, height, depth] = self.shape().as_array();
+    let origin = self.get_origin(chunk);
+    let size = VoxelId(width as i32, height as i32, depth as i32);
+    (
+      self.voxel_to_space(&(origin + VoxelId(1, 1, 1))),
+      self.voxel_to_space(&(origin + size - VoxelId(1, 1, 1))),
+    )
+  }
+
+  /// The chunks across each face of the chunk's voxel data, in -x, +x, -y, +y, -z, +z order.
+  /// `None` for layouts whose chunks don't share whole faces with their neighbors.
+  fn chunk_face_neighbors(&self, _chunk: &ChunkId) -> Option<[ChunkId; 6]> {
+    None
+  }
+
+  /// Size of a voxel in world units, meshes are built in voxels and scaled by this.
+  fn voxel_side_length(&self) -> f32;
+
+  /// Distance in world units between the centers of two adjacent chunks.
+  fn chunk_side_length(&self) -> f32;
+
+  /// The voxel closest to the center of the chunk, at the bottom of the column.
+  fn get_center_voxel(&self, chunk: &ChunkId) -> VoxelId;
+
+  /// All chunks within `distance` rings of `chunk`, not including `chunk` itself. Nearer rings
+  /// come first.
+  fn get_chunk_neighbors(&self, chunk: &ChunkId, distance: i32) -> Vec<ChunkId>;
+
+  /// Number of rings between two chunks, the inverse of `get_chunk_neighbors`.
+  fn chunk_ring_distance(&self, a: &ChunkId, b: &ChunkId) -> i32;
+
+  /// Every voxel that belongs to the chunk, excluding padding.
+  fn get_chunk_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId>;
+
+  /// Every voxel in the chunk's voxel data that belongs to another chunk. Those chunks are at
+  /// most two rings away.
+  fn get_padding_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId> {
+    let origin = self.get_origin(chunk);
+    let shape = self.shape();
+    (0..shape.size())
+      .map(|i| {
+        let [x, y, z] = shape.delinearize(i);
+        origin + VoxelId::new(x as i32, y as i32, z as i32)
+      })
+      .filter(|voxel| self.voxel_to_chunk(voxel) != *chunk)
+      .collect()
+  }
+
+  fn voxel_to_chunk(&self, voxel: &VoxelId) -> ChunkId;
+
+  fn voxel_to_space(&self, voxel: &VoxelId) -> Vec3;
+
+  fn space_to_voxel(&self, space: &Vec3) -> VoxelId;
+
+  /// Position of the chunk's center voxel in world space.
+  fn chunk_to_space(&self, chunk: &ChunkId) -> Vec3 {
+    self.voxel_to_space(&self.get_center_voxel(chunk))
+  }
+
+  fn space_to_chunk(&self, space: &Vec3) -> ChunkId {
+    self.voxel_to_chunk(&self.space_to_voxel(space))
+  }
+
+  /// Distance in world units between the centers of two chunks.
+  fn get_chunk_distance(&self, a: &ChunkId, b: &ChunkId) -> f32 {
+    (self.chunk_to_space(a) - self.chunk_to_space(b)).length()
+  }
+}
+
+/// Maps between chunks, voxels and world space for a grid of square chunks.
+///
+/// Each chunk is `2 * chunk_voxel_length + 1` voxels wide and deep, centered on its center voxel,
+/// and `chunk_voxel_height` voxels high. World space is relative to the center of the `origin`
+/// chunk.
+///
+/// ```
+/// use bevy::math::Vec3;
+/// use voxel_terrain::{ChunkId, ChunkLayout, CubicVoxelLayout};
+///
+/// // 1 unit voxels, 101 voxel wide chunks
+/// let layout = CubicVoxelLayout::new(ChunkId::new(0, 0), 1.0, 50, 50);
+///
+/// let chunk = layout.space_to_chunk(&Vec3::new(60.0, 10.0, -20.0));
+/// assert_eq!(chunk, ChunkId::new(1, 0));
+/// assert_eq!(layout.chunk_to_space(&chunk), Vec3::new(101.0, 0.0, 0.0));
+///
+/// let voxel = layout.space_to_voxel(&Vec3::new(60.5, 10.5, -20.5));
+/// assert_eq!(layout.voxel_to_chunk(&voxel), chunk);
+/// assert_eq!(layout.get_chunk_neighbors(&chunk, 1).len(), 8);
+/// ```
+#[derive(Clone)]
 pub struct CubicVoxelLayout {
-  pub origin: ChunkId,
+  origin: ChunkId,
   voxel_side_length: f32,
   chunk_voxel_length: u32,
   chunk_voxel_height: u32,
-  pub shape: RuntimeShape<u32, 3>,
+  shape: RuntimeShape<u32, 3>,
 }
 
 impl CubicVoxelLayout {
+  /// Number of voxels between a chunk's center voxel and its edge.
   #[inline]
-  pub fn chunk_side_length(&self) -> f32 {
-    self.chunk_voxel_full_length() as f32 * self.voxel_side_length
+  pub fn chunk_voxel_length(&self) -> u32 {
+    self.chunk_voxel_length
   }
 
+  /// Width and depth of a chunk in voxels.
   #[inline]
   pub fn chunk_voxel_full_length(&self) -> u32 {
     1 + (self.chunk_voxel_length * 2)
@@ -116,24 +280,6 @@ impl CubicVoxelLayout {
     self.chunk_voxel_height
   }
 
-  #[inline]
-  pub fn get_center_voxel(&self, chunk: &ChunkId) -> VoxelId {
-    VoxelId(
-      chunk.x() * self.chunk_voxel_full_length() as i32,
-      0,
-      chunk.y() * self.chunk_voxel_full_length() as i32,
-    )
-  }
-
-  #[inline]
-  pub fn get_origin(&self, chunk: &ChunkId) -> VoxelId {
-    VoxelId(
-      (chunk.x() * self.chunk_voxel_full_length() as i32) - self.chunk_voxel_length as i32,
-      0,
-      (chunk.y() * self.chunk_voxel_full_length() as i32) - self.chunk_voxel_length as i32,
-    )
-  }
-
   #[inline]
   pub fn get_voxel(&self, chunk: &ChunkId, x: i32, y: i32, z: i32) -> VoxelId {
     let vx = x + (chunk.x() * self.chunk_voxel_full_length() as i32);
@@ -160,8 +306,64 @@ impl CubicVoxelLayout {
       ]),
     }
   }
+}
+impl ChunkLayout for CubicVoxelLayout {
+  #[inline]
+  fn origin(&self) -> ChunkId {
+    self.origin
+  }
 
-  pub fn get_chunk_neighbors(&self, chunk: &ChunkId, distance: i32) -> Vec<ChunkId> {
+  #[inline]
+  fn set_origin(&mut self, origin: ChunkId) {
+    self.origin = origin;
+  }
+
+  #[inline]
+  fn shape(&self) -> &RuntimeShape<u32, 3> {
+    &self.shape
+  }
+
+  // one voxel of padding before the chunk's minimum corner
+  #[inline]
+  fn get_origin(&self, chunk: &ChunkId) -> VoxelId {
+    let padding = self.chunk_voxel_length as i32 + 1;
+    VoxelId(
+      (chunk.x() * self.chunk_voxel_full_length() as i32) - padding,
+      -1,
+      (chunk.y() * self.chunk_voxel_full_length() as i32) - padding,
+    )
+  }
+
+  // columns never have anything above or below them, but they are boxes all the same
+  fn chunk_face_neighbors(&self, chunk: &ChunkId) -> Option<[ChunkId; 6]> {
+    Some(box_face_neighbors(chunk))
+  }
+
+  // the padding above and below belongs to the column itself
+  fn get_padding_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId> {
+    box_padding(self.get_origin(chunk), &self.shape, [true, false, true])
+  }
+
+  #[inline]
+  fn voxel_side_length(&self) -> f32 {
+    self.voxel_side_length
+  }
+
+  #[inline]
+  fn chunk_side_length(&self) -> f32 {
+    self.chunk_voxel_full_length() as f32 * self.voxel_side_length
+  }
+
+  #[inline]
+  fn get_center_voxel(&self, chunk: &ChunkId) -> VoxelId {
+    VoxelId(
+      chunk.x() * self.chunk_voxel_full_length() as i32,
+      0,
+      chunk.y() * self.chunk_voxel_full_length() as i32,
+    )
+  }
+
+  fn get_chunk_neighbors(&self, chunk: &ChunkId, distance: i32) -> Vec<ChunkId> {
     (1..=distance)
       .flat_map(move |ring| {
         (0..(2 * ring)).flat_map(move |offset| {
@@ -174,7 +376,12 @@ impl CubicVoxelLayout {
       .collect()
   }
 
-  pub fn get_chunk_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId> {
+  fn chunk_ring_distance(&self, a: &ChunkId, b: &ChunkId) -> i32 {
+    let diff = *a - *b;
+    diff.x().abs().max(diff.y().abs())
+  }
+
+  fn get_chunk_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId> {
     (0..self.chunk_voxel_full_length())
       .flat_map(|x| {
         (0..self.chunk_voxel_full_length()).flat_map(move |z| {
@@ -191,11 +398,7 @@ impl CubicVoxelLayout {
       .collect()
   }
 
-  pub fn chunk_to_space(&self, chunk: &ChunkId) -> Vec3 {
-    self.voxel_to_space(&self.get_center_voxel(chunk))
-  }
-
-  pub fn voxel_to_chunk(&self, voxel: &VoxelId) -> ChunkId {
+  fn voxel_to_chunk(&self, voxel: &VoxelId) -> ChunkId {
     let x = (voxel.x() + self.chunk_voxel_length as i32)
       .div_euclid(self.chunk_voxel_full_length() as i32);
     let y = (voxel.z() + self.chunk_voxel_length as i32)
@@ -203,7 +406,7 @@ impl CubicVoxelLayout {
     ChunkId::new(x, y)
   }
 
-  pub fn voxel_to_space(&self, voxel: &VoxelId) -> Vec3 {
+  fn voxel_to_space(&self, voxel: &VoxelId) -> Vec3 {
     let center = self.get_center_voxel(&self.origin);
     let transposed = *voxel - center;
     let x = transposed.x() as f32 * self.voxel_side_length;
@@ -212,7 +415,7 @@ impl CubicVoxelLayout {
     Vec3::new(x, y, z)
   }
 
-  pub fn space_to_voxel(&self, space: &Vec3) -> VoxelId {
+  fn space_to_voxel(&self, space: &Vec3) -> VoxelId {
     let center = self.get_center_voxel(&self.origin);
     let divisor = self.voxel_side_length;
     let x = space.x.div_euclid(divisor) as i32;
@@ -220,15 +423,34 @@ impl CubicVoxelLayout {
     let z = space.z.div_euclid(divisor) as i32;
     VoxelId(x, y, z) + center
   }
+}
 
-  pub fn space_to_chunk(&self, space: &Vec3) -> ChunkId {
-    self.voxel_to_chunk(&self.space_to_voxel(space))
-  }
+// the outermost layer of voxel data along the given axes
+pub(crate) fn box_padding(
+  origin: VoxelId,
+  shape: &RuntimeShape<u32, 3>,
+  axes: [bool; 3],
+) -> Vec<VoxelId> {
+  let size = shape.as_array();
+  (0..shape.size())
+    .map(|i| shape.delinearize(i))
+    .filter(|p| (0..3).any(|a| axes[a] && (p[a] == 0 || p[a] == size[a] - 1)))
+    .map(|[x, y, z]| origin + VoxelId::new(x as i32, y as i32, z as i32))
+    .collect()
+}
 
-  pub fn get_chunk_distance(&self, a: &ChunkId, b: &ChunkId) -> f32 {
-    (self.chunk_to_space(a) - self.chunk_to_space(b)).length()
-  }
+// neighbors of a box shaped chunk, in the face order of `ChunkLayout::chunk_face_neighbors`
+pub(crate) fn box_face_neighbors(chunk: &ChunkId) -> [ChunkId; 6] {
+  [
+    *chunk + ChunkId::new(-1, 0),
+    *chunk + ChunkId::new(1, 0),
+    chunk.with_layer(chunk.layer() - 1),
+    chunk.with_layer(chunk.layer() + 1),
+    *chunk + ChunkId::new(0, -1),
+    *chunk + ChunkId::new(0, 1),
+  ]
 }
+
 impl Default for CubicVoxelLayout {
   fn default() -> Self {
     Self::new(ChunkId::default(), 1.0, 50, 50)
@@ -239,87 +461,98 @@ impl Default for CubicVoxelLayout {
 mod tests {
   // Note this useful idiom: importing names from outer (for mod tests) scope.
   use super::*;
+  use crate::{testing::*, HexVoxelLayout};
   use proptest::prelude::*;
 
+  // every test runs against both layouts, with chunks of roughly the same size
+  fn layouts(
+    origin: ChunkId,
+    voxel_length: u32,
+    height: u32,
+  ) -> (CubicVoxelLayout, HexVoxelLayout) {
+    (
+      CubicVoxelLayout::new(origin, 1.0, voxel_length, height),
+      HexVoxelLayout::new(origin, 1.0, voxel_length, height),
+    )
+  }
+
+  // find a random chunk via neighbors
+  fn random_chunk(layout: &impl ChunkLayout, ring_num: i32, index: i32) -> ChunkId {
+    let mut chunk = ChunkId::default();
+    for _ring in 0..ring_num {
+      let mut n: Vec<_> = layout.get_chunk_neighbors(&chunk, 1);
+      chunk = n.remove((index % n.len() as i32) as usize);
+    }
+    chunk
+  }
+
   proptest! {
       #[test]
       fn chunk_should_have_appropriate_number_of_neighbors(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..50, distance in 1i32..10) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
           let voxel = VoxelId(x2, 0, z2);
-          let chunk = layout.voxel_to_chunk(&voxel);
-          let count =  layout.get_chunk_neighbors(&chunk, distance).len();
-          let expected = ((distance * 2) + 1) * ((distance * 2) + 1) - 1;
-          assert_eq!(expected, count as i32);
+          check_neighbor_count(&cubic, voxel, distance, (((distance * 2) + 1) * ((distance * 2) + 1) - 1) as usize);
+          // every hex ring has 6 more chunks than the one inside it
+          check_neighbor_count(&hex, voxel, distance, (3 * distance * (distance + 1)) as usize);
       }
 
       #[test]
       fn neighbor_should_have_correct_distance(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..50, distance in 1i32..10) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
           let voxel = VoxelId(x2, 0, z2);
-          let chunk = layout.voxel_to_chunk(&voxel);
-          for neighbor in layout.get_chunk_neighbors(&chunk, distance) {
+          check_neighbor_distance(&cubic, voxel, distance);
+          check_neighbor_distance(&hex, voxel, distance);
+
+          // chebyshev distance for square chunks
+          let chunk = cubic.voxel_to_chunk(&voxel);
+          for neighbor in cubic.get_chunk_neighbors(&chunk, distance) {
               let diff = neighbor - chunk;
-              let x = diff.x().abs();
-              let y = diff.y().abs();
-              let max = if x > y { x } else { y };
-              assert!(max <= distance);
+              assert!(diff.x().abs().max(diff.y().abs()) <= distance);
           }
       }
 
       #[test]
       fn neighbor_should_be_mutual(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..50, distance in 1i32..10) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
           let voxel = VoxelId(x2, 0, z2);
-          let chunk = layout.voxel_to_chunk(&voxel);
-          for neighbor in layout.get_chunk_neighbors(&chunk, distance) {
-              let ns: Vec<_> = layout.get_chunk_neighbors(&neighbor, distance);
-              let original: Vec<_> = ns.clone().into_iter().filter(|n| *n == chunk).collect();
-              assert_eq!(original.len(), 1);
-              assert_eq!(original[0], chunk);
-          }
+          check_neighbor_mutual(&cubic, voxel, distance);
+          check_neighbor_mutual(&hex, voxel, distance);
       }
 
       #[test]
       fn chunk_space_coordinates_should_be_zero_when_at_origin(x1 in -10000i32..=10000, y1 in -10000i32..=10000, voxel_length in 1u32..50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
-          let coords = layout.chunk_to_space(&layout.origin);
-          assert_eq!(coords.x, 0.0);
-          assert_eq!(coords.y, 0.0);
-          assert_eq!(coords.z, 0.0);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
+          check_origin_at_zero(&cubic);
+          check_origin_at_zero(&hex);
       }
 
       #[test]
       fn voxel_space_coordinates_should_be_reversible(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
           let voxel = VoxelId(x2, 0, z2);
-          let space_coords = layout.voxel_to_space(&voxel);
-          let result = layout.space_to_voxel(&space_coords);
-          assert_eq!(result, voxel, "Coords: {:?}", space_coords);
+          check_voxel_space_reversible(&cubic, voxel);
+          check_voxel_space_reversible(&hex, voxel);
       }
 
       #[test]
       fn chunk_space_coordinates_should_be_reversible(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
           let voxel = VoxelId(x2, 0, z2);
-          let chunk = layout.voxel_to_chunk(&voxel);
-          let space_coords = layout.chunk_to_space(&chunk);
-          let result = layout.space_to_chunk(&space_coords);
-          assert_eq!(result, chunk, "Chunk coords: {:?}", space_coords);
+          check_chunk_space_reversible(&cubic, voxel);
+          check_chunk_space_reversible(&hex, voxel);
       }
 
       #[test]
       fn voxel_should_resolve_to_same_chunk_in_space(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
           let voxel = VoxelId(x2, 0, z2);
-          let space_coords = layout.voxel_to_space(&voxel);
-          let space_chunk = layout.space_to_chunk(&space_coords);
-          let voxel_chunk = layout.voxel_to_chunk(&voxel);
-          assert_eq!(space_chunk, voxel_chunk);
+          check_voxel_chunk_in_space(&cubic, voxel);
+          check_voxel_chunk_in_space(&hex, voxel);
       }
 
       #[test]
       fn voxel_to_chunk_xz_distance_should_be_voxel_length_or_less(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let layout = CubicVoxelLayout::new(ChunkId::new(x1, y1), 1.0, voxel_length, voxel_length);
           let voxel = VoxelId(x2, 0, z2);
           let chunk = layout.voxel_to_chunk(&voxel);
           let chunk_center = layout.get_center_voxel(&chunk);
@@ -329,40 +562,39 @@ mod tests {
       }
 
       #[test]
-      fn voxel_to_chunk_vertical_distance_should_be_voxel_length_or_less(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
-          let voxel = VoxelId(x2, 0, z2);
-          let chunk = layout.voxel_to_chunk(&voxel);
-          let chunk_center = layout.get_center_voxel(&chunk);
-          let diff = voxel - chunk_center;
-          let distance = diff.y().abs();
-          assert!(distance <= layout.chunk_voxel_length as i32);
+      fn chunks_should_be_whole_columns(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, y2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=50) {
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
+          // the height of a voxel never changes its chunk, and centers sit at the bottom of the column
+          let chunk = cubic.voxel_to_chunk(&VoxelId(x2, 0, z2));
+          assert_eq!(cubic.voxel_to_chunk(&VoxelId(x2, y2, z2)), chunk);
+          assert_eq!(cubic.get_center_voxel(&chunk).y(), 0);
+          let chunk = hex.voxel_to_chunk(&VoxelId(x2, 0, z2));
+          assert_eq!(hex.voxel_to_chunk(&VoxelId(x2, y2, z2)), chunk);
+          assert_eq!(hex.get_center_voxel(&chunk).y(), 0);
       }
 
       #[test]
       fn voxel_to_chunk_should_return_same_value_for_same_chunk(x1 in -10000i32..=10000, y1 in -10000i32..=10000, ring_num in 0i32..10, index in 0i32..1000, voxel_length in 1u32..=50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, voxel_length);
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, voxel_length);
+          check_chunk_voxels(&cubic, &random_chunk(&cubic, ring_num, index));
+          check_chunk_voxels(&hex, &random_chunk(&hex, ring_num, index));
+      }
 
-          // find a random chunk via neighbors
-          let mut chunk = ChunkId::default();
-          for _ring in 0..ring_num {
-              let mut n: Vec<_> = layout.get_chunk_neighbors(&chunk, 1);
-              chunk = n.remove((index % n.len() as i32) as usize);
-          }
-          for voxel in layout.get_chunk_voxels(&chunk) {
-              let result = layout.voxel_to_chunk(&voxel);
-              assert_eq!(result, chunk, "Voxel: {:?}, expected chunk: {:?}, actual: {:?}", voxel, chunk, result);
-          }
+      #[test]
+      fn chunk_voxel_data_should_contain_chunk_with_padding(x1 in -10000i32..=10000, y1 in -10000i32..=10000, ring_num in 0i32..10, index in 0i32..1000, voxel_length in 1u32..=50, height in 0u32..=50) {
+          let (cubic, hex) = layouts(ChunkId::new(x1, y1), voxel_length, height);
+          check_chunk_data(&cubic, &random_chunk(&cubic, ring_num, index));
+          check_chunk_data(&hex, &random_chunk(&hex, ring_num, index));
       }
 
       #[test]
       fn chunk_should_have_correct_number_of_voxels(x1 in -10000i32..=10000, y1 in -10000i32..=10000, x2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=50, height in 0u32..=50) {
-          let layout = CubicVoxelLayout::new(ChunkId(x1, y1), 1.0, voxel_length, height);
+          let layout = CubicVoxelLayout::new(ChunkId::new(x1, y1), 1.0, voxel_length, height);
 
           let voxel = VoxelId(x2, 0, z2);
           let chunk = layout.voxel_to_chunk(&voxel);
           let voxel_count = layout.get_chunk_voxels(&chunk).len() as i32;
-          let expected = (layout.chunk_voxel_full_length() * layout.chunk_voxel_full_length()) * height; // 6 triangle cross-sections (excl center), each section has a number of voxels equal to the nth triangle number * height
+          let expected = (layout.chunk_voxel_full_length() * layout.chunk_voxel_full_length()) * height;
           assert_eq!(expected as i32, voxel_count);
       }
   }
@@ -1,26 +1,71 @@
 use bevy::{
   prelude::*,
   tasks::{AsyncComputeTaskPool, Task},
+  transform::TransformSystem,
 };
 use futures_lite::future;
+use std::{collections::HashMap, convert::TryFrom, marker::PhantomData};
 
-// module organization doesn't make sense
-// maybe the layout abstraction doesn't work
-// because all the other modules depend on the layout
-// mesh, voxel generation, voxelId and chunkId meaning etc
-mod generator;
+// the layout decides what chunk and voxel ids mean, everything else goes through the ChunkLayout
+// trait and works with any layout
+mod cache;
+mod edit;
+mod erosion;
+mod export;
+pub mod generator;
+mod height_image;
+mod heightmap;
+mod hex_layout;
 mod layout;
-mod mesher;
+mod light;
+mod material;
+pub mod mesher;
+mod navigation;
+mod navmesh;
+mod origin;
+mod padding;
+mod planet;
+mod render;
+mod replication;
+mod settings;
+mod structures;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod tracker;
+mod visibility;
+mod volume_layout;
+mod vox;
+mod voxel_data;
 
-use layout::*;
-
-#[derive(Default)]
-pub struct TempTerrainMaterial {
-  pub material: Handle<StandardMaterial>,
-  pub tex: Handle<Image>,
-  pub normal: Handle<Image>,
-}
+pub use edit::VoxelEdit;
+pub use erosion::{ErosionSettings, Rng};
+pub use export::{merge_meshes, weld, write_gltf, write_obj, ExportFormat, ExportTerrain};
+pub use generator::{VoxelGenerator, VoxelType};
+pub use height_image::{HeightmapImage, HeightmapImageError, HeightmapImageSettings, ImageEdge};
+pub use hex_layout::HexVoxelLayout;
+pub use layout::{ChunkId, ChunkLayout, CubicVoxelLayout, VoxelId};
+pub use light::{ChunkLight, MAX_LIGHT};
+pub use material::TerrainMaterial;
+pub use mesher::{greedy_mesh, surface_nets_mesh, MeshBuffers, MesherScratch};
+pub use navigation::{NavChunk, NavGraph, NavSettings, VoxelNavigationPlugin};
+pub use navmesh::{NavMesh, NavMeshPlugin, NavMeshSettings, NavMeshTile};
+pub use origin::{FloatingOriginShifted, IgnoreFloatingOrigin};
+pub use padding::{chunks_containing, copy_padding, padding_by_owner, voxel_index};
+pub use planet::PlanetSettings;
+pub use render::{ChunkWater, TempTerrainMaterial, VoxelTerrainPlugin};
+pub use replication::{
+  LoopbackTransport, ReplicationClient, ReplicationClientPlugin, ReplicationError,
+  ReplicationMessage, ReplicationServer, ReplicationServerPlugin, ReplicationTransport,
+};
+pub use settings::{LayoutSettings, MeshStyle, SettingsError, VoxelTerrainSettings};
+pub use structures::{
+  Dungeons, Roads, StructurePiece, StructurePlanner, StructureRegion, Structures, Villages,
+};
+pub use tracker::{ChunkState, ChunkTracker};
+pub use visibility::{visible_chunks, ChunkConnectivity};
+pub use volume_layout::VolumeVoxelLayout;
+pub use vox::{VoxError, VoxModel, VoxStamp};
+pub use voxel_data::{ChunkVoxelData, VoxelDataError};
 
 #[derive(Default, Debug, Component)]
 pub struct ChunkSpawner {
@@ -34,37 +79,86 @@ pub struct Chunk {
   pub distance_to_nearest_spawner: f32,
 }
 
+// the chunk has finished meshing, chunks without any surface are marked without getting a mesh
 #[derive(Debug, Default, Component)]
-pub struct ChunkVoxelData {
-  pub voxels: Vec<f32>,
-}
+pub struct ChunkMeshed;
 
-#[derive(Default)]
-pub struct VoxelTerrainPlugin;
+// mesh data for a chunk, waiting to be picked up by whatever draws (or exports) the terrain
+#[derive(Debug, Default, Component)]
+pub struct ChunkMesh(pub MeshBuffers);
 
-impl Plugin for VoxelTerrainPlugin {
+#[derive(Debug, Default, Component)]
+pub struct ChunkWaterMesh(pub MeshBuffers);
+
+// Streams, generates and meshes chunks around every ChunkSpawner without touching assets or the
+// renderer, so it runs with MinimalPlugins on a server or in tests. Chunks are laid out by `L`
+pub struct VoxelTerrainCorePlugin<L = CubicVoxelLayout> {
+  pub settings: VoxelTerrainSettings,
+  layout: PhantomData<L>,
+}
+impl<L> VoxelTerrainCorePlugin<L> {
+  pub fn new(settings: VoxelTerrainSettings) -> Self {
+    Self {
+      settings,
+      layout: PhantomData,
+    }
+  }
+}
+impl Default for VoxelTerrainCorePlugin {
+  fn default() -> Self {
+    Self::new(VoxelTerrainSettings::default())
+  }
+}
+
+impl<L> Plugin for VoxelTerrainCorePlugin<L>
+where
+  L: ChunkLayout + for<'a> TryFrom<&'a LayoutSettings, Error = SettingsError>,
+{
   fn build(&self, app: &mut App) {
+    let layout = L::try_from(&self.settings.layout)
+      .and_then(|layout| self.settings.validate_for(&layout).map(|_| layout))
+      .expect("invalid voxel terrain settings");
+
     app
+      .insert_resource(self.settings.clone())
+      .insert_resource(layout)
       .init_resource::<tracker::ChunkTracker>()
-      .init_resource::<generator::VoxelGenerator>()
-      .init_resource::<layout::CubicVoxelLayout>()
-      .init_resource::<TempTerrainMaterial>()
-      .add_startup_system(load_textures)
-      .add_system(spawn_chunks)
-      .add_system(calc_chunk_distances)
-      .add_system(load_voxels)
-      .add_system(build_chunk_mesh)
-      .add_system(attach_chunk_mesh)
-      .add_system(despawn_chunks)
-      .add_system(set_texture_tiled);
+      .init_resource::<generator::VoxelGenerator>();
+    // a generator inserted before the plugin is checked along with the settings
+    app
+      .world
+      .resource::<generator::VoxelGenerator>()
+      .validate()
+      .expect("invalid voxel terrain settings");
+
+    app
+      .add_system(spawn_chunks::<L>)
+      .add_system(calc_chunk_distances::<L>)
+      .add_system(load_voxels::<L>)
+      .add_system(light::load_light::<L>)
+      .add_event::<VoxelEdit>()
+      .add_system(edit::apply_voxel_edits::<L>.before(build_chunk_mesh::<L>))
+      .add_system(build_chunk_mesh::<L>)
+      .add_system(load_chunk_mesh)
+      .add_system(load_water_mesh)
+      .add_system(visibility::build_connectivity::<L>)
+      .add_system(visibility::load_connectivity)
+      .add_system(despawn_chunks::<L>)
+      .add_system_to_stage(CoreStage::PostUpdate, tracker::sync_chunk_tracker)
+      .add_event::<FloatingOriginShifted>()
+      .add_system_to_stage(
+        CoreStage::PostUpdate,
+        origin::recenter_floating_origin::<L>.before(TransformSystem::TransformPropagate),
+      );
   }
 }
 
-pub fn spawn_chunks(
+pub fn spawn_chunks<L: ChunkLayout>(
   mut commands: Commands,
   thread_pool: Res<AsyncComputeTaskPool>,
-  layout: Res<layout::CubicVoxelLayout>,
+  layout: Res<L>,
   generator: Res<generator::VoxelGenerator>,
+  settings: Res<VoxelTerrainSettings>,
   mut tracker: ResMut<tracker::ChunkTracker>,
   mut query: Query<(&Transform, &mut ChunkSpawner)>,
 ) {
@@ -80,29 +174,38 @@ pub fn spawn_chunks(
     }
 
     // find neighboring chunks
-    let neighbors = layout.get_chunk_neighbors(&current_chunk, 4);
+    let neighbors = layout.get_chunk_neighbors(&current_chunk, settings.load_radius);
 
     // spawn chunks
     for chunk in std::iter::once(current_chunk).chain(neighbors) {
-      if tracker.try_spawn(&chunk) {
+      if !tracker.is_loaded(&chunk) {
         // println!("Spawning {:?}", chunk);
-        let pos = layout.chunk_to_space(&chunk);
+        // chunks are placed where their voxel data starts, so the mesh lines up with the voxels
         let origin = layout.get_origin(&chunk);
+        let pos = layout.voxel_to_space(&origin);
 
         // TODO: the voxel data might be better off in a resource
         // this allows access to the voxel data from an async task
-        let load_voxels_task =
-          generator.load_voxel_data(&thread_pool, origin, layout.shape.clone());
+        let load_voxels_task = {
+          let (generator, layout) = ((*generator).clone(), (*layout).clone());
+          thread_pool.spawn(async move {
+            let data = generator.generate(origin, layout.shape());
+            (data, padding::ChunkPadding::new(&layout, &chunk))
+          })
+        };
 
         // create entities for chunks
-        commands
+        let entity = commands
           .spawn()
           .insert(Transform::from_translation(pos))
+          .insert(GlobalTransform::default())
           .insert(Chunk {
             id: chunk,
             distance_to_nearest_spawner: 0., // will be computed by another system
           })
-          .insert(load_voxels_task);
+          .insert(load_voxels_task)
+          .id();
+        tracker.try_spawn(&chunk, entity);
       }
     }
 
@@ -111,8 +214,8 @@ pub fn spawn_chunks(
   }
 }
 
-pub fn calc_chunk_distances(
-  layout: Res<layout::CubicVoxelLayout>,
+pub fn calc_chunk_distances<L: ChunkLayout>(
+  layout: Res<L>,
   mut query: Query<&mut Chunk>,
   mut site_query: Query<&mut ChunkSpawner>,
 ) {
@@ -143,111 +246,166 @@ pub fn calc_chunk_distances(
   }
 }
 
-pub fn load_voxels(
+pub fn load_voxels<L: ChunkLayout>(
   mut commands: Commands,
-  mut tasks: Query<(Entity, &Chunk, &mut Task<ChunkVoxelData>)>,
+  layout: Res<L>,
+  thread_pool: Res<AsyncComputeTaskPool>,
+  settings: Res<VoxelTerrainSettings>,
+  tracker: Res<tracker::ChunkTracker>,
+  mut tasks: Query<(
+    Entity,
+    &Chunk,
+    &mut Task<(ChunkVoxelData, padding::ChunkPadding)>,
+  )>,
+  mut chunks: Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
 ) {
   // check if voxel data load task is complete
+  let mut generated = HashMap::new();
+  let mut paddings = HashMap::new();
   for (entity, chunk, mut task) in tasks.iter_mut() {
-    if let Some(voxel_data) = future::block_on(future::poll_once(&mut *task)) {
-      // Add our new PbrBundle of components to our tagged entity
-      commands
-        .entity(entity)
-        .insert(voxel_data)
-        .remove::<Task<ChunkVoxelData>>();
+    if let Some((voxel_data, padding)) = future::block_on(future::poll_once(&mut *task)) {
+      generated.insert(chunk.id, (entity, voxel_data));
+      paddings.insert(chunk.id, padding);
     }
   }
+  if generated.is_empty() {
+    return;
+  }
+
+  let changed =
+    padding::sync_generated_padding(&*layout, &tracker, &mut generated, &paddings, &mut chunks);
+  for entity in changed {
+    let (data, light) = chunks.get(entity).expect("changed chunks are loaded");
+    let id = tracker.chunk(entity).expect("changed chunks are tracked");
+    let mut chunk = commands.entity(entity);
+    edit::invalidate_chunk(
+      &mut chunk,
+      &id,
+      data,
+      light.is_some(),
+      &settings,
+      &thread_pool,
+      &*layout,
+    );
+  }
+
+  for (id, (entity, voxel_data)) in generated {
+    let mut chunk_entity = commands.entity(entity);
+    if settings.lighting {
+      chunk_entity.insert(light::generate_light(
+        &thread_pool,
+        &voxel_data,
+        &*layout,
+        id,
+      ));
+    }
+    // Add our new PbrBundle of components to our tagged entity
+    chunk_entity
+      .insert(voxel_data)
+      .remove::<Task<(ChunkVoxelData, padding::ChunkPadding)>>();
+  }
 }
 
-pub fn build_chunk_mesh(
+pub fn build_chunk_mesh<L: ChunkLayout>(
   mut commands: Commands,
-  layout: Res<layout::CubicVoxelLayout>,
+  layout: Res<L>,
   thread_pool: Res<AsyncComputeTaskPool>,
-  query: Query<(Entity, &Chunk, &ChunkVoxelData), (Without<Task<Mesh>>, Without<Handle<Mesh>>)>,
+  generator: Res<generator::VoxelGenerator>,
+  settings: Res<VoxelTerrainSettings>,
+  query: Query<
+    (Entity, &Chunk, &ChunkVoxelData, Option<&ChunkLight>),
+    (Without<Task<MeshBuffers>>, Without<ChunkMeshed>),
+  >,
 ) {
-  for (entity, chunk, voxel_data) in query.iter() {
-    let gen_mesh_task =
-      mesher::generate_mesh(&thread_pool, &voxel_data.voxels, layout.shape.clone(), 0);
+  for (entity, chunk, voxel_data, light) in query.iter() {
+    // wait for the light so the mesh doesn't come out dark
+    if settings.lighting && light.is_none() {
+      continue;
+    }
+    let mut chunk_entity = commands.entity(entity);
+    let mask = layout.chunk_column_mask(&chunk.id);
 
-    commands.entity(entity).insert(gen_mesh_task);
-  }
-}
+    if voxel_data.contains(generator::VoxelType::Water) {
+      // the mesh is relative to the start of the voxel data
+      let sea_level = generator.sea_level - layout.get_origin(&chunk.id).y() as f32;
+      chunk_entity.insert(mesher::generate_water_mesh(
+        &thread_pool,
+        voxel_data,
+        layout.shape().clone(),
+        mask.clone(),
+        sea_level,
+        layout.voxel_side_length(),
+      ));
+    }
 
-pub fn load_textures(
-  asset_server: Res<AssetServer>,
-  mut terrain_mat: ResMut<TempTerrainMaterial>,
-  mut materials: ResMut<Assets<StandardMaterial>>,
-) {
-  // tempmorary, should load textures separately
-  // and probably need a custom terrain material
-  terrain_mat.tex = asset_server.load("textures/test.png");
-  terrain_mat.normal = asset_server.load("textures/test_n.png");
-
-  terrain_mat.material = materials.add(StandardMaterial {
-    base_color_texture: Some(terrain_mat.tex.clone()),
-    normal_map_texture: Some(terrain_mat.normal.clone()),
-    perceptual_roughness: 0.89,
-    ..default()
-  });
+    if voxel_data.is_uniform() {
+      chunk_entity.insert(ChunkMeshed);
+    } else {
+      chunk_entity.insert(match settings.mesh_style {
+        MeshStyle::Blocky => mesher::generate_mesh(
+          &thread_pool,
+          voxel_data,
+          layout.shape().clone(),
+          mask,
+          light.cloned(),
+          layout.voxel_side_length(),
+          0,
+        ),
+        MeshStyle::Smooth => mesher::generate_mesh2(
+          &thread_pool,
+          voxel_data,
+          layout.shape().clone(),
+          light.cloned(),
+          layout.voxel_side_length(),
+          0,
+        ),
+      });
+    }
+  }
 }
 
-pub fn set_texture_tiled(
-  mut texture_events: EventReader<AssetEvent<Image>>,
-  mut textures: ResMut<Assets<Image>>,
-) {
-  // wgpu's sampler settings are currently hard coded,
-  // quick and dirty way to get a tiled texture
-  for event in texture_events.iter() {
-    match event {
-      AssetEvent::Created { handle } => {
-        if let Some(mut texture) = textures.get_mut(handle) {
-          texture.sampler_descriptor.address_mode_u =
-            bevy::render::render_resource::AddressMode::Repeat;
-          texture.sampler_descriptor.address_mode_v =
-            bevy::render::render_resource::AddressMode::Repeat;
-          texture.sampler_descriptor.address_mode_w =
-            bevy::render::render_resource::AddressMode::Repeat;
-        }
-      }
-      _ => (),
+pub fn load_chunk_mesh(mut commands: Commands, mut tasks: Query<(Entity, &mut Task<MeshBuffers>)>) {
+  for (entity, mut task) in tasks.iter_mut() {
+    if let Some(mesh) = future::block_on(future::poll_once(&mut *task)) {
+      commands
+        .entity(entity)
+        .remove::<Task<MeshBuffers>>()
+        .insert(ChunkMeshed)
+        .insert(ChunkMesh(mesh));
     }
   }
 }
 
-pub fn attach_chunk_mesh(
-  layout: Res<layout::CubicVoxelLayout>,
+pub fn load_water_mesh(
   mut commands: Commands,
-  mut meshes: ResMut<Assets<Mesh>>,
-  mut materials: ResMut<Assets<StandardMaterial>>,
-  terrain_mat: Res<TempTerrainMaterial>,
-  mut tasks: Query<(Entity, &Chunk, &mut Task<Mesh>), Without<Handle<Mesh>>>,
+  mut tasks: Query<(Entity, &mut Task<mesher::WaterMesh>)>,
 ) {
-  for (entity, chunk, mut task) in tasks.iter_mut() {
-    if let Some(mesh) = future::block_on(future::poll_once(&mut *task)) {
-      commands.entity(entity).insert_bundle(PbrBundle {
-        mesh: meshes.add(mesh),
-        // material: terrain_mat.material.clone(),
-        material: materials.add(StandardMaterial {
-          base_color_texture: Some(terrain_mat.tex.clone()),
-          normal_map_texture: Some(terrain_mat.normal.clone()),
-          perceptual_roughness: 0.89,
-          ..default()
-        }),
-        transform: Transform::from_translation(layout.chunk_to_space(&chunk.id)),
-        ..default()
-      });
+  for (entity, mut task) in tasks.iter_mut() {
+    if let Some(water) = future::block_on(future::poll_once(&mut *task)) {
+      let mut chunk = commands.entity(entity);
+      chunk.remove::<Task<mesher::WaterMesh>>();
+
+      // dry chunks don't get a water surface at all
+      if let mesher::WaterMesh(Some(mesh)) = water {
+        chunk.insert(ChunkWaterMesh(mesh));
+      }
     }
   }
 }
 
-pub fn despawn_chunks(
+pub fn despawn_chunks<L: ChunkLayout>(
   mut commands: Commands,
+  layout: Res<L>,
+  settings: Res<VoxelTerrainSettings>,
   mut tracker: ResMut<tracker::ChunkTracker>,
   qry: Query<(Entity, &Chunk)>,
 ) {
+  let unload_distance = settings.unload_radius as f32 * layout.chunk_side_length();
   for (entity, chunk) in qry.iter() {
     // TODO: figure out proper criteria for despawning
-    if chunk.distance_to_nearest_spawner > 1000.0 && tracker.try_despawn(&chunk.id) {
+    if chunk.distance_to_nearest_spawner > unload_distance
+      && tracker.try_despawn(&chunk.id).is_some()
+    {
       commands.entity(entity).despawn_recursive();
     }
   }
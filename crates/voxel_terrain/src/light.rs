@@ -0,0 +1,574 @@
+use super::{
+  edit, generator::VoxelType, mesher::MeshBuffers, padding, settings::MeshStyle,
+  voxel_data::ChunkVoxelData, Chunk, ChunkId, ChunkLayout, ChunkTracker, VoxelTerrainSettings,
+};
+use bevy::{
+  prelude::*,
+  tasks::{AsyncComputeTaskPool, Task},
+};
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use futures_lite::future;
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  sync::Arc,
+};
+
+pub const MAX_LIGHT: u8 = 15;
+// brightness lost per light level below MAX_LIGHT, unlit caves end up almost black
+const LIGHT_FALLOFF: f32 = 0.8;
+// neighbor offsets, straight down has to be first
+const NEIGHBORS: [[i32; 3]; 6] = [
+  [0, -1, 0],
+  [0, 1, 0],
+  [-1, 0, 0],
+  [1, 0, 0],
+  [0, 0, -1],
+  [0, 0, 1],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Channel {
+  Sky,
+  Block,
+}
+
+/// Light levels of every voxel in a chunk's voxel data, from 0 to [`MAX_LIGHT`].
+///
+/// Skylight comes in through the top of the voxel data and falls straight down through air
+/// without getting dimmer, block light comes from emissive voxels like `Lamp`. Both spread
+/// sideways losing a level per voxel and stop at solid voxels. Edits are applied incrementally
+/// with [`ChunkLight::update`], only the voxels that depend on the edited one are relit.
+///
+/// Only layouts whose chunks span whole columns are lit, the top of their voxel data is the top of
+/// the world. Light crosses chunk borders through the padding: once a chunk is loaded with the
+/// terrain, the light of its padding is copied from the loaded chunks that own it and kept in sync
+/// with them, so light from one chunk spreads on into the next.
+#[derive(Debug, Clone, Component)]
+pub struct ChunkLight {
+  // skylight in the high nibble and block light in the low one. Shared with mesh tasks, the first
+  // edit after a mesh task started copies it
+  levels: Arc<Vec<u8>>,
+  padding: Arc<LightPadding>,
+}
+
+// The voxels of a chunk's padding whose light comes from the chunks that own them
+#[derive(Debug, Default)]
+struct LightPadding {
+  // indexed like the voxel data
+  borrowed: Vec<bool>,
+  // see padding_by_owner
+  owners: HashMap<ChunkId, Vec<(usize, usize)>>,
+}
+
+impl LightPadding {
+  fn new<L: ChunkLayout>(layout: &L, chunk: &ChunkId) -> Self {
+    let mut owners = padding::padding_by_owner(layout, chunk);
+    owners.remove(chunk);
+    let mut borrowed = vec![false; layout.shape().usize()];
+    for &(i, _) in owners.values().flatten() {
+      borrowed[i] = true;
+    }
+    Self { borrowed, owners }
+  }
+
+  // the light of the part of the padding the owner has, as it would be stored here
+  fn levels_from(&self, owner: &ChunkId, light: &ChunkLight) -> Vec<(usize, u8)> {
+    self.owners.get(owner).map_or_else(Vec::new, |padding| {
+      padding.iter().map(|&(i, j)| (i, light.levels[j])).collect()
+    })
+  }
+}
+
+impl ChunkLight {
+  /// Lights the voxel data on its own, as if nothing was around it.
+  pub fn new(data: &ChunkVoxelData, shape: &RuntimeShape<u32, 3>) -> Self {
+    let mut light = Self {
+      levels: Arc::new(vec![0; shape.usize()]),
+      padding: Arc::default(),
+    };
+    let [width, height, depth] = shape.as_array();
+
+    // sunlight falls down every column until it hits something
+    let mut queue = VecDeque::new();
+    for z in 0..depth {
+      for x in 0..width {
+        for y in (0..height).rev() {
+          let i = shape.linearize([x, y, z]) as usize;
+          if data.material(i) != VoxelType::Air {
+            break;
+          }
+          light.set(Channel::Sky, i, MAX_LIGHT);
+          queue.push_back(i);
+        }
+      }
+    }
+    light.spread(Channel::Sky, data, shape, queue);
+
+    let mut queue = VecDeque::new();
+    for i in 0..shape.usize() {
+      let emission = data.material(i).emission();
+      if emission > 0 {
+        light.set(Channel::Block, i, emission);
+        queue.push_back(i);
+      }
+    }
+    light.spread(Channel::Block, data, shape, queue);
+    light
+  }
+
+  /// Lights the voxel data of a chunk, its padding is then kept in sync with the loaded chunks
+  /// that own it.
+  pub fn with_padding<L: ChunkLayout>(data: &ChunkVoxelData, layout: &L, chunk: &ChunkId) -> Self {
+    // the light worked out from the chunk's own voxel data stands in for the neighbors until they
+    // are loaded
+    Self {
+      padding: Arc::new(LightPadding::new(layout, chunk)),
+      ..Self::new(data, layout.shape())
+    }
+  }
+
+  #[inline]
+  pub fn sky(&self, i: usize) -> u8 {
+    self.get(Channel::Sky, i)
+  }
+
+  #[inline]
+  pub fn block(&self, i: usize) -> u8 {
+    self.get(Channel::Block, i)
+  }
+
+  /// The brighter of skylight and block light.
+  #[inline]
+  pub fn level(&self, i: usize) -> u8 {
+    self.sky(i).max(self.block(i))
+  }
+
+  /// Relights around voxel `i` after it was changed in `data`.
+  pub fn update(&mut self, data: &ChunkVoxelData, shape: &RuntimeShape<u32, 3>, i: usize) {
+    let [_, height, _] = shape.as_array();
+    let material = data.material(i);
+    let top = shape.delinearize(i as u32)[1] == height - 1;
+    let sky = if top && material == VoxelType::Air {
+      MAX_LIGHT
+    } else {
+      0
+    };
+    self.relight(Channel::Sky, data, shape, i, sky);
+    self.relight(Channel::Block, data, shape, i, material.emission());
+  }
+
+  // Sets the light of the padding to the owner's, `levels` being pairs of indices into the voxel
+  // data and light levels as they are stored. Returns whether anything changed.
+  fn set_padding(
+    &mut self,
+    data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    levels: &[(usize, u8)],
+  ) -> bool {
+    let mut changed = false;
+    for channel in [Channel::Sky, Channel::Block] {
+      // darker voxels are relit one by one, brighter ones spread all at once
+      let mut brighter = VecDeque::new();
+      for &(i, packed) in levels {
+        let level = Self::unpack(channel, packed);
+        let current = self.get(channel, i);
+        if level < current {
+          self.relight(channel, data, shape, i, level);
+        } else if level > current {
+          self.set(channel, i, level);
+          brighter.push_back(i);
+        }
+        changed |= level != current;
+      }
+      self.spread(channel, data, shape, brighter);
+    }
+    changed
+  }
+
+  // Gives voxel `i` its own light level `source`, then relights the voxels around it. Borrowed
+  // padding keeps its light, it's only ever set from the owner.
+  fn relight(
+    &mut self,
+    channel: Channel,
+    data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    i: usize,
+    source: u8,
+  ) {
+    // take away everything the old voxel lit, voxels lit from somewhere else light the gap back up
+    let mut removed = VecDeque::from([(i, self.get(channel, i))]);
+    let mut relight = VecDeque::new();
+    self.set(channel, i, 0);
+    while let Some((j, level)) = removed.pop_front() {
+      let position = shape.delinearize(j as u32);
+      for (direction, offset) in NEIGHBORS.iter().enumerate() {
+        let n = match neighbor(shape, position, offset) {
+          Some(n) => n,
+          None => continue,
+        };
+        let neighbor_level = self.get(channel, n);
+        if neighbor_level == 0 {
+          continue;
+        }
+        let lit_by_j = neighbor_level < level
+          || (channel == Channel::Sky && direction == 0 && level == MAX_LIGHT);
+        let source =
+          (channel == Channel::Block && data.material(n).emission() > 0) || self.is_borrowed(n);
+        if lit_by_j && !source {
+          self.set(channel, n, 0);
+          removed.push_back((n, neighbor_level));
+        } else {
+          relight.push_back(n);
+        }
+      }
+    }
+
+    if source > 0 {
+      self.set(channel, i, source);
+      relight.push_back(i);
+    }
+    self.spread(channel, data, shape, relight);
+  }
+
+  /// Darkens the colors of a mesh made from the same voxel data by the light around each vertex.
+  pub fn bake(
+    &self,
+    mesh: &mut MeshBuffers,
+    data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    style: MeshStyle,
+  ) {
+    // block meshes have voxel corners on whole numbers, surface nets have voxel centers there
+    let center = match style {
+      MeshStyle::Blocky => 0.5,
+      MeshStyle::Smooth => 0.0,
+    };
+    mesh.colors.resize(mesh.positions.len(), [1.0; 4]);
+    for ((position, normal), color) in mesh
+      .positions
+      .iter()
+      .zip(&mesh.normals)
+      .zip(mesh.colors.iter_mut())
+    {
+      // just in front of the surface, where the light falls on it
+      let point = Vec3::from(*position) + Vec3::from(*normal).normalize_or_zero() * 0.5;
+      let level = self.sample(data, shape, point - center);
+      let brightness = LIGHT_FALLOFF.powf(MAX_LIGHT as f32 - level);
+      for c in color.iter_mut().take(3) {
+        *c *= brightness;
+      }
+    }
+  }
+
+  // light interpolated between the voxels around a point, ignoring solid voxels as they are always
+  // dark
+  fn sample(&self, data: &ChunkVoxelData, shape: &RuntimeShape<u32, 3>, point: Vec3) -> f32 {
+    let size = shape.as_array();
+    let base = point.floor();
+    let t = point - base;
+    let (mut total, mut weight) = (0.0, 0.0);
+    for corner in 0..8 {
+      let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+      let p = [
+        base.x as i32 + offset[0],
+        base.y as i32 + offset[1],
+        base.z as i32 + offset[2],
+      ];
+      if (0..3).any(|i| p[i] < 0 || p[i] >= size[i] as i32) {
+        continue;
+      }
+      let i = shape.linearize(p.map(|c| c as u32)) as usize;
+      if data.material(i).is_solid() {
+        continue;
+      }
+      let w = [t.x, t.y, t.z]
+        .iter()
+        .zip(offset)
+        .map(|(t, o)| if o == 1 { *t } else { 1.0 - t })
+        .product::<f32>();
+      total += w * self.level(i) as f32;
+      weight += w;
+    }
+    if weight > 0.0 {
+      total / weight
+    } else {
+      0.0
+    }
+  }
+
+  // flood fill from the voxels in the queue, which are already lit
+  fn spread(
+    &mut self,
+    channel: Channel,
+    data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    mut queue: VecDeque<usize>,
+  ) {
+    while let Some(i) = queue.pop_front() {
+      let level = self.get(channel, i);
+      let position = shape.delinearize(i as u32);
+      for (direction, offset) in NEIGHBORS.iter().enumerate() {
+        let n = match neighbor(shape, position, offset) {
+          Some(n) => n,
+          None => continue,
+        };
+        let material = data.material(n);
+        if material.is_solid() || self.is_borrowed(n) {
+          continue;
+        }
+        let next = if channel == Channel::Sky
+          && direction == 0
+          && level == MAX_LIGHT
+          && material == VoxelType::Air
+        {
+          MAX_LIGHT
+        } else {
+          level.saturating_sub(1)
+        };
+        if self.get(channel, n) < next {
+          self.set(channel, n, next);
+          queue.push_back(n);
+        }
+      }
+    }
+  }
+
+  #[inline]
+  fn get(&self, channel: Channel, i: usize) -> u8 {
+    Self::unpack(channel, self.levels[i])
+  }
+
+  #[inline]
+  fn unpack(channel: Channel, packed: u8) -> u8 {
+    match channel {
+      Channel::Sky => packed >> 4,
+      Channel::Block => packed & 0x0f,
+    }
+  }
+
+  #[inline]
+  fn is_borrowed(&self, i: usize) -> bool {
+    self.padding.borrowed.get(i).copied().unwrap_or(false)
+  }
+
+  #[inline]
+  fn set(&mut self, channel: Channel, i: usize, level: u8) {
+    let value = &mut Arc::make_mut(&mut self.levels)[i];
+    *value = match channel {
+      Channel::Sky => (*value & 0x0f) | (level << 4),
+      Channel::Block => (*value & 0xf0) | level,
+    };
+  }
+}
+
+#[inline]
+fn neighbor(shape: &RuntimeShape<u32, 3>, [x, y, z]: [u32; 3], offset: &[i32; 3]) -> Option<usize> {
+  let size = shape.as_array();
+  let p = [
+    x as i32 + offset[0],
+    y as i32 + offset[1],
+    z as i32 + offset[2],
+  ];
+  if (0..3).any(|i| p[i] < 0 || p[i] >= size[i] as i32) {
+    return None;
+  }
+  Some(shape.linearize(p.map(|c| c as u32)) as usize)
+}
+
+pub fn generate_light<L: ChunkLayout>(
+  thread_pool: &Res<AsyncComputeTaskPool>,
+  voxel_data: &ChunkVoxelData,
+  layout: &L,
+  chunk: ChunkId,
+) -> Task<ChunkLight> {
+  let (voxel_data, layout) = (voxel_data.clone(), layout.clone());
+  thread_pool.spawn(async move { ChunkLight::with_padding(&voxel_data, &layout, &chunk) })
+}
+
+pub fn load_light<L: ChunkLayout>(
+  mut commands: Commands,
+  layout: Res<L>,
+  thread_pool: Res<AsyncComputeTaskPool>,
+  settings: Res<VoxelTerrainSettings>,
+  tracker: Res<ChunkTracker>,
+  mut tasks: Query<(Entity, &Chunk, &mut Task<ChunkLight>)>,
+  mut chunks: Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
+) {
+  let mut fresh = HashMap::new();
+  for (entity, chunk, mut task) in tasks.iter_mut() {
+    if let Some(light) = future::block_on(future::poll_once(&mut *task)) {
+      fresh.insert(chunk.id, (entity, light));
+    }
+  }
+  if fresh.is_empty() {
+    return;
+  }
+
+  let ids: Vec<_> = fresh.keys().copied().collect();
+  let changed = sync_light(&*layout, &tracker, &mut fresh, &mut chunks, ids);
+  for entity in changed {
+    if let (Some(id), Ok((data, _))) = (tracker.chunk(entity), chunks.get(entity)) {
+      let mut chunk = commands.entity(entity);
+      edit::invalidate_chunk(
+        &mut chunk,
+        &id,
+        data,
+        true,
+        &settings,
+        &thread_pool,
+        &*layout,
+      );
+    }
+  }
+  for (_, (entity, light)) in fresh {
+    commands
+      .entity(entity)
+      .remove::<Task<ChunkLight>>()
+      .insert(light);
+  }
+}
+
+// Light crosses chunk borders through the padding, which takes its light from the chunks that
+// own it. Starting from the chunks whose light changed, the loaded chunks copy the light of their
+// padding from its owners until they all agree. `fresh` chunks aren't inserted yet, they first
+// take the light of their padding from the chunks already lit. Returns the other chunks whose
+// light changed.
+pub(crate) fn sync_light<L: ChunkLayout>(
+  layout: &L,
+  tracker: &ChunkTracker,
+  fresh: &mut HashMap<ChunkId, (Entity, ChunkLight)>,
+  chunks: &mut Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
+  changed: impl IntoIterator<Item = ChunkId>,
+) -> HashSet<Entity> {
+  let mut queue: VecDeque<_> = changed.into_iter().collect();
+  let fresh_ids: Vec<_> = fresh.keys().copied().collect();
+  for id in &fresh_ids {
+    let owners: Vec<_> = fresh[id].1.padding.owners.keys().copied().collect();
+    for owner in owners {
+      copy_light(layout, tracker, fresh, chunks, id, &owner);
+    }
+  }
+
+  let mut relit = HashSet::new();
+  while let Some(owner) = queue.pop_front() {
+    for chunk in layout.get_chunk_neighbors(&owner, padding::PADDING_RINGS) {
+      if copy_light(layout, tracker, fresh, chunks, &chunk, &owner) {
+        if !fresh.contains_key(&chunk) {
+          relit.extend(tracker.entity(&chunk));
+        }
+        queue.push_back(chunk);
+      }
+    }
+  }
+  relit
+}
+
+// copies the light of the part of the chunk's padding the owner has, returns whether the chunk's
+// light changed
+fn copy_light<L: ChunkLayout>(
+  layout: &L,
+  tracker: &ChunkTracker,
+  fresh: &mut HashMap<ChunkId, (Entity, ChunkLight)>,
+  chunks: &mut Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
+  chunk: &ChunkId,
+  owner: &ChunkId,
+) -> bool {
+  let padding = match light_of(tracker, fresh, chunks, chunk) {
+    Some(light) => light.padding.clone(),
+    None => return false,
+  };
+  if !padding.owners.contains_key(owner) {
+    return false;
+  }
+  let levels = match light_of(tracker, fresh, chunks, owner) {
+    Some(owner_light) => padding.levels_from(owner, owner_light),
+    None => return false,
+  };
+
+  if let Some((entity, light)) = fresh.get_mut(chunk) {
+    match chunks.get(*entity) {
+      Ok((data, _)) => light.set_padding(data, layout.shape(), &levels),
+      Err(_) => false,
+    }
+  } else {
+    match tracker.entity(chunk).map(|entity| chunks.get_mut(entity)) {
+      Some(Ok((data, Some(mut light)))) => light.set_padding(&data, layout.shape(), &levels),
+      _ => false,
+    }
+  }
+}
+
+fn light_of<'a>(
+  tracker: &ChunkTracker,
+  fresh: &'a HashMap<ChunkId, (Entity, ChunkLight)>,
+  chunks: &'a Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
+  chunk: &ChunkId,
+) -> Option<&'a ChunkLight> {
+  match fresh.get(chunk) {
+    Some((_, light)) => Some(light),
+    None => chunks.get(tracker.entity(chunk)?).ok()?.1,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{testing::MATERIALS, vox::placed_sdf, CubicVoxelLayout, VoxelId};
+  use proptest::prelude::*;
+
+  #[test]
+  fn light_should_cross_chunk_borders() {
+    let layout = CubicVoxelLayout::new(ChunkId::default(), 1.0, 2, 4);
+    let shape = layout.shape();
+    let (a, b) = (ChunkId::new(0, 0), ChunkId::new(1, 0));
+    let air = vec![VoxelType::Air; shape.usize()];
+    let sdf: Vec<f32> = air.iter().map(|m| placed_sdf(*m)).collect();
+    // a lamp a voxel in from the border, out of reach of b's padding
+    let lamp = padding::voxel_index(&layout, &a, &VoxelId::new(1, 1, 0)).unwrap();
+    let mut data_a = ChunkVoxelData::new(&sdf, &air);
+    data_a.set(lamp, placed_sdf(VoxelType::Lamp), VoxelType::Lamp);
+    let data_b = ChunkVoxelData::new(&sdf, &air);
+
+    let mut light_a = ChunkLight::with_padding(&data_a, &layout, &a);
+    let mut light_b = ChunkLight::with_padding(&data_b, &layout, &b);
+    let lit = padding::voxel_index(&layout, &b, &VoxelId::new(4, 1, 0)).unwrap();
+    assert_eq!(light_b.block(lit), 0);
+
+    let levels = light_b.padding.levels_from(&a, &light_a);
+    assert!(light_b.set_padding(&data_b, shape, &levels));
+    assert_eq!(light_b.block(lit), VoxelType::Lamp.emission() - 3);
+
+    data_a.set(lamp, placed_sdf(VoxelType::Air), VoxelType::Air);
+    light_a.update(&data_a, shape, lamp);
+    let levels = light_b.padding.levels_from(&a, &light_a);
+    assert!(light_b.set_padding(&data_b, shape, &levels));
+    assert_eq!(light_b.block(lit), 0);
+  }
+
+  proptest! {
+      #[test]
+      fn incremental_updates_should_match_a_full_relight(ground in prop::collection::vec(0u32..8, 36), edits in prop::collection::vec((0usize..288, 0usize..4), 1..20)) {
+          let shape = RuntimeShape::<u32, 3>::new([6, 8, 6]);
+          let materials: Vec<VoxelType> = (0..shape.size())
+              .map(|i| {
+                  let [x, y, z] = shape.delinearize(i);
+                  if y < ground[(x + z * 6) as usize] { VoxelType::Dirt } else { VoxelType::Air }
+              })
+              .collect();
+          let sdf: Vec<f32> = materials.iter().map(|m| placed_sdf(*m)).collect();
+          let mut data = ChunkVoxelData::new(&sdf, &materials);
+          let mut light = ChunkLight::new(&data, &shape);
+
+          for (i, material) in edits {
+              let material = MATERIALS[material];
+              data.set(i, placed_sdf(material), material);
+              light.update(&data, &shape, i);
+          }
+
+          let expected = ChunkLight::new(&data, &shape);
+          for i in 0..shape.usize() {
+              assert_eq!((light.sky(i), light.block(i)), (expected.sky(i), expected.block(i)), "voxel {:?}", shape.delinearize(i as u32));
+          }
+      }
+  }
+}
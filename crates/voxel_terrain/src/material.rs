@@ -0,0 +1,159 @@
+use super::mesher::ATTRIBUTE_LIGHT;
+use bevy::{
+  ecs::system::{lifetimeless::SRes, SystemParamItem},
+  pbr::MaterialPipeline,
+  prelude::*,
+  reflect::TypeUuid,
+  render::{
+    mesh::MeshVertexBufferLayout,
+    render_asset::{PrepareAssetError, RenderAsset, RenderAssets},
+    render_resource::{
+      std140::{AsStd140, Std140},
+      *,
+    },
+    renderer::RenderDevice,
+  },
+};
+
+/// Material of the terrain meshes: `base_color` times `base_color_texture`, darkened by the light
+/// the meshers bake into every vertex (see [`ATTRIBUTE_LIGHT`]).
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "9b3c8f5e-2d41-4c7a-a6e0-5f1d2b8c7e93"]
+pub struct TerrainMaterial {
+  pub base_color: Color,
+  pub base_color_texture: Option<Handle<Image>>,
+}
+impl Default for TerrainMaterial {
+  fn default() -> Self {
+    Self {
+      base_color: Color::WHITE,
+      base_color_texture: None,
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct GpuTerrainMaterial {
+  _buffer: Buffer,
+  bind_group: BindGroup,
+}
+
+impl RenderAsset for TerrainMaterial {
+  type ExtractedAsset = TerrainMaterial;
+  type PreparedAsset = GpuTerrainMaterial;
+  type Param = (
+    SRes<RenderDevice>,
+    SRes<MaterialPipeline<Self>>,
+    SRes<RenderAssets<Image>>,
+  );
+  fn extract_asset(&self) -> Self::ExtractedAsset {
+    self.clone()
+  }
+
+  fn prepare_asset(
+    material: Self::ExtractedAsset,
+    (render_device, pbr_pipeline, gpu_images): &mut SystemParamItem<Self::Param>,
+  ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+    let (tex_view, tex_sampler) = if let Some(result) = pbr_pipeline
+      .mesh_pipeline
+      .get_image_texture(gpu_images, &material.base_color_texture)
+    {
+      result
+    } else {
+      return Err(PrepareAssetError::RetryNextUpdate(material));
+    };
+
+    let base_color = Vec4::from_slice(&material.base_color.as_linear_rgba_f32());
+    let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+      contents: base_color.as_std140().as_bytes(),
+      label: Some("terrain_material_uniform_buffer"),
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+    });
+    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+      entries: &[
+        BindGroupEntry {
+          binding: 0,
+          resource: buffer.as_entire_binding(),
+        },
+        BindGroupEntry {
+          binding: 1,
+          resource: BindingResource::TextureView(tex_view),
+        },
+        BindGroupEntry {
+          binding: 2,
+          resource: BindingResource::Sampler(tex_sampler),
+        },
+      ],
+      label: Some("terrain_material_bind_group"),
+      layout: &pbr_pipeline.material_layout,
+    });
+
+    Ok(GpuTerrainMaterial {
+      _buffer: buffer,
+      bind_group,
+    })
+  }
+}
+
+impl Material for TerrainMaterial {
+  fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+    Some(asset_server.load("shaders/terrain.wgsl"))
+  }
+
+  fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+    Some(asset_server.load("shaders/terrain.wgsl"))
+  }
+
+  fn bind_group(render_asset: &<Self as RenderAsset>::PreparedAsset) -> &BindGroup {
+    &render_asset.bind_group
+  }
+
+  fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+      entries: &[
+        BindGroupLayoutEntry {
+          binding: 0,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: BufferSize::new(Vec4::std140_size_static() as u64),
+          },
+          count: None,
+        },
+        BindGroupLayoutEntry {
+          binding: 1,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Texture {
+            multisampled: false,
+            sample_type: TextureSampleType::Float { filterable: true },
+            view_dimension: TextureViewDimension::D2,
+          },
+          count: None,
+        },
+        BindGroupLayoutEntry {
+          binding: 2,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Sampler(SamplerBindingType::Filtering),
+          count: None,
+        },
+      ],
+      label: Some("terrain_material_layout"),
+    })
+  }
+
+  // the mesh pipeline only lays out the attributes it knows about, the light comes after them
+  fn specialize(
+    _pipeline: &MaterialPipeline<Self>,
+    descriptor: &mut RenderPipelineDescriptor,
+    layout: &MeshVertexBufferLayout,
+  ) -> Result<(), SpecializedMeshPipelineError> {
+    descriptor.vertex.buffers = vec![layout.get_layout(&[
+      Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+      Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+      Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+      ATTRIBUTE_LIGHT.at_shader_location(3),
+    ])?];
+    Ok(())
+  }
+}
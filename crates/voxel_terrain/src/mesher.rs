@@ -1,140 +1,810 @@
-use super::generator::VoxelType;
+use super::{generator::VoxelType, light::ChunkLight, settings::MeshStyle, ChunkVoxelData};
 use bevy::{
   prelude::*,
   render::{
-    mesh::{Indices, VertexAttributeValues},
-    render_resource::PrimitiveTopology,
+    mesh::{Indices, MeshVertexAttribute, VertexAttributeValues},
+    render_resource::{PrimitiveTopology, VertexFormat},
   },
   tasks::{AsyncComputeTaskPool, Task},
 };
 use block_mesh::{
   greedy_quads,
   ndshape::{RuntimeShape, Shape},
-  GreedyQuadsBuffer, RIGHT_HANDED_Y_UP_CONFIG,
+  visible_block_faces, GreedyQuadsBuffer, MergeVoxel, OrientedBlockFace, UnitQuadBuffer,
+  UnorientedQuad, Voxel, VoxelVisibility, RIGHT_HANDED_Y_UP_CONFIG,
 };
+use fast_surface_nets::{surface_nets, SurfaceNetsBuffer};
+use std::cell::RefCell;
+
+// index of the +Y face in RIGHT_HANDED_Y_UP_CONFIG
+const UP_FACE: usize = 4;
+// brightness of a quad corner touched by 0 to 3 solid voxels in front of the quad
+const AO_CURVE: [f32; 4] = [1.0, 0.8, 0.6, 0.4];
+
+/// The baked light of [`MeshBuffers::colors`] in a mesh asset, read by the terrain material. Bevy's
+/// own color attribute is packed into a `u32` and ignored by the standard material.
+pub const ATTRIBUTE_LIGHT: MeshVertexAttribute =
+  MeshVertexAttribute::new("Vertex_Light", 473_182_905, VertexFormat::Float32x4);
+
+// Plain vertex and index buffers, independent of any renderer. The terrain pipeline only ever
+// produces these, turning them into a Mesh asset is left to whoever draws the terrain. The meshers
+// work in voxel units, the mesh tasks scale the result to the voxel size of the layout
+#[derive(Debug, Default, Clone)]
+pub struct MeshBuffers {
+  pub positions: Vec<[f32; 3]>,
+  pub normals: Vec<[f32; 3]>,
+  pub uvs: Vec<[f32; 2]>,
+  // baked lighting, ambient occlusion and voxel light. White when there is nothing to bake
+  pub colors: Vec<[f32; 4]>,
+  pub indices: Vec<u32>,
+}
+impl MeshBuffers {
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.indices.is_empty()
+  }
+
+  /// Multiplies every position by `scale`, to go from voxel units to world units.
+  pub fn scale(&mut self, scale: f32) {
+    for position in self.positions.iter_mut() {
+      *position = position.map(|c| c * scale);
+    }
+  }
+}
+impl From<MeshBuffers> for Mesh {
+  fn from(buffers: MeshBuffers) -> Self {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(
+      Mesh::ATTRIBUTE_POSITION,
+      VertexAttributeValues::Float32x3(buffers.positions),
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, buffers.normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, buffers.uvs);
+    mesh.insert_attribute(ATTRIBUTE_LIGHT, buffers.colors);
+    mesh.set_indices(Some(Indices::U32(buffers.indices)));
+    mesh
+  }
+}
+
+pub struct WaterMesh(pub Option<MeshBuffers>);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct WaterVoxel(bool);
+impl Voxel for WaterVoxel {
+  fn get_visibility(&self) -> VoxelVisibility {
+    if self.0 {
+      VoxelVisibility::Opaque
+    } else {
+      VoxelVisibility::Empty
+    }
+  }
+}
+impl MergeVoxel for WaterVoxel {
+  type MergeValue = Self;
+
+  fn merge_value(&self) -> Self::MergeValue {
+    *self
+  }
+}
+
+// buffers that are too big to allocate for every chunk. Each thread in the task pool keeps its own
+// set that is reused by every mesh task that runs on it
+#[derive(Default)]
+pub struct MesherScratch {
+  materials: Vec<VoxelType>,
+  water: Vec<WaterVoxel>,
+  sdf: Vec<f32>,
+  quads: Option<GreedyQuadsBuffer>,
+  unit_quads: Option<UnitQuadBuffer>,
+  surface_nets: SurfaceNetsBuffer,
+}
+
+thread_local! {
+  static SCRATCH: RefCell<MesherScratch> = RefCell::new(MesherScratch::default());
+}
+
+impl MesherScratch {
+  pub fn with<R>(f: impl FnOnce(&mut MesherScratch) -> R) -> R {
+    SCRATCH.with(|scratch| f(&mut scratch.borrow_mut()))
+  }
+
+  pub fn greedy_mesh(
+    &mut self,
+    voxel_data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+  ) -> MeshBuffers {
+    voxel_data.decompress_materials(&mut self.materials);
+    let quads = self
+      .quads
+      .get_or_insert_with(|| GreedyQuadsBuffer::new(shape.usize()));
+    greedy_quads_mesh(&self.materials, shape, quads)
+  }
+
+  // for chunks that only own the columns in `mask` of their voxel data
+  pub fn masked_mesh(
+    &mut self,
+    voxel_data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    mask: &[bool],
+  ) -> MeshBuffers {
+    voxel_data.decompress_materials(&mut self.materials);
+    let quads = self.unit_quads.get_or_insert_with(UnitQuadBuffer::new);
+    masked_quads_mesh(&self.materials, shape, mask, quads)
+  }
+
+  pub fn surface_nets_mesh(
+    &mut self,
+    voxel_data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+  ) -> MeshBuffers {
+    voxel_data.decompress_sdf(&mut self.sdf);
+    surface_nets_buffer_mesh(&self.sdf, shape, &mut self.surface_nets)
+  }
+
+  pub fn water_mesh(
+    &mut self,
+    voxel_data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    mask: Option<&[bool]>,
+    sea_level: f32,
+  ) -> Option<MeshBuffers> {
+    voxel_data.decompress_materials(&mut self.materials);
+    self.water.clear();
+    self.water.extend(
+      self
+        .materials
+        .iter()
+        .map(|m| WaterVoxel(*m == VoxelType::Water)),
+    );
+    match mask {
+      Some(mask) => {
+        let quads = self.unit_quads.get_or_insert_with(UnitQuadBuffer::new);
+        masked_water_mesh(&self.water, shape, mask, quads, sea_level)
+      }
+      None => {
+        let quads = self
+          .quads
+          .get_or_insert_with(|| GreedyQuadsBuffer::new(shape.usize()));
+        water_quads_mesh(&self.water, shape, quads, sea_level)
+      }
+    }
+  }
+}
 
 // TODO: lod
 // TODO: use asset loader and return Handle<Mesh> instead of blocking
 pub fn generate_mesh(
   thread_pool: &Res<AsyncComputeTaskPool>,
-  voxels: &[f32],
+  voxel_data: &ChunkVoxelData,
   shape: RuntimeShape<u32, 3>,
+  mask: Option<Vec<bool>>,
+  light: Option<ChunkLight>,
+  voxel_size: f32,
   _lod: u8,
-) -> Task<Mesh> {
+) -> Task<MeshBuffers> {
   // how do we use the voxel data?
   // we cannot move the voxel data out of the ecs system
-  // for now we could clone it but maybe the voxel data needs to sit somewhere else
+  // for now we clone it (it's compressed so that's cheap) but maybe the voxel data needs to sit
+  // somewhere else
   // but! if it's not in the ecs, how do we edit the voxel data from a system?
   // and if we can edit, we need to make sure that we don't edit while we are using it to generate
   // the mesh hmmm... maybe we need some sort of double buffer?
   // edits are made in the front buffer while we use the back buffer to generate the mesh
   // we swap buffers if there are changes in the front buffer and mesh generation is complete
-  let v = voxels
-    .iter()
-    .map(|sdf| {
-      if *sdf > 0.0 {
-        VoxelType::Air
-      } else {
-        VoxelType::Dirt
-      }
-    })
-    .collect::<Vec<_>>();
+  let voxel_data = voxel_data.clone();
 
   thread_pool.spawn(async move {
-    let scale = 1.0;
-    let mut mesh_buffer = GreedyQuadsBuffer::new(shape.usize());
-
-    let [x, y, z] = shape.as_array();
-    greedy_quads(
-      &v,
+    blocky_mesh(
+      &voxel_data,
       &shape,
-      [0; 3],
-      [x - 1, y - 1, z - 1],
-      &RIGHT_HANDED_Y_UP_CONFIG.faces,
-      &mut mesh_buffer,
-    );
+      mask.as_deref(),
+      light.as_ref(),
+      voxel_size,
+    )
+  })
+}
 
-    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+pub fn generate_mesh2(
+  thread_pool: &Res<AsyncComputeTaskPool>,
+  voxel_data: &ChunkVoxelData,
+  shape: RuntimeShape<u32, 3>,
+  light: Option<ChunkLight>,
+  voxel_size: f32,
+  _lod: u8,
+) -> Task<MeshBuffers> {
+  let voxel_data = voxel_data.clone();
 
-    let num_indices = mesh_buffer.quads.num_quads() * 6;
-    let num_vertices = mesh_buffer.quads.num_quads() * 4;
-    let mut indices = Vec::with_capacity(num_indices);
-    let mut positions = Vec::with_capacity(num_vertices);
-    let mut normals = Vec::with_capacity(num_vertices);
-    let mut uvs = Vec::with_capacity(num_vertices);
+  thread_pool.spawn(async move { smooth_mesh(&voxel_data, &shape, light.as_ref(), voxel_size) })
+}
 
-    for (_, (group, face)) in mesh_buffer
-      .quads
-      .groups
-      .as_ref()
-      .into_iter()
-      .zip(RIGHT_HANDED_Y_UP_CONFIG.faces.into_iter())
-      .enumerate()
-    {
-      for quad in group.into_iter() {
-        let i = face.quad_mesh_indices(positions.len() as u32);
-        let p = face.quad_mesh_positions(&quad, scale);
-        let n = face.quad_mesh_normals(); // calculate_normals(&p, &i);
-
-        indices.extend_from_slice(&i);
-        positions.extend_from_slice(&p);
-        normals.extend_from_slice(&n);
-        uvs.extend_from_slice(&face.tex_coords(RIGHT_HANDED_Y_UP_CONFIG.u_flip_face, false, &quad));
-        // uvs.extend_from_slice(&[
-        //   [0., 0.],
-        //   [0., 1.],
-        //   [1., 1.],
-        //   [1., 0.]
-        // ]);
+// the mesh of a chunk in world units relative to the start of its voxel data, as the mesh tasks
+// build it
+fn blocky_mesh(
+  voxel_data: &ChunkVoxelData,
+  shape: &RuntimeShape<u32, 3>,
+  mask: Option<&[bool]>,
+  light: Option<&ChunkLight>,
+  voxel_size: f32,
+) -> MeshBuffers {
+  let mut mesh = MesherScratch::with(|scratch| match mask {
+    Some(mask) => scratch.masked_mesh(voxel_data, shape, mask),
+    None => scratch.greedy_mesh(voxel_data, shape),
+  });
+  // light is looked up by voxel, bake it before scaling
+  if let Some(light) = light {
+    light.bake(&mut mesh, voxel_data, shape, MeshStyle::Blocky);
+  }
+  mesh.scale(voxel_size);
+  mesh
+}
+
+fn smooth_mesh(
+  voxel_data: &ChunkVoxelData,
+  shape: &RuntimeShape<u32, 3>,
+  light: Option<&ChunkLight>,
+  voxel_size: f32,
+) -> MeshBuffers {
+  let mut mesh = MesherScratch::with(|scratch| scratch.surface_nets_mesh(voxel_data, shape));
+  if let Some(light) = light {
+    light.bake(&mut mesh, voxel_data, shape, MeshStyle::Smooth);
+  }
+  mesh.scale(voxel_size);
+  mesh
+}
+
+pub fn generate_water_mesh(
+  thread_pool: &Res<AsyncComputeTaskPool>,
+  voxel_data: &ChunkVoxelData,
+  shape: RuntimeShape<u32, 3>,
+  mask: Option<Vec<bool>>,
+  sea_level: f32,
+  voxel_size: f32,
+) -> Task<WaterMesh> {
+  let voxel_data = voxel_data.clone();
+
+  thread_pool.spawn(async move {
+    let mut mesh = MesherScratch::with(|scratch| {
+      scratch.water_mesh(&voxel_data, &shape, mask.as_deref(), sea_level)
+    });
+    if let Some(mesh) = &mut mesh {
+      mesh.scale(voxel_size);
+    }
+    WaterMesh(mesh)
+  })
+}
+
+// meshes with freshly allocated buffers, prefer MesherScratch when meshing more than one chunk
+pub fn greedy_mesh(voxels: &[VoxelType], shape: &RuntimeShape<u32, 3>) -> MeshBuffers {
+  greedy_quads_mesh(voxels, shape, &mut GreedyQuadsBuffer::new(shape.usize()))
+}
+
+pub fn surface_nets_mesh(sdf: &[f32], shape: &RuntimeShape<u32, 3>) -> MeshBuffers {
+  surface_nets_buffer_mesh(sdf, shape, &mut SurfaceNetsBuffer::default())
+}
+
+fn greedy_quads_mesh(
+  voxels: &[VoxelType],
+  shape: &RuntimeShape<u32, 3>,
+  mesh_buffer: &mut GreedyQuadsBuffer,
+) -> MeshBuffers {
+  let voxels = lit_voxels(voxels, shape);
+  let [x, y, z] = shape.as_array();
+  greedy_quads(
+    &voxels,
+    shape,
+    [0; 3],
+    [x - 1, y - 1, z - 1],
+    &RIGHT_HANDED_Y_UP_CONFIG.faces,
+    mesh_buffer,
+  );
+
+  let num_indices = mesh_buffer.quads.num_quads() * 6;
+  let num_vertices = mesh_buffer.quads.num_quads() * 4;
+  let mut mesh = MeshBuffers {
+    positions: Vec::with_capacity(num_vertices),
+    normals: Vec::with_capacity(num_vertices),
+    uvs: Vec::with_capacity(num_vertices),
+    colors: Vec::with_capacity(num_vertices),
+    indices: Vec::with_capacity(num_indices),
+  };
+
+  for (i, (group, face)) in mesh_buffer
+    .quads
+    .groups
+    .iter()
+    .zip(RIGHT_HANDED_Y_UP_CONFIG.faces.iter())
+    .enumerate()
+  {
+    for quad in group.iter() {
+      // every face of a merged quad has the same occlusion
+      let occluders = voxels[shape.linearize(quad.minimum) as usize].occluders[i];
+      push_merged_quad(&mut mesh, face, quad, occluders);
+    }
+  }
+  mesh
+}
+
+// A voxel and the ambient occlusion of its visible faces, as occluder counts packed two bits per
+// corner. Merging compares them along with the material, so faces next to an occluder don't merge
+// with the evenly lit faces around them. All six faces are in the key, so a voxel with more than
+// one visible face may be merged less than it could be
+#[derive(Clone, Copy)]
+struct LitVoxel {
+  voxel: VoxelType,
+  occluders: [u8; 6],
+}
+impl Voxel for LitVoxel {
+  fn get_visibility(&self) -> VoxelVisibility {
+    self.voxel.get_visibility()
+  }
+}
+impl MergeVoxel for LitVoxel {
+  type MergeValue = (VoxelType, [u8; 6]);
+
+  fn merge_value(&self) -> Self::MergeValue {
+    (self.voxel, self.occluders)
+  }
+}
+
+// the occlusion of each face is worked out once, and only for faces that can be seen
+fn lit_voxels(voxels: &[VoxelType], shape: &RuntimeShape<u32, 3>) -> Vec<LitVoxel> {
+  let size = shape.as_array();
+  let opaque = |p: [i32; 3]| {
+    (0..3).all(|i| p[i] >= 0 && p[i] < size[i] as i32)
+      && matches!(
+        voxels[shape.linearize(p.map(|c| c as u32)) as usize].get_visibility(),
+        VoxelVisibility::Opaque
+      )
+  };
+
+  (0..shape.size())
+    .map(|i| {
+      let voxel = voxels[i as usize];
+      let mut occluders = [0; 6];
+      if matches!(voxel.get_visibility(), VoxelVisibility::Opaque) {
+        let minimum = shape.delinearize(i);
+        for (face, packed) in RIGHT_HANDED_Y_UP_CONFIG.faces.iter().zip(&mut occluders) {
+          let normal = face.signed_normal().to_array();
+          let front = [0, 1, 2].map(|a| minimum[a] as i32 + normal[a]);
+          if !opaque(front) {
+            let quad = UnorientedQuad {
+              minimum,
+              width: 1,
+              height: 1,
+            };
+            let corners = corner_occluders(voxels, shape, face, &quad);
+            *packed = (0..4).fold(0, |packed, k| packed | corners[k] << (k * 2));
+          }
+        }
       }
+      LitVoxel { voxel, occluders }
+    })
+    .collect()
+}
+
+// A merged quad repeats the occlusion of its unit faces, which its corners alone can only show
+// when it is even. Occlusion that changes along one axis of the faces splits the quad into strips
+// along the other, anything else into unit faces
+fn push_merged_quad(
+  mesh: &mut MeshBuffers,
+  face: &OrientedBlockFace,
+  quad: &UnorientedQuad,
+  occluders: u8,
+) {
+  // minu_minv, maxu_minv, minu_maxv, maxu_maxv
+  let ao = [0, 1, 2, 3].map(|k| AO_CURVE[(occluders >> (k * 2) & 3) as usize]);
+  let along_u = ao[0] == ao[1] && ao[2] == ao[3];
+  let along_v = ao[0] == ao[2] && ao[1] == ao[3];
+  let (width, height) = match (along_u, along_v) {
+    (true, true) => (quad.width, quad.height),
+    (true, false) => (quad.width, 1),
+    (false, true) => (1, quad.height),
+    (false, false) => (1, 1),
+  };
+
+  let [u, v, _] = quad_axes(face, quad);
+  for j in (0..quad.height).step_by(height as usize) {
+    for i in (0..quad.width).step_by(width as usize) {
+      let mut minimum = quad.minimum;
+      minimum[u] += i;
+      minimum[v] += j;
+      let part = UnorientedQuad {
+        minimum,
+        width,
+        height,
+      };
+      push_quad(mesh, face, &part, ao);
     }
+  }
+}
 
-    mesh.insert_attribute(
-      Mesh::ATTRIBUTE_POSITION,
-      VertexAttributeValues::Float32x3(positions),
-    );
+fn surface_nets_buffer_mesh(
+  sdf: &[f32],
+  shape: &RuntimeShape<u32, 3>,
+  buffer: &mut SurfaceNetsBuffer,
+) -> MeshBuffers {
+  let [x, y, z] = shape.as_array();
+  surface_nets(sdf, shape, [0; 3], [x - 1, y - 1, z - 1], buffer);
+
+  // the buffer is reused, only copy out what the mesh needs
+  MeshBuffers {
+    positions: buffer.positions.clone(),
+    normals: buffer.normals.clone(),
+    uvs: vec![[0.0; 2]; buffer.positions.len()],
+    colors: buffer
+      .positions
+      .iter()
+      .zip(&buffer.normals)
+      .map(|(position, normal)| {
+        let ao = sdf_ao(sdf, shape, Vec3::from(*position), Vec3::from(*normal));
+        [ao, ao, ao, 1.0]
+      })
+      .collect(),
+    indices: buffer.indices.clone(),
+  }
+}
 
-    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
-    mesh.set_indices(Some(Indices::U32(indices)));
+fn water_quads_mesh(
+  voxels: &[WaterVoxel],
+  shape: &RuntimeShape<u32, 3>,
+  mesh_buffer: &mut GreedyQuadsBuffer,
+  sea_level: f32,
+) -> Option<MeshBuffers> {
+  if !voxels.iter().any(|w| w.0) {
+    return None;
+  }
 
-    mesh
+  let [x, y, z] = shape.as_array();
+  greedy_quads(
+    voxels,
+    shape,
+    [0; 3],
+    [x - 1, y - 1, z - 1],
+    &RIGHT_HANDED_Y_UP_CONFIG.faces,
+    mesh_buffer,
+  );
+
+  // only the top of the water volume is visible, the sides and bottom are
+  // either against terrain or hidden in a neighboring chunk
+  let face = &RIGHT_HANDED_Y_UP_CONFIG.faces[UP_FACE];
+  let group = &mesh_buffer.quads.groups[UP_FACE];
+  if group.is_empty() {
+    return None;
+  }
+
+  let mut indices = Vec::with_capacity(group.len() * 6);
+  let mut positions = Vec::with_capacity(group.len() * 4);
+  let mut normals = Vec::with_capacity(group.len() * 4);
+  let mut uvs = Vec::with_capacity(group.len() * 4);
+  let colors = vec![[1.0; 4]; group.len() * 4];
+  for quad in group.iter() {
+    let i = face.quad_mesh_indices(positions.len() as u32);
+    // voxels are whole units, snap the surface to the actual sea level
+    let p = face
+      .quad_mesh_positions(quad, 1.0)
+      .map(|[px, _, pz]| [px, sea_level, pz]);
+
+    indices.extend_from_slice(&i);
+    positions.extend_from_slice(&p);
+    normals.extend_from_slice(&face.quad_mesh_normals());
+    uvs.extend_from_slice(&face.tex_coords(RIGHT_HANDED_Y_UP_CONFIG.u_flip_face, false, quad));
+  }
+
+  Some(MeshBuffers {
+    positions,
+    normals,
+    uvs,
+    colors,
+    indices,
   })
 }
 
-pub fn generate_mesh2(
-  thread_pool: &Res<AsyncComputeTaskPool>,
-  voxels: &[f32],
-  shape: RuntimeShape<u32, 3>,
-  _lod: u8,
-) -> Task<Mesh> {
-  let v = voxels.iter().cloned().collect::<Vec<_>>();
+// faces of the voxels in the columns of `mask` (indexed by x + z * width) that aren't hidden by
+// another voxel. Faces aren't merged, a merged quad could reach into columns of another chunk
+fn masked_quads<T: Voxel>(
+  voxels: &[T],
+  shape: &RuntimeShape<u32, 3>,
+  mask: &[bool],
+  buffer: &mut UnitQuadBuffer,
+) {
+  let [x, y, z] = shape.as_array();
+  visible_block_faces(
+    voxels,
+    shape,
+    [0; 3],
+    [x - 1, y - 1, z - 1],
+    &RIGHT_HANDED_Y_UP_CONFIG.faces,
+    buffer,
+  );
+  for group in buffer.groups.iter_mut() {
+    group.retain(|quad| mask[(quad.minimum[0] + quad.minimum[2] * x) as usize]);
+  }
+}
 
-  thread_pool.spawn(async move {
-    let scale = 1.0;
+fn push_quad(
+  mesh: &mut MeshBuffers,
+  face: &OrientedBlockFace,
+  quad: &UnorientedQuad,
+  ao: [f32; 4],
+) {
+  let i = face.quad_mesh_indices(mesh.positions.len() as u32);
+  mesh.indices.extend_from_slice(&i);
+  mesh
+    .positions
+    .extend_from_slice(&face.quad_mesh_positions(quad, 1.0));
+  mesh.normals.extend_from_slice(&face.quad_mesh_normals());
+  mesh
+    .uvs
+    .extend_from_slice(&face.tex_coords(RIGHT_HANDED_Y_UP_CONFIG.u_flip_face, false, quad));
+  mesh.colors.extend(ao.map(|ao| [ao, ao, ao, 1.0]));
+}
 
-    let [x, y, z] = shape.as_array();
-    let mut buffer = fast_surface_nets::SurfaceNetsBuffer::default();
-    fast_surface_nets::surface_nets(
-      v.as_slice(),
-      &shape,
-      [0; 3],
-      [x - 1, y - 1, z - 1],
-      &mut buffer,
-    );
+fn quad_ao<T: Voxel>(
+  voxels: &[T],
+  shape: &RuntimeShape<u32, 3>,
+  face: &OrientedBlockFace,
+  quad: &UnorientedQuad,
+) -> [f32; 4] {
+  corner_occluders(voxels, shape, face, quad).map(|occluders| AO_CURVE[occluders as usize])
+}
 
-    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-    let num_vertices = buffer.positions.len();
+// Classic voxel ambient occlusion: each corner of a quad is darkened by the solid voxels touching
+// it in front of the quad, from 0 to 3 of them. Only the corners are sampled, which is enough for
+// unit faces. The padding voxels are the same in every chunk that has them, so corners on chunk
+// borders match
+fn corner_occluders<T: Voxel>(
+  voxels: &[T],
+  shape: &RuntimeShape<u32, 3>,
+  face: &OrientedBlockFace,
+  quad: &UnorientedQuad,
+) -> [u8; 4] {
+  // minu_minv, maxu_minv, minu_maxv, maxu_maxv on the voxel grid
+  let corners = face.quad_corners(quad).map(|corner| corner.to_array());
+  let [u, v, n] = quad_axes(face, quad);
+  let outward = face.signed_normal().to_array()[n] > 0;
 
-    mesh.insert_attribute(
-      Mesh::ATTRIBUTE_POSITION,
-      VertexAttributeValues::Float32x3(buffer.positions),
-    );
+  let size = shape.as_array();
+  let solid = |p: [i32; 3]| {
+    (0..3).all(|i| p[i] >= 0 && p[i] < size[i] as i32)
+      && matches!(
+        voxels[shape.linearize(p.map(|c| c as u32)) as usize].get_visibility(),
+        VoxelVisibility::Opaque
+      )
+  };
 
-    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, buffer.normals);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0; 2]; num_vertices]);
-    mesh.set_indices(Some(Indices::U32(buffer.indices)));
+  let mut occluders = [0; 4];
+  for (k, corner) in corners.iter().enumerate() {
+    // the voxel in front of the quad at this corner, and its neighbors away from the quad
+    let (toward_u, toward_v) = (k & 1 != 0, k & 2 != 0);
+    let mut front = corner.map(|c| c as i32);
+    if !outward {
+      front[n] -= 1;
+    }
+    if toward_u {
+      front[u] -= 1;
+    }
+    if toward_v {
+      front[v] -= 1;
+    }
+    let mut side_u = front;
+    side_u[u] += if toward_u { 1 } else { -1 };
+    let mut side_v = front;
+    side_v[v] += if toward_v { 1 } else { -1 };
+    let mut diagonal = side_u;
+    diagonal[v] = side_v[v];
 
-    mesh
-  })
+    occluders[k] = match (solid(side_u), solid(side_v)) {
+      (true, true) => 3,
+      (a, b) => a as u8 + b as u8 + solid(diagonal) as u8,
+    };
+  }
+  occluders
+}
+
+// the axes the width, height and normal of a quad run along
+fn quad_axes(face: &OrientedBlockFace, quad: &UnorientedQuad) -> [usize; 3] {
+  let corners = face.quad_corners(quad).map(|corner| corner.to_array());
+  let axis = |a: [u32; 3], b: [u32; 3]| (0..3).find(|i| a[*i] != b[*i]).unwrap_or(0);
+  let u = axis(corners[0], corners[1]);
+  let v = axis(corners[0], corners[2]);
+  [u, v, 3 - u - v]
+}
+
+// Ambient occlusion for smooth meshes: stepping away from the surface along the normal, the sdf
+// should grow by the distance travelled. Anything closer than that is occluding the vertex
+fn sdf_ao(sdf: &[f32], shape: &RuntimeShape<u32, 3>, position: Vec3, normal: Vec3) -> f32 {
+  let normal = normal.normalize_or_zero();
+  let mut occlusion = 0.0;
+  for step in 1..=4 {
+    let distance = step as f32;
+    let expected = sample_sdf(sdf, shape, position + normal * distance);
+    occlusion += (distance - expected).max(0.0) / (1 << step) as f32;
+  }
+  (1.0 - occlusion).clamp(AO_CURVE[3], 1.0)
+}
+
+// trilinear sample, clamped to the voxel data
+fn sample_sdf(sdf: &[f32], shape: &RuntimeShape<u32, 3>, point: Vec3) -> f32 {
+  let size = shape.as_array();
+  let base = point.floor();
+  let t = point - base;
+  let at = |dx: i32, dy: i32, dz: i32| {
+    let p = [base.x as i32 + dx, base.y as i32 + dy, base.z as i32 + dz];
+    let p = [0, 1, 2].map(|i| p[i].clamp(0, size[i] as i32 - 1) as u32);
+    sdf[shape.linearize(p) as usize]
+  };
+
+  let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+  let x00 = lerp(at(0, 0, 0), at(1, 0, 0), t.x);
+  let x10 = lerp(at(0, 1, 0), at(1, 1, 0), t.x);
+  let x01 = lerp(at(0, 0, 1), at(1, 0, 1), t.x);
+  let x11 = lerp(at(0, 1, 1), at(1, 1, 1), t.x);
+  lerp(lerp(x00, x10, t.y), lerp(x01, x11, t.y), t.z)
+}
+
+fn masked_quads_mesh(
+  voxels: &[VoxelType],
+  shape: &RuntimeShape<u32, 3>,
+  mask: &[bool],
+  buffer: &mut UnitQuadBuffer,
+) -> MeshBuffers {
+  masked_quads(voxels, shape, mask, buffer);
+
+  let mut mesh = MeshBuffers::default();
+  for (group, face) in buffer
+    .groups
+    .iter()
+    .zip(RIGHT_HANDED_Y_UP_CONFIG.faces.iter())
+  {
+    for quad in group.iter() {
+      let quad = (*quad).into();
+      push_quad(&mut mesh, face, &quad, quad_ao(voxels, shape, face, &quad));
+    }
+  }
+  mesh
+}
+
+fn masked_water_mesh(
+  voxels: &[WaterVoxel],
+  shape: &RuntimeShape<u32, 3>,
+  mask: &[bool],
+  buffer: &mut UnitQuadBuffer,
+  sea_level: f32,
+) -> Option<MeshBuffers> {
+  if !voxels.iter().any(|w| w.0) {
+    return None;
+  }
+
+  masked_quads(voxels, shape, mask, buffer);
+  let group = &buffer.groups[UP_FACE];
+  if group.is_empty() {
+    return None;
+  }
+
+  let face = &RIGHT_HANDED_Y_UP_CONFIG.faces[UP_FACE];
+  let mut mesh = MeshBuffers::default();
+  for quad in group.iter() {
+    push_quad(&mut mesh, face, &(*quad).into(), [1.0; 4]);
+  }
+  for position in mesh.positions.iter_mut() {
+    position[1] = sea_level;
+  }
+  Some(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  fn bounds(mesh: &MeshBuffers) -> (Vec3, Vec3) {
+    mesh.positions.iter().fold(
+      (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+      |(min, max), p| (min.min(Vec3::from(*p)), max.max(Vec3::from(*p))),
+    )
+  }
+
+  #[test]
+  fn meshes_should_scale_with_the_voxel_size() {
+    // a hill in the middle of the chunk
+    let shape = RuntimeShape::<u32, 3>::new([10, 10, 10]);
+    let sdf: Vec<f32> = (0..shape.size())
+      .map(|i| {
+        let [x, y, z] = shape.delinearize(i).map(|c| c as f32 - 4.5);
+        y + 2.0 - (x * x + z * z).sqrt() * 0.5
+      })
+      .collect();
+    let materials: Vec<_> = sdf
+      .iter()
+      .map(|v| {
+        if *v <= 0.0 {
+          VoxelType::Dirt
+        } else {
+          VoxelType::Air
+        }
+      })
+      .collect();
+    let data = ChunkVoxelData::new(&sdf, &materials);
+
+    for (unit, half) in [
+      (
+        blocky_mesh(&data, &shape, None, None, 1.0),
+        blocky_mesh(&data, &shape, None, None, 0.5),
+      ),
+      (
+        smooth_mesh(&data, &shape, None, 1.0),
+        smooth_mesh(&data, &shape, None, 0.5),
+      ),
+    ] {
+      let (unit_min, unit_max) = bounds(&unit);
+      let (half_min, half_max) = bounds(&half);
+      assert!(unit_max.cmpgt(unit_min).all());
+      assert_eq!(half_min, unit_min * 0.5);
+      assert_eq!(half_max, unit_max * 0.5);
+    }
+  }
+
+  #[test]
+  fn merged_quads_should_be_split_around_an_occluder() {
+    // a floor as wide as the chunk with a single block on it, away from the edges
+    let shape = RuntimeShape::<u32, 3>::new([10, 6, 10]);
+    let voxels: Vec<VoxelType> = (0..shape.size())
+      .map(|i| match shape.delinearize(i) {
+        [_, 0..=1, _] | [4, 2, 5] => VoxelType::Dirt,
+        _ => VoxelType::Air,
+      })
+      .collect();
+    let mesh = greedy_mesh(&voxels, &shape);
+
+    // floor vertices only exist at the block when the quads running past it were split
+    let floor: Vec<_> = mesh
+      .positions
+      .iter()
+      .zip(&mesh.normals)
+      .zip(&mesh.colors)
+      .filter(|((p, n), _)| p[1] == 2.0 && n[1] > 0.0)
+      .map(|((p, _), c)| (*p, c[0]))
+      .collect();
+    for corner in [
+      [4.0, 2.0, 5.0],
+      [5.0, 2.0, 5.0],
+      [4.0, 2.0, 6.0],
+      [5.0, 2.0, 6.0],
+    ] {
+      let at_corner: Vec<_> = floor.iter().filter(|(p, _)| *p == corner).collect();
+      assert!(!at_corner.is_empty(), "no floor vertex at {:?}", corner);
+      assert!(at_corner.iter().all(|(_, ao)| *ao < 1.0), "{:?}", at_corner);
+    }
+    // the floor away from the block is still merged
+    assert!(floor.len() < 4 * 8 * 8, "{} floor vertices", floor.len());
+  }
+
+  proptest! {
+      #[test]
+      fn ao_should_only_darken_corners_next_to_a_block(floor in 2u32..6, bx in 2u32..6, bz in 2u32..6) {
+          // a flat floor with a single block sitting on it
+          let shape = RuntimeShape::<u32, 3>::new([8, 8, 8]);
+          let voxels: Vec<VoxelType> = (0..shape.size())
+              .map(|i| {
+                  let [x, y, z] = shape.delinearize(i);
+                  if y < floor || (x, y, z) == (bx, floor, bz) { VoxelType::Dirt } else { VoxelType::Air }
+              })
+              .collect();
+          let mut buffer = UnitQuadBuffer::new();
+          visible_block_faces(&voxels, &shape, [0; 3], [7; 3], &RIGHT_HANDED_Y_UP_CONFIG.faces, &mut buffer);
+
+          let face = &RIGHT_HANDED_Y_UP_CONFIG.faces[UP_FACE];
+          for quad in buffer.groups[UP_FACE].iter() {
+              let quad: UnorientedQuad = (*quad).into();
+              let ao = quad_ao(&voxels, &shape, face, &quad);
+              let [x, y, z] = quad.minimum;
+              let touching = y + 1 == floor && x + 1 >= bx && x <= bx + 1 && z + 1 >= bz && z <= bz + 1 && (x, z) != (bx, bz);
+              if touching {
+                  assert!(ao.iter().any(|a| *a < 1.0), "{:?} next to the block is fully lit", quad.minimum);
+              } else if y + 1 == floor && x > 0 && z > 0 && x < 7 && z < 7 {
+                  assert_eq!(ao, [1.0; 4], "{:?}", quad.minimum);
+              }
+          }
+      }
+  }
 }
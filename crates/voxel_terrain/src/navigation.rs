@@ -0,0 +1,527 @@
+use super::{
+  generator::VoxelType, tracker::ChunkTracker, Chunk, ChunkId, ChunkLayout, ChunkVoxelData,
+  CubicVoxelLayout, VoxelId,
+};
+use bevy::{
+  prelude::*,
+  tasks::{AsyncComputeTaskPool, Task},
+};
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use futures_lite::future;
+use std::{
+  cmp::Reverse,
+  collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet, VecDeque},
+  hash::Hash,
+  marker::PhantomData,
+};
+
+const DIRECTIONS: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+// border crossings per entrance along a long stretch of walkable border
+const ENTRANCE_SPACING: usize = 8;
+
+/// How big the agents walking the terrain are, in voxels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NavSettings {
+  /// Air voxels an agent needs, starting with the one it stands in.
+  pub agent_height: u32,
+  /// How far an agent can step up or down in a single move.
+  pub max_step: u32,
+}
+impl Default for NavSettings {
+  fn default() -> Self {
+    Self {
+      agent_height: 2,
+      max_step: 1,
+    }
+  }
+}
+
+/// The walkable voxels of a single chunk and the cheapest walks between its entrances.
+///
+/// A voxel is walkable when it's air, has a solid voxel below it and enough air above it for an
+/// agent. Agents move to one of the four horizontal neighbors, stepping up or down by up to
+/// `max_step` voxels. Entrances are where walkable voxels meet the walkable voxels of a
+/// neighboring chunk, a few per stretch of border.
+#[derive(Debug, Default, Clone)]
+pub struct NavChunk {
+  walkable: HashSet<VoxelId>,
+  // an inside and an outside voxel for every entrance
+  entrances: Vec<(VoxelId, VoxelId)>,
+  // walking distances between entrances
+  edges: HashMap<VoxelId, Vec<(VoxelId, u32)>>,
+}
+
+impl NavChunk {
+  /// Finds the walkable voxels of a chunk. `origin` is the world position of the voxel data,
+  /// `mask` the chunk's columns as given by [`ChunkLayout::chunk_column_mask`].
+  pub fn new(
+    data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    origin: VoxelId,
+    mask: Option<&[bool]>,
+    settings: NavSettings,
+  ) -> Self {
+    let [width, height, depth] = shape.as_array().map(|s| s as i32);
+    let inside = |x: i32, y: i32, z: i32| {
+      (0..width).contains(&x) && (0..height).contains(&y) && (0..depth).contains(&z)
+    };
+    let material = |x: i32, y: i32, z: i32| {
+      data.material(shape.linearize([x as u32, y as u32, z as u32]) as usize)
+    };
+    // anything above the voxel data is open air
+    let walkable = |x: i32, y: i32, z: i32| {
+      inside(x, y, z)
+        && y > 0
+        && material(x, y, z) == VoxelType::Air
+        && material(x, y - 1, z).is_solid()
+        && (1..settings.agent_height as i32)
+          .all(|h| y + h >= height || !material(x, y + h, z).is_solid())
+    };
+    let owned = |x: i32, y: i32, z: i32| {
+      let padding = |v: i32, size: i32| v == 0 || v == size - 1;
+      !padding(y, height)
+        && match mask {
+          Some(mask) => mask[(x + z * width) as usize],
+          None => !padding(x, width) && !padding(z, depth),
+        }
+    };
+
+    let mut nav = Self::default();
+    let mut portals = Vec::new();
+    let step = settings.max_step as i32;
+    for z in 0..depth {
+      for y in 0..height {
+        for x in 0..width {
+          if !owned(x, y, z) || !walkable(x, y, z) {
+            continue;
+          }
+          let cell = origin + VoxelId::new(x, y, z);
+          nav.walkable.insert(cell);
+          for (dx, dz) in DIRECTIONS {
+            for dy in -step..=step {
+              let (nx, ny, nz) = (x + dx, y + dy, z + dz);
+              if inside(nx, ny, nz) && !owned(nx, ny, nz) && walkable(nx, ny, nz) {
+                portals.push((cell, origin + VoxelId::new(nx, ny, nz)));
+              }
+            }
+          }
+        }
+      }
+    }
+
+    nav.entrances = group_portals(&portals, settings);
+    for (entrance, _) in nav.entrances.iter() {
+      let distances = nav.distances(*entrance, settings);
+      let edges = nav
+        .entrances
+        .iter()
+        .filter(|(other, _)| other != entrance)
+        .filter_map(|(other, _)| distances.get(other).map(|d| (*other, *d)))
+        .collect();
+      nav.edges.insert(*entrance, edges);
+    }
+    nav
+  }
+
+  #[inline]
+  pub fn is_walkable(&self, voxel: &VoxelId) -> bool {
+    self.walkable.contains(voxel)
+  }
+
+  /// Walkable voxels of the chunk, in no particular order.
+  pub fn walkable(&self) -> impl Iterator<Item = &VoxelId> {
+    self.walkable.iter()
+  }
+
+  // walkable voxels one move away, within this chunk
+  fn moves(&self, cell: VoxelId, settings: NavSettings, moves: &mut Vec<(VoxelId, u32)>) {
+    let step = settings.max_step as i32;
+    for (dx, dz) in DIRECTIONS {
+      for dy in -step..=step {
+        let next = cell + VoxelId::new(dx, dy, dz);
+        if self.walkable.contains(&next) {
+          moves.push((next, 1));
+        }
+      }
+    }
+  }
+
+  // walking distance to every voxel of the chunk reachable from `from`
+  fn distances(&self, from: VoxelId, settings: NavSettings) -> HashMap<VoxelId, u32> {
+    let mut distances = HashMap::from([(from, 0)]);
+    let mut queue = VecDeque::from([from]);
+    let mut moves = Vec::new();
+    while let Some(cell) = queue.pop_front() {
+      let distance = distances[&cell];
+      moves.clear();
+      self.moves(cell, settings, &mut moves);
+      for (next, _) in moves.iter() {
+        if let Entry::Vacant(entry) = distances.entry(*next) {
+          entry.insert(distance + 1);
+          queue.push_back(*next);
+        }
+      }
+    }
+    distances
+  }
+
+  fn path(&self, from: VoxelId, to: VoxelId, settings: NavSettings) -> Option<Vec<VoxelId>> {
+    a_star(
+      from,
+      to,
+      |cell, moves| self.moves(cell, settings, moves),
+      |cell| manhattan(cell, to),
+    )
+  }
+}
+
+/// Walkable voxels of every loaded chunk, with paths across chunk borders.
+///
+/// Paths are found on a coarse graph of chunk entrances first and then walked voxel by voxel one
+/// chunk at a time, so they are close to but not always the shortest.
+#[derive(Debug, Default)]
+pub struct NavGraph {
+  pub settings: NavSettings,
+  chunks: HashMap<ChunkId, NavChunk>,
+}
+
+impl NavGraph {
+  pub fn new(settings: NavSettings) -> Self {
+    Self {
+      settings,
+      chunks: HashMap::new(),
+    }
+  }
+
+  /// Adds a chunk or replaces its old version.
+  pub fn insert(&mut self, chunk: ChunkId, nav: NavChunk) {
+    self.chunks.insert(chunk, nav);
+  }
+
+  pub fn remove(&mut self, chunk: &ChunkId) -> Option<NavChunk> {
+    self.chunks.remove(chunk)
+  }
+
+  #[inline]
+  pub fn get(&self, chunk: &ChunkId) -> Option<&NavChunk> {
+    self.chunks.get(chunk)
+  }
+
+  pub fn is_walkable<L: ChunkLayout>(&self, layout: &L, voxel: &VoxelId) -> bool {
+    self
+      .get(&layout.voxel_to_chunk(voxel))
+      .map_or(false, |nav| nav.is_walkable(voxel))
+  }
+
+  /// Voxels to walk through from `start` to `goal`, both included. `None` if either isn't walkable
+  /// or there is no way between them through loaded chunks.
+  pub fn find_path<L: ChunkLayout>(
+    &self,
+    layout: &L,
+    start: VoxelId,
+    goal: VoxelId,
+  ) -> Option<Vec<VoxelId>> {
+    let start_chunk = layout.voxel_to_chunk(&start);
+    let goal_chunk = layout.voxel_to_chunk(&goal);
+    let start_nav = self.chunks.get(&start_chunk)?;
+    let goal_nav = self.chunks.get(&goal_chunk)?;
+    if !start_nav.is_walkable(&start) || !goal_nav.is_walkable(&goal) {
+      return None;
+    }
+    if start_chunk == goal_chunk {
+      if let Some(path) = start_nav.path(start, goal, self.settings) {
+        return Some(path);
+      }
+    }
+
+    // hook the start and the goal up to the entrances of their chunks
+    let entrance_distances = |nav: &NavChunk, from: VoxelId| -> Vec<(VoxelId, u32)> {
+      let distances = nav.distances(from, self.settings);
+      nav
+        .entrances
+        .iter()
+        .filter_map(|(entrance, _)| distances.get(entrance).map(|d| (*entrance, *d)))
+        .collect()
+    };
+    let from_start = entrance_distances(start_nav, start);
+    let to_goal: HashMap<_, _> = entrance_distances(goal_nav, goal).into_iter().collect();
+
+    let waypoints = a_star(
+      start,
+      goal,
+      |node, edges| {
+        if node == start {
+          edges.extend(from_start.iter().copied());
+        }
+        if let Some(nav) = self.chunks.get(&layout.voxel_to_chunk(&node)) {
+          edges.extend(nav.edges.get(&node).into_iter().flatten().copied());
+          // across the border, if the chunk on the other side is loaded
+          for (entrance, outside) in nav.entrances.iter() {
+            if *entrance == node && self.is_walkable(layout, outside) {
+              edges.push((*outside, 1));
+            }
+          }
+        }
+        if let Some(distance) = to_goal.get(&node) {
+          edges.push((goal, *distance));
+        }
+      },
+      |node| manhattan(node, goal),
+    )?;
+
+    // walk between the waypoints, which are either in the same chunk or right across a border
+    let mut path = vec![start];
+    for pair in waypoints.windows(2) {
+      let chunk = layout.voxel_to_chunk(&pair[0]);
+      if chunk == layout.voxel_to_chunk(&pair[1]) {
+        let walk = self.chunks[&chunk].path(pair[0], pair[1], self.settings)?;
+        path.extend(walk.into_iter().skip(1));
+      } else {
+        path.push(pair[1]);
+      }
+    }
+    Some(path)
+  }
+}
+
+// Merges border crossings next to each other into entrances. Both chunks have to pick the same
+// crossings for their entrances, so crossings are only merged when they are next to each other on
+// both sides, and the stretches are split up in the same order on both sides.
+fn group_portals(portals: &[(VoxelId, VoxelId)], settings: NavSettings) -> Vec<(VoxelId, VoxelId)> {
+  let adjacent = |a: &VoxelId, b: &VoxelId| {
+    let d = *a - *b;
+    d.x().abs() + d.z().abs() == 1 && d.y().unsigned_abs() <= settings.max_step
+  };
+  let key = |v: &VoxelId| (v.x(), v.y(), v.z());
+  let ordered = |(a, b): &(VoxelId, VoxelId)| {
+    let (a, b) = (key(a), key(b));
+    (a.min(b), a.max(b))
+  };
+
+  let mut group: Vec<usize> = (0..portals.len()).collect();
+  fn root(group: &mut [usize], mut i: usize) -> usize {
+    while group[i] != i {
+      group[i] = group[group[i]];
+      i = group[i];
+    }
+    i
+  }
+  for (i, a) in portals.iter().enumerate() {
+    for (j, b) in portals.iter().enumerate().skip(i + 1) {
+      if adjacent(&a.0, &b.0) && adjacent(&a.1, &b.1) {
+        let (a, b) = (root(&mut group, i), root(&mut group, j));
+        group[a] = b;
+      }
+    }
+  }
+
+  let mut stretches: HashMap<usize, Vec<(VoxelId, VoxelId)>> = HashMap::new();
+  for (i, portal) in portals.iter().enumerate() {
+    stretches
+      .entry(root(&mut group, i))
+      .or_default()
+      .push(*portal);
+  }
+  // long stretches get more than one entrance, or paths would all go through a single voxel
+  stretches
+    .into_values()
+    .flat_map(|mut stretch| {
+      stretch.sort_by_key(ordered);
+      stretch
+        .chunks(ENTRANCE_SPACING)
+        .map(|entrance| entrance[0])
+        .collect::<Vec<_>>()
+    })
+    .collect()
+}
+
+#[inline]
+fn manhattan(a: VoxelId, b: VoxelId) -> u32 {
+  let d = a - b;
+  // a move goes one voxel sideways, climbing on the way
+  (d.x().unsigned_abs() + d.z().unsigned_abs()).max(d.y().unsigned_abs())
+}
+
+pub(crate) fn a_star<N: Copy + Eq + Hash>(
+  start: N,
+  goal: N,
+  mut neighbors: impl FnMut(N, &mut Vec<(N, u32)>),
+  heuristic: impl Fn(N) -> u32,
+) -> Option<Vec<N>> {
+  // nodes are kept in a list so the heap doesn't need to order them
+  let mut nodes = vec![start];
+  let mut index = HashMap::from([(start, 0)]);
+  let mut costs = vec![0u32];
+  let mut came_from = vec![usize::MAX];
+  let mut open = BinaryHeap::from([Reverse((heuristic(start), 0u32, 0usize))]);
+  let mut edges = Vec::new();
+
+  while let Some(Reverse((_, cost, i))) = open.pop() {
+    if cost > costs[i] {
+      continue;
+    }
+    if nodes[i] == goal {
+      let mut path = vec![nodes[i]];
+      let mut i = i;
+      while came_from[i] != usize::MAX {
+        i = came_from[i];
+        path.push(nodes[i]);
+      }
+      path.reverse();
+      return Some(path);
+    }
+
+    edges.clear();
+    neighbors(nodes[i], &mut edges);
+    for (next, edge_cost) in edges.iter() {
+      let next_cost = cost + edge_cost;
+      let j = *index.entry(*next).or_insert_with(|| {
+        nodes.push(*next);
+        costs.push(u32::MAX);
+        came_from.push(usize::MAX);
+        nodes.len() - 1
+      });
+      if next_cost < costs[j] {
+        costs[j] = next_cost;
+        came_from[j] = i;
+        open.push(Reverse((next_cost + heuristic(*next), next_cost, j)));
+      }
+    }
+  }
+  None
+}
+
+// the chunk's walkable voxels are in the NavGraph
+#[derive(Debug, Default, Component)]
+pub struct ChunkNavigable;
+
+// Keeps a NavGraph of the loaded chunks, for anything that walks the terrain
+pub struct VoxelNavigationPlugin<L = CubicVoxelLayout> {
+  pub settings: NavSettings,
+  layout: PhantomData<L>,
+}
+impl<L> VoxelNavigationPlugin<L> {
+  pub fn new(settings: NavSettings) -> Self {
+    Self {
+      settings,
+      layout: PhantomData,
+    }
+  }
+}
+
+impl Default for VoxelNavigationPlugin {
+  fn default() -> Self {
+    Self::new(NavSettings::default())
+  }
+}
+
+impl<L: ChunkLayout> Plugin for VoxelNavigationPlugin<L> {
+  fn build(&self, app: &mut App) {
+    app
+      .insert_resource(NavGraph::new(self.settings))
+      .add_system(build_nav_chunks::<L>)
+      .add_system(load_nav_chunks)
+      .add_system(unload_nav_chunks);
+  }
+}
+
+pub fn build_nav_chunks<L: ChunkLayout>(
+  mut commands: Commands,
+  layout: Res<L>,
+  graph: Res<NavGraph>,
+  thread_pool: Res<AsyncComputeTaskPool>,
+  query: Query<
+    (Entity, &Chunk, &ChunkVoxelData),
+    (Without<ChunkNavigable>, Without<Task<NavChunk>>),
+  >,
+) {
+  for (entity, chunk, voxel_data) in query.iter() {
+    let voxel_data = voxel_data.clone();
+    let shape = layout.shape().clone();
+    let origin = layout.get_origin(&chunk.id);
+    let mask = layout.chunk_column_mask(&chunk.id);
+    let settings = graph.settings;
+    commands.entity(entity).insert(
+      thread_pool.spawn(async move {
+        NavChunk::new(&voxel_data, &shape, origin, mask.as_deref(), settings)
+      }),
+    );
+  }
+}
+
+pub fn load_nav_chunks(
+  mut commands: Commands,
+  mut graph: ResMut<NavGraph>,
+  mut tasks: Query<(Entity, &Chunk, &mut Task<NavChunk>)>,
+) {
+  for (entity, chunk, mut task) in tasks.iter_mut() {
+    if let Some(nav) = future::block_on(future::poll_once(&mut *task)) {
+      graph.insert(chunk.id, nav);
+      commands
+        .entity(entity)
+        .remove::<Task<NavChunk>>()
+        .insert(ChunkNavigable);
+    }
+  }
+}
+
+pub fn unload_nav_chunks(tracker: Res<ChunkTracker>, mut graph: ResMut<NavGraph>) {
+  if tracker.is_changed() {
+    graph.chunks.retain(|chunk, _| tracker.is_loaded(chunk));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{vox::placed_sdf, CubicVoxelLayout};
+
+  // flat ground at y = 0 with a wall along x = 7, open at z = 2
+  fn nav_chunk(layout: &CubicVoxelLayout, chunk: ChunkId) -> NavChunk {
+    let shape = layout.shape();
+    let origin = layout.get_origin(&chunk);
+    let materials: Vec<_> = (0..shape.size())
+      .map(|i| {
+        let [x, y, z] = shape.delinearize(i);
+        let voxel = origin + VoxelId::new(x as i32, y as i32, z as i32);
+        let wall = voxel.x() == 7 && voxel.z() != 2 && voxel.y() <= 2;
+        if voxel.y() <= 0 || wall {
+          VoxelType::Dirt
+        } else {
+          VoxelType::Air
+        }
+      })
+      .collect();
+    let sdf: Vec<_> = materials.iter().map(|m| placed_sdf(*m)).collect();
+    let data = ChunkVoxelData::new(&sdf, &materials);
+    NavChunk::new(&data, shape, origin, None, NavSettings::default())
+  }
+
+  #[test]
+  fn paths_should_cross_chunks_and_go_around_walls() {
+    let layout = CubicVoxelLayout::new(ChunkId::default(), 1.0, 2, 6);
+    let mut graph = NavGraph::new(NavSettings::default());
+    for x in -1..=2 {
+      for y in -1..=1 {
+        let chunk = ChunkId::new(x, y);
+        graph.insert(chunk, nav_chunk(&layout, chunk));
+      }
+    }
+
+    let (start, goal) = (VoxelId::new(-4, 1, -4), VoxelId::new(11, 1, -3));
+    let path = graph.find_path(&layout, start, goal).unwrap();
+    assert_eq!((path[0], path[path.len() - 1]), (start, goal));
+    for pair in path.windows(2) {
+      assert_eq!(manhattan(pair[0], pair[1]), 1);
+      assert!(graph.is_walkable(&layout, &pair[1]));
+    }
+    assert!(path.contains(&VoxelId::new(7, 1, 2)));
+
+    // the only gap in the wall is in the middle chunk
+    graph.remove(&ChunkId::new(1, 0));
+    assert_eq!(graph.find_path(&layout, start, goal), None);
+    assert!(graph
+      .find_path(&layout, start, VoxelId::new(5, 1, 4))
+      .is_some());
+  }
+}
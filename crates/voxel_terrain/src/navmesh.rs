@@ -0,0 +1,624 @@
+use super::{
+  mesher::{MeshBuffers, MesherScratch},
+  navigation::a_star,
+  tracker::ChunkTracker,
+  Chunk, ChunkId, ChunkLayout, ChunkVoxelData, CubicVoxelLayout, VoxelId,
+};
+use bevy::{
+  prelude::*,
+  tasks::{AsyncComputeTaskPool, Task},
+};
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use futures_lite::future;
+use std::{
+  collections::{HashMap, VecDeque},
+  marker::PhantomData,
+};
+
+// edges of neighboring tiles are matched by their ends, rounded to this fraction of a voxel
+const EDGE_PRECISION: f32 = 1024.0;
+// path costs are whole numbers, in this fraction of a voxel
+const COST_PRECISION: f32 = 1000.0;
+
+// a triangle of a tile
+type Polygon = (ChunkId, u32);
+type EdgeKey = ([i32; 3], [i32; 3]);
+
+/// Size and climbing ability of the agents a navmesh is built for, in voxels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NavMeshSettings {
+  /// Walkable surface closer than this to a wall or a drop is left out.
+  pub agent_radius: f32,
+  /// Free space needed above the surface.
+  pub agent_height: f32,
+  /// Steepest walkable slope, in radians.
+  pub max_slope: f32,
+}
+impl Default for NavMeshSettings {
+  fn default() -> Self {
+    Self {
+      agent_radius: 0.5,
+      agent_height: 2.0,
+      max_slope: std::f32::consts::FRAC_PI_4,
+    }
+  }
+}
+
+/// The walkable triangles of a single chunk's smooth terrain mesh, in voxel space.
+#[derive(Debug, Default, Clone)]
+pub struct NavMeshTile {
+  vertices: Vec<Vec3>,
+  triangles: Vec<[u32; 3]>,
+  // the triangle across each edge, edge k goes from corner k to corner k + 1
+  neighbors: Vec<[Option<u32>; 3]>,
+}
+
+impl NavMeshTile {
+  /// Picks the walkable triangles out of a mesh made by `surface_nets_mesh` (or
+  /// `generate_mesh2`) from the chunk's voxel data. `origin` is the world position of the voxel
+  /// data, `mask` the chunk's columns as given by [`ChunkLayout::chunk_column_mask`].
+  pub fn new(
+    mesh: &MeshBuffers,
+    data: &ChunkVoxelData,
+    shape: &RuntimeShape<u32, 3>,
+    origin: VoxelId,
+    mask: Option<&[bool]>,
+    settings: NavMeshSettings,
+  ) -> Self {
+    let [width, height, depth] = shape.as_array().map(|s| s as i32);
+    let inside = |x: i32, y: i32, z: i32| {
+      (0..width).contains(&x) && (0..height).contains(&y) && (0..depth).contains(&z)
+    };
+    // surface nets puts the sdf samples on whole numbers, anything outside the data is open
+    let open = |p: Vec3| {
+      let [x, y, z] = [p.x.round() as i32, p.y.round() as i32, p.z.round() as i32];
+      !inside(x, y, z) || data.sdf(shape.linearize([x as u32, y as u32, z as u32]) as usize) > 0.0
+    };
+    // neighboring chunks mesh the same surface where their voxel data overlaps
+    let owned = |p: Vec3| match mask {
+      Some(mask) => {
+        let [x, z] = [p.x.round() as i32, p.z.round() as i32];
+        inside(x, 0, z) && mask[(x + z * width) as usize]
+      }
+      None => true,
+    };
+
+    let positions: Vec<Vec3> = mesh.positions.iter().map(|p| Vec3::from(*p)).collect();
+    let min_up = settings.max_slope.cos();
+    let mut walkable = Vec::new();
+    let mut blocked = vec![false; positions.len()];
+    for triangle in mesh.indices.chunks_exact(3) {
+      let [a, b, c] = [0, 1, 2].map(|i| positions[triangle[i] as usize]);
+      let centroid = (a + b + c) / 3.0;
+      if !owned(centroid) {
+        continue;
+      }
+      // the winding isn't fixed, the vertex normals point out of the terrain
+      let mut normal = (b - a).cross(c - a).normalize_or_zero();
+      let outward: Vec3 = triangle
+        .iter()
+        .map(|i| Vec3::from(mesh.normals[*i as usize]))
+        .sum();
+      if normal.dot(outward) < 0.0 {
+        normal = -normal;
+      }
+      let clear =
+        (1..=settings.agent_height.ceil() as i32).all(|h| open(centroid + Vec3::Y * h as f32));
+      if normal.y >= min_up && clear {
+        walkable.push([triangle[0], triangle[1], triangle[2]]);
+      } else {
+        for i in triangle {
+          blocked[*i as usize] = true;
+        }
+      }
+    }
+
+    // keep the agent away from anything it can't walk on, measured along the walkable surface
+    let mut clearance = vec![f32::INFINITY; positions.len()];
+    if settings.agent_radius > 0.0 {
+      let mut adjacent = vec![Vec::new(); positions.len()];
+      for triangle in walkable.iter() {
+        for k in 0..3 {
+          let (a, b) = (triangle[k] as usize, triangle[(k + 1) % 3] as usize);
+          adjacent[a].push(b);
+          adjacent[b].push(a);
+        }
+      }
+      let mut queue: VecDeque<usize> = (0..positions.len()).filter(|i| blocked[*i]).collect();
+      for i in queue.iter() {
+        clearance[*i] = 0.0;
+      }
+      while let Some(i) = queue.pop_front() {
+        for j in adjacent[i].iter() {
+          let distance = clearance[i] + positions[i].distance(positions[*j]);
+          if distance < clearance[*j] && distance < settings.agent_radius {
+            clearance[*j] = distance;
+            queue.push_back(*j);
+          }
+        }
+      }
+    }
+
+    let mut tile = Self::default();
+    let mut remap = vec![u32::MAX; positions.len()];
+    let offset = Vec3::new(origin.x() as f32, origin.y() as f32, origin.z() as f32);
+    for triangle in walkable.iter() {
+      if triangle
+        .iter()
+        .any(|i| clearance[*i as usize] < settings.agent_radius)
+      {
+        continue;
+      }
+      let triangle = triangle.map(|i| {
+        let i = i as usize;
+        if remap[i] == u32::MAX {
+          remap[i] = tile.vertices.len() as u32;
+          tile.vertices.push(positions[i] + offset);
+        }
+        remap[i]
+      });
+      tile.triangles.push(triangle);
+    }
+
+    let mut edges: HashMap<(u32, u32), Vec<u32>> = HashMap::new();
+    for (t, triangle) in tile.triangles.iter().enumerate() {
+      for k in 0..3 {
+        let (a, b) = (triangle[k], triangle[(k + 1) % 3]);
+        edges
+          .entry((a.min(b), a.max(b)))
+          .or_default()
+          .push(t as u32);
+      }
+    }
+    tile.neighbors = tile
+      .triangles
+      .iter()
+      .enumerate()
+      .map(|(t, triangle)| {
+        [0, 1, 2].map(|k| {
+          let (a, b) = (triangle[k], triangle[(k + 1) % 3]);
+          edges[&(a.min(b), a.max(b))]
+            .iter()
+            .copied()
+            .find(|other| *other != t as u32)
+        })
+      })
+      .collect();
+    tile
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.triangles.is_empty()
+  }
+
+  /// Corners of every walkable triangle, in voxel space.
+  pub fn triangles(&self) -> impl Iterator<Item = [Vec3; 3]> + '_ {
+    self
+      .triangles
+      .iter()
+      .map(|triangle| triangle.map(|i| self.vertices[i as usize]))
+  }
+
+  #[inline]
+  fn corner(&self, triangle: u32, k: usize) -> Vec3 {
+    self.vertices[self.triangles[triangle as usize][k % 3] as usize]
+  }
+
+  fn centroid(&self, triangle: u32) -> Vec3 {
+    (self.corner(triangle, 0) + self.corner(triangle, 1) + self.corner(triangle, 2)) / 3.0
+  }
+
+  // edges without a triangle on the other side within the tile
+  fn open_edges(&self) -> impl Iterator<Item = (u32, EdgeKey)> + '_ {
+    (0..self.triangles.len() as u32).flat_map(move |t| {
+      (0..3)
+        .filter(move |k| self.neighbors[t as usize][*k].is_none())
+        .map(move |k| (t, edge_key(self.corner(t, k), self.corner(t, k + 1))))
+    })
+  }
+}
+
+/// Navmesh tiles of every loaded chunk, stitched together where they meet.
+#[derive(Debug, Default)]
+pub struct NavMesh {
+  pub settings: NavMeshSettings,
+  tiles: HashMap<ChunkId, NavMeshTile>,
+  // open edges of every tile, to step from one tile onto the next
+  borders: HashMap<EdgeKey, Vec<Polygon>>,
+}
+
+impl NavMesh {
+  pub fn new(settings: NavMeshSettings) -> Self {
+    Self {
+      settings,
+      ..Default::default()
+    }
+  }
+
+  /// Adds a chunk's tile or replaces its old version.
+  pub fn insert(&mut self, chunk: ChunkId, tile: NavMeshTile) {
+    self.remove(&chunk);
+    for (triangle, key) in tile.open_edges() {
+      self.borders.entry(key).or_default().push((chunk, triangle));
+    }
+    self.tiles.insert(chunk, tile);
+  }
+
+  pub fn remove(&mut self, chunk: &ChunkId) -> Option<NavMeshTile> {
+    let tile = self.tiles.remove(chunk)?;
+    for (_, key) in tile.open_edges() {
+      if let Some(polygons) = self.borders.get_mut(&key) {
+        polygons.retain(|(other, _)| other != chunk);
+        if polygons.is_empty() {
+          self.borders.remove(&key);
+        }
+      }
+    }
+    Some(tile)
+  }
+
+  #[inline]
+  pub fn get(&self, chunk: &ChunkId) -> Option<&NavMeshTile> {
+    self.tiles.get(chunk)
+  }
+
+  /// World space points to walk along from `start` to `goal`, both included. `None` if either is
+  /// off the navmesh or there is no way between them through loaded chunks.
+  pub fn find_path<L: ChunkLayout>(
+    &self,
+    layout: &L,
+    start: Vec3,
+    goal: Vec3,
+  ) -> Option<Vec<Vec3>> {
+    let (start, goal) = (from_space(layout, start), from_space(layout, goal));
+    let from = self.locate(layout, start)?;
+    let to = self.locate(layout, goal)?;
+
+    let centroid = |(chunk, triangle): Polygon| self.tiles[&chunk].centroid(triangle);
+    let polygons = a_star(
+      from,
+      to,
+      |polygon, next| {
+        let here = centroid(polygon);
+        for (other, _, _) in self.edges(polygon) {
+          let cost = here.distance(centroid(other)) * COST_PRECISION;
+          next.push((other, cost.ceil() as u32));
+        }
+      },
+      // the centroids don't lie on the straight line, so this only guides the search
+      |polygon| (centroid(polygon).distance(goal) * COST_PRECISION) as u32,
+    )?;
+
+    // the edges crossed on the way, left and right as seen walking towards the goal
+    let mut portals = vec![(start, start)];
+    for pair in polygons.windows(2) {
+      let (_, a, b) = self
+        .edges(pair[0])
+        .into_iter()
+        .find(|(other, _, _)| *other == pair[1])?;
+      let (here, there) = (centroid(pair[0]), centroid(pair[1]));
+      if triarea2(here, there, a) > triarea2(here, there, b) {
+        portals.push((b, a));
+      } else {
+        portals.push((a, b));
+      }
+    }
+    portals.push((goal, goal));
+
+    Some(
+      string_pull(&portals)
+        .into_iter()
+        .map(|point| to_space(layout, point))
+        .collect(),
+    )
+  }
+
+  // the triangle under a point, looking at the point's chunk and the chunks around it
+  fn locate<L: ChunkLayout>(&self, layout: &L, point: Vec3) -> Option<Polygon> {
+    let voxel = VoxelId::new(
+      point.x.floor() as i32,
+      point.y.floor() as i32,
+      point.z.floor() as i32,
+    );
+    let chunk = layout.voxel_to_chunk(&voxel);
+    std::iter::once(chunk)
+      .chain(layout.get_chunk_neighbors(&chunk, 1))
+      .filter_map(|chunk| self.tiles.get(&chunk).map(|tile| (chunk, tile)))
+      .flat_map(|(chunk, tile)| {
+        (0..tile.triangles.len() as u32).filter_map(move |t| {
+          let height = height_on(point, [0, 1, 2].map(|k| tile.corner(t, k)))?;
+          Some(((chunk, t), (point.y - height).abs()))
+        })
+      })
+      .filter(|(_, distance)| *distance <= self.settings.agent_height)
+      .min_by(|a, b| a.1.total_cmp(&b.1))
+      .map(|(polygon, _)| polygon)
+  }
+
+  // neighboring triangles with the ends of the shared edge
+  fn edges(&self, (chunk, triangle): Polygon) -> Vec<(Polygon, Vec3, Vec3)> {
+    let tile = &self.tiles[&chunk];
+    let mut edges = Vec::new();
+    for k in 0..3 {
+      let (a, b) = (tile.corner(triangle, k), tile.corner(triangle, k + 1));
+      match tile.neighbors[triangle as usize][k] {
+        Some(other) => edges.push(((chunk, other), a, b)),
+        None => {
+          let across = self.borders.get(&edge_key(a, b)).into_iter().flatten();
+          for other in across.filter(|(other, _)| *other != chunk) {
+            edges.push((*other, a, b));
+          }
+        }
+      }
+    }
+    edges
+  }
+}
+
+fn edge_key(a: Vec3, b: Vec3) -> EdgeKey {
+  let round = |v: Vec3| [v.x, v.y, v.z].map(|c| (c * EDGE_PRECISION).round() as i32);
+  let (a, b) = (round(a), round(b));
+  (a.min(b), a.max(b))
+}
+
+// height of the triangle at the point's x and z, if the point is above or below it
+fn height_on(point: Vec3, [a, b, c]: [Vec3; 3]) -> Option<f32> {
+  let area = triarea2(a, b, c);
+  if area.abs() < f32::EPSILON {
+    return None;
+  }
+  let u = triarea2(point, b, c) / area;
+  let v = triarea2(a, point, c) / area;
+  let w = 1.0 - u - v;
+  if u < 0.0 || v < 0.0 || w < 0.0 {
+    return None;
+  }
+  Some(a.y * u + b.y * v + c.y * w)
+}
+
+// twice the signed area of a triangle, seen from above
+#[inline]
+fn triarea2(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+  let (ax, az) = (b.x - a.x, b.z - a.z);
+  let (bx, bz) = (c.x - a.x, c.z - a.z);
+  bx * az - ax * bz
+}
+
+// Shortest way through a row of portals, the "simple stupid funnel algorithm". The first and the
+// last portal are the start and the goal.
+fn string_pull(portals: &[(Vec3, Vec3)]) -> Vec<Vec3> {
+  let mut path = vec![portals[0].0];
+  let (mut apex, mut left, mut right) = (portals[0].0, portals[0].0, portals[0].1);
+  let (mut apex_index, mut left_index, mut right_index) = (0, 0, 0);
+  let mut i = 1;
+  while i < portals.len() {
+    let (next_left, next_right) = portals[i];
+
+    if triarea2(apex, right, next_right) <= 0.0 {
+      if apex == right || triarea2(apex, left, next_right) > 0.0 {
+        right = next_right;
+        right_index = i;
+      } else {
+        // the right side crossed over the left one, which becomes a corner of the path
+        path.push(left);
+        apex = left;
+        apex_index = left_index;
+        right = apex;
+        right_index = apex_index;
+        i = apex_index + 1;
+        continue;
+      }
+    }
+
+    if triarea2(apex, left, next_left) >= 0.0 {
+      if apex == left || triarea2(apex, right, next_left) < 0.0 {
+        left = next_left;
+        left_index = i;
+      } else {
+        path.push(right);
+        apex = right;
+        apex_index = right_index;
+        left = apex;
+        left_index = apex_index;
+        i = apex_index + 1;
+        continue;
+      }
+    }
+    i += 1;
+  }
+
+  let goal = portals[portals.len() - 1].0;
+  if path.last() != Some(&goal) {
+    path.push(goal);
+  }
+  path
+}
+
+// Voxel space is where terrain meshes are built, whole numbers are voxel corners. Chunks are
+// placed at the world position of their first voxel, with the mesh scaled by the voxel size.
+fn to_space<L: ChunkLayout>(layout: &L, point: Vec3) -> Vec3 {
+  let corner = point.floor();
+  let voxel = VoxelId::new(corner.x as i32, corner.y as i32, corner.z as i32);
+  layout.voxel_to_space(&voxel) + (point - corner) * layout.voxel_side_length()
+}
+
+fn from_space<L: ChunkLayout>(layout: &L, point: Vec3) -> Vec3 {
+  let voxel = layout.space_to_voxel(&point);
+  let corner = Vec3::new(voxel.x() as f32, voxel.y() as f32, voxel.z() as f32);
+  corner + (point - layout.voxel_to_space(&voxel)) / layout.voxel_side_length()
+}
+
+// the chunk's navmesh tile is in the NavMesh
+#[derive(Debug, Default, Component)]
+pub struct ChunkNavMeshed;
+
+// Keeps a NavMesh of the loaded chunks. The tiles come from the smooth terrain mesh, whatever
+// mesh style the terrain is drawn with
+pub struct NavMeshPlugin<L = CubicVoxelLayout> {
+  pub settings: NavMeshSettings,
+  layout: PhantomData<L>,
+}
+impl<L> NavMeshPlugin<L> {
+  pub fn new(settings: NavMeshSettings) -> Self {
+    Self {
+      settings,
+      layout: PhantomData,
+    }
+  }
+}
+impl Default for NavMeshPlugin {
+  fn default() -> Self {
+    Self::new(NavMeshSettings::default())
+  }
+}
+
+impl<L: ChunkLayout> Plugin for NavMeshPlugin<L> {
+  fn build(&self, app: &mut App) {
+    app
+      .insert_resource(NavMesh::new(self.settings))
+      .add_system(build_navmesh_tiles::<L>)
+      .add_system(load_navmesh_tiles)
+      .add_system(unload_navmesh_tiles);
+  }
+}
+
+pub fn build_navmesh_tiles<L: ChunkLayout>(
+  mut commands: Commands,
+  layout: Res<L>,
+  navmesh: Res<NavMesh>,
+  thread_pool: Res<AsyncComputeTaskPool>,
+  query: Query<
+    (Entity, &Chunk, &ChunkVoxelData),
+    (Without<ChunkNavMeshed>, Without<Task<NavMeshTile>>),
+  >,
+) {
+  for (entity, chunk, voxel_data) in query.iter() {
+    let voxel_data = voxel_data.clone();
+    let shape = layout.shape().clone();
+    let origin = layout.get_origin(&chunk.id);
+    let mask = layout.chunk_column_mask(&chunk.id);
+    let settings = navmesh.settings;
+    commands
+      .entity(entity)
+      .insert(thread_pool.spawn(async move {
+        let mesh = MesherScratch::with(|scratch| scratch.surface_nets_mesh(&voxel_data, &shape));
+        NavMeshTile::new(
+          &mesh,
+          &voxel_data,
+          &shape,
+          origin,
+          mask.as_deref(),
+          settings,
+        )
+      }));
+  }
+}
+
+pub fn load_navmesh_tiles(
+  mut commands: Commands,
+  mut navmesh: ResMut<NavMesh>,
+  mut tasks: Query<(Entity, &Chunk, &mut Task<NavMeshTile>)>,
+) {
+  for (entity, chunk, mut task) in tasks.iter_mut() {
+    if let Some(tile) = future::block_on(future::poll_once(&mut *task)) {
+      navmesh.insert(chunk.id, tile);
+      commands
+        .entity(entity)
+        .remove::<Task<NavMeshTile>>()
+        .insert(ChunkNavMeshed);
+    }
+  }
+}
+
+pub fn unload_navmesh_tiles(tracker: Res<ChunkTracker>, mut navmesh: ResMut<NavMesh>) {
+  if tracker.is_changed() {
+    let unloaded: Vec<_> = navmesh
+      .tiles
+      .keys()
+      .filter(|chunk| !tracker.is_loaded(chunk))
+      .copied()
+      .collect();
+    for chunk in unloaded {
+      navmesh.remove(&chunk);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{mesher::surface_nets_mesh, VoxelType};
+
+  // flat ground at y = 2.5 with a pillar around x = 0.5, z = 0
+  fn tile(layout: &CubicVoxelLayout, chunk: ChunkId) -> NavMeshTile {
+    let shape = layout.shape();
+    let origin = layout.get_origin(&chunk);
+    let sdf: Vec<_> = (0..shape.size())
+      .map(|i| {
+        let [x, y, z] = shape.delinearize(i);
+        let [x, y, z] = [
+          origin.x() as f32 + x as f32,
+          origin.y() as f32 + y as f32,
+          origin.z() as f32 + z as f32,
+        ];
+        let pillar = ((x - 0.5).abs() - 1.0).max(z.abs() - 1.5).max(y - 5.5);
+        (y - 2.5).min(pillar)
+      })
+      .collect();
+    let materials: Vec<_> = sdf
+      .iter()
+      .map(|d| {
+        if *d < 0.0 {
+          VoxelType::Dirt
+        } else {
+          VoxelType::Air
+        }
+      })
+      .collect();
+    let data = ChunkVoxelData::new(&sdf, &materials);
+    let mesh = surface_nets_mesh(&sdf, shape);
+    NavMeshTile::new(
+      &mesh,
+      &data,
+      shape,
+      origin,
+      None,
+      NavMeshSettings::default(),
+    )
+  }
+
+  #[test]
+  fn paths_should_be_straight_on_open_ground_and_go_around_obstacles() {
+    let layout = CubicVoxelLayout::new(ChunkId::default(), 1.0, 2, 6);
+    let mut navmesh = NavMesh::new(NavMeshSettings::default());
+    for x in -1..=1 {
+      for y in -1..=1 {
+        let chunk = ChunkId::new(x, y);
+        navmesh.insert(chunk, tile(&layout, chunk));
+      }
+    }
+
+    let (start, goal) = (Vec3::new(-4.2, 2.5, 5.3), Vec3::new(6.7, 2.5, 5.3));
+    let path = navmesh.find_path(&layout, start, goal).unwrap();
+    assert_eq!(path.len(), 2);
+    assert!(path[0].distance(start) < 1e-4 && path[1].distance(goal) < 1e-4);
+
+    let (start, goal) = (Vec3::new(-3.8, 2.5, 0.3), Vec3::new(4.7, 2.5, 0.3));
+    let path = navmesh.find_path(&layout, start, goal).unwrap();
+    assert!(path.len() > 2);
+    assert!(path[path.len() - 1].distance(goal) < 1e-4);
+    for point in path.iter() {
+      assert!(
+        (point.x - 0.5).abs() > 1.0 || point.z.abs() > 1.5,
+        "{:?}",
+        point
+      );
+    }
+
+    // the pillar can't be walked around without the chunks beside it
+    navmesh.remove(&ChunkId::new(0, 1));
+    navmesh.remove(&ChunkId::new(0, -1));
+    assert_eq!(navmesh.find_path(&layout, start, goal), None);
+  }
+}
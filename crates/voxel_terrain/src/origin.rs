@@ -0,0 +1,185 @@
+use super::{layout::ChunkLayout, ChunkId, ChunkSpawner, VoxelTerrainSettings};
+use bevy::prelude::*;
+
+// root entities with this component keep their Transform when the origin moves, for things that
+// aren't placed in world space, or that move themselves along on FloatingOriginShifted
+#[derive(Debug, Default, Component)]
+pub struct IgnoreFloatingOrigin;
+
+// sent after every root Transform was moved by `offset` because the origin moved to `origin`
+#[derive(Debug, Clone, Copy)]
+pub struct FloatingOriginShifted {
+  pub origin: ChunkId,
+  pub offset: Vec3,
+}
+
+// World space is only precise close to the origin. The ChunkSpawner drags the origin along with
+// it so it never gets too far away, wherever it is in the hierarchy. With more than one spawner
+// there is no single place that needs the precision, and the origin stays where it is.
+//
+// Runs after all the update systems so transforms that were just placed using the old origin are
+// moved along with everything else, and before transform propagation so that everything moves
+// within the same frame.
+pub fn recenter_floating_origin<L: ChunkLayout>(
+  settings: Res<VoxelTerrainSettings>,
+  mut layout: ResMut<L>,
+  mut shifted: EventWriter<FloatingOriginShifted>,
+  spawners: Query<&GlobalTransform, With<ChunkSpawner>>,
+  mut transforms: Query<&mut Transform, (Without<Parent>, Without<IgnoreFloatingOrigin>)>,
+) {
+  let threshold = match settings.floating_origin_threshold {
+    Some(threshold) => threshold,
+    None => return,
+  };
+  // a spawner's GlobalTransform is from the last frame, close enough to tell whether it left the
+  // origin chunk behind
+  let focus = match spawners.get_single() {
+    Ok(transform) => transform.translation,
+    Err(_) => return,
+  };
+
+  let current_chunk = layout.space_to_chunk(&focus);
+  if layout.chunk_ring_distance(&current_chunk, &layout.origin()) <= threshold {
+    return;
+  }
+
+  // the new origin chunk's position in the old space becomes zero, moving the roots moves
+  // everything below them
+  let offset = -layout.chunk_to_space(&current_chunk);
+  layout.set_origin(current_chunk);
+  for mut transform in transforms.iter_mut() {
+    transform.translation += offset;
+  }
+
+  shifted.send(FloatingOriginShifted {
+    origin: current_chunk,
+    offset,
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::CubicVoxelLayout;
+
+  #[test]
+  fn shifting_should_keep_the_terrain_around_the_camera() {
+    let mut app = App::new();
+    app
+      .insert_resource(VoxelTerrainSettings {
+        floating_origin_threshold: Some(2),
+        ..Default::default()
+      })
+      .insert_resource(CubicVoxelLayout::new(ChunkId::default(), 1.0, 2, 4))
+      .add_event::<FloatingOriginShifted>()
+      .add_system(recenter_floating_origin::<CubicVoxelLayout>);
+
+    // three chunks out, one more than the threshold
+    let camera_at = Vec3::new(15.5, 8.0, -2.0);
+    let chunk_at = Vec3::new(10.0, 0.0, 0.0);
+    let hud_at = Vec3::new(1.0, 2.0, 3.0);
+    let camera = app
+      .world
+      .spawn()
+      .insert(Transform::from_translation(camera_at))
+      .insert(GlobalTransform::from_translation(camera_at))
+      .insert(ChunkSpawner::default())
+      .id();
+    let chunk = app
+      .world
+      .spawn()
+      .insert(Transform::from_translation(chunk_at))
+      .id();
+    let hud = app
+      .world
+      .spawn()
+      .insert(Transform::from_translation(hud_at))
+      .insert(IgnoreFloatingOrigin)
+      .id();
+    app.update();
+
+    let layout = app.world.get_resource::<CubicVoxelLayout>().unwrap();
+    assert_eq!(layout.origin(), ChunkId::new(3, 0));
+    let translation = |entity| app.world.get::<Transform>(entity).unwrap().translation;
+    assert_eq!(
+      translation(camera) - translation(chunk),
+      camera_at - chunk_at
+    );
+    assert!(translation(camera).length() < camera_at.length());
+    assert_eq!(translation(hud), hud_at);
+
+    let events = app
+      .world
+      .get_resource::<Events<FloatingOriginShifted>>()
+      .unwrap();
+    let shifts: Vec<_> = events.get_reader().iter(events).copied().collect();
+    assert_eq!(shifts.len(), 1);
+    assert_eq!(translation(chunk), chunk_at + shifts[0].offset);
+
+    // close to the new origin, nothing moves
+    app.update();
+    let camera_now = app.world.get::<Transform>(camera).unwrap().translation;
+    let chunk_now = app.world.get::<Transform>(chunk).unwrap().translation;
+    assert_eq!(camera_now - chunk_now, camera_at - chunk_at);
+    assert_eq!(chunk_now, chunk_at + shifts[0].offset);
+  }
+
+  fn app(threshold: i32) -> App {
+    let mut app = App::new();
+    app
+      .insert_resource(VoxelTerrainSettings {
+        floating_origin_threshold: Some(threshold),
+        ..Default::default()
+      })
+      .insert_resource(CubicVoxelLayout::new(ChunkId::default(), 1.0, 2, 4))
+      .add_event::<FloatingOriginShifted>()
+      .add_system(recenter_floating_origin::<CubicVoxelLayout>);
+    app
+  }
+
+  #[test]
+  fn parented_spawners_should_move_their_root() {
+    let mut app = app(2);
+    let player_at = Vec3::new(15.0, 0.0, 0.0);
+    let player = app
+      .world
+      .spawn()
+      .insert(Transform::from_translation(player_at))
+      .id();
+    // the camera sits on the player, its GlobalTransform has the world position
+    let camera_at = player_at + Vec3::Y;
+    let camera = app
+      .world
+      .spawn()
+      .insert(Transform::from_translation(Vec3::Y))
+      .insert(GlobalTransform::from_translation(camera_at))
+      .insert(ChunkSpawner::default())
+      .insert(Parent(player))
+      .id();
+    app.update();
+
+    let layout = app.world.get_resource::<CubicVoxelLayout>().unwrap();
+    assert_eq!(layout.origin(), ChunkId::new(3, 0));
+    let translation = |entity| app.world.get::<Transform>(entity).unwrap().translation;
+    assert!(translation(player).length() < player_at.length());
+    assert_eq!(translation(camera), Vec3::Y);
+  }
+
+  #[test]
+  fn origin_should_stay_with_more_than_one_spawner() {
+    let mut app = app(2);
+    for x in [15.0, -15.0] {
+      let at = Vec3::new(x, 0.0, 0.0);
+      app
+        .world
+        .spawn()
+        .insert(Transform::from_translation(at))
+        .insert(GlobalTransform::from_translation(at))
+        .insert(ChunkSpawner::default());
+    }
+    app.update();
+
+    let layout = app.world.get_resource::<CubicVoxelLayout>().unwrap();
+    assert_eq!(layout.origin(), ChunkId::default());
+  }
+}
@@ -0,0 +1,266 @@
+use super::{
+  light::{self, ChunkLight},
+  ChunkId, ChunkLayout, ChunkTracker, ChunkVoxelData, VoxelId,
+};
+use bevy::prelude::*;
+use block_mesh::ndshape::Shape;
+use std::collections::{HashMap, HashSet};
+
+// padding of small hexagonal chunks reaches into the second ring
+pub(crate) const PADDING_RINGS: i32 = 2;
+
+/// Index of a voxel in the chunk's voxel data, `None` if the voxel data doesn't reach it.
+pub fn voxel_index<L: ChunkLayout>(layout: &L, chunk: &ChunkId, voxel: &VoxelId) -> Option<usize> {
+  let local = *voxel - layout.get_origin(chunk);
+  let local = [local.x(), local.y(), local.z()];
+  let size = layout.shape().as_array();
+  if (0..3).any(|i| local[i] < 0 || local[i] >= size[i] as i32) {
+    return None;
+  }
+  Some(layout.shape().linearize(local.map(|c| c as u32)) as usize)
+}
+
+/// Every chunk whose voxel data has the voxel, with its index there. The chunk the voxel belongs
+/// to comes first, the rest have it in their padding.
+///
+/// ```
+/// use voxel_terrain::{chunks_containing, ChunkId, CubicVoxelLayout, VoxelId};
+///
+/// let layout = CubicVoxelLayout::new(ChunkId::default(), 1.0, 2, 4);
+/// // on the +x edge of the origin chunk, in the padding of the chunk next to it
+/// let chunks: Vec<_> = chunks_containing(&layout, &VoxelId::new(2, 0, 0))
+///   .into_iter()
+///   .map(|(chunk, _)| chunk)
+///   .collect();
+/// assert_eq!(chunks, vec![ChunkId::new(0, 0), ChunkId::new(1, 0)]);
+/// ```
+pub fn chunks_containing<L: ChunkLayout>(layout: &L, voxel: &VoxelId) -> Vec<(ChunkId, usize)> {
+  let owner = layout.voxel_to_chunk(voxel);
+  std::iter::once(owner)
+    .chain(layout.get_chunk_neighbors(&owner, PADDING_RINGS))
+    .filter_map(|chunk| voxel_index(layout, &chunk, voxel).map(|i| (chunk, i)))
+    .collect()
+}
+
+/// The padding of a chunk's voxel data grouped by the chunk each voxel belongs to, as indices
+/// into the chunk's voxel data and into the owner's.
+pub fn padding_by_owner<L: ChunkLayout>(
+  layout: &L,
+  chunk: &ChunkId,
+) -> HashMap<ChunkId, Vec<(usize, usize)>> {
+  let mut padding: HashMap<_, Vec<_>> = HashMap::new();
+  for voxel in layout.get_padding_voxels(chunk) {
+    let owner = layout.voxel_to_chunk(&voxel);
+    let (i, j) = (
+      voxel_index(layout, chunk, &voxel),
+      voxel_index(layout, &owner, &voxel),
+    );
+    if let (Some(i), Some(j)) = (i, j) {
+      padding.entry(owner).or_default().push((i, j));
+    }
+  }
+  padding
+}
+
+/// Copies the owner's voxels into the padding, `padding` being the owner's entry from
+/// [`padding_by_owner`]. Returns the indices in `data` that changed.
+pub fn copy_padding(
+  data: &mut ChunkVoxelData,
+  padding: &[(usize, usize)],
+  owner: &ChunkVoxelData,
+) -> Vec<usize> {
+  let mut changed = Vec::new();
+  for &(i, j) in padding {
+    let (sdf, material) = (owner.sdf(j), owner.material(j));
+    if data.sdf(i) != sdf || data.material(i) != material {
+      data.set(i, sdf, material);
+      changed.push(i);
+    }
+  }
+  changed
+}
+
+// whether the voxel data of two chunks overlap, cheaper than going through the padding
+fn overlaps<L: ChunkLayout>(layout: &L, a: &ChunkId, b: &ChunkId) -> bool {
+  let size = layout.shape().as_array();
+  let offset = layout.get_origin(b) - layout.get_origin(a);
+  [offset.x(), offset.y(), offset.z()]
+    .iter()
+    .zip(size)
+    .all(|(offset, size)| offset.abs() < size as i32)
+}
+
+/// The padding a freshly generated chunk swaps with its neighbors. Working it out goes through
+/// the padding of the chunk and of every neighbor, so it's done in the generation task.
+pub struct ChunkPadding {
+  // the chunk's own padding, see padding_by_owner
+  own: HashMap<ChunkId, Vec<(usize, usize)>>,
+  // the part of each neighbor's padding the chunk owns, as indices into the neighbor's voxel data
+  // and into the chunk's
+  neighbors: HashMap<ChunkId, Vec<(usize, usize)>>,
+}
+
+impl ChunkPadding {
+  pub(crate) fn new<L: ChunkLayout>(layout: &L, chunk: &ChunkId) -> Self {
+    let neighbors = layout
+      .get_chunk_neighbors(chunk, PADDING_RINGS)
+      .into_iter()
+      .filter(|neighbor| overlaps(layout, chunk, neighbor))
+      .filter_map(|neighbor| {
+        let padding = padding_by_owner(layout, &neighbor).remove(chunk)?;
+        Some((neighbor, padding))
+      })
+      .collect();
+    Self {
+      own: padding_by_owner(layout, chunk),
+      neighbors,
+    }
+  }
+}
+
+// Chunks are generated on their own, so their padding is only right as long as the neighbors
+// still look the way the generator made them. Freshly generated chunks take their padding from the
+// loaded neighbors and hand their own voxels to the neighbors' padding. Returns the loaded
+// neighbors whose padding changed, their light is already updated.
+pub(crate) fn sync_generated_padding<L: ChunkLayout>(
+  layout: &L,
+  tracker: &ChunkTracker,
+  generated: &mut HashMap<ChunkId, (Entity, ChunkVoxelData)>,
+  padding: &HashMap<ChunkId, ChunkPadding>,
+  chunks: &mut Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
+) -> HashSet<Entity> {
+  let ids: Vec<_> = generated.keys().copied().collect();
+  for id in &ids {
+    let (entity, mut data) = generated.remove(id).expect("every id was generated");
+    for (owner, padding) in &padding[id].own {
+      if let Some((_, owner_data)) = generated.get(owner) {
+        copy_padding(&mut data, padding, owner_data);
+      } else if let Some(Ok((owner_data, _))) = tracker.entity(owner).map(|e| chunks.get(e)) {
+        copy_padding(&mut data, padding, owner_data);
+      }
+    }
+    generated.insert(*id, (entity, data));
+  }
+
+  let mut changed = HashSet::new();
+  let mut relit = Vec::new();
+  for id in &ids {
+    let (_, data) = &generated[id];
+    for (neighbor, padding) in &padding[id].neighbors {
+      let entity = match tracker.entity(neighbor) {
+        Some(entity) if !generated.contains_key(neighbor) => entity,
+        _ => continue,
+      };
+      let (mut neighbor_data, light) = match chunks.get_mut(entity) {
+        Ok(chunk) => chunk,
+        Err(_) => continue,
+      };
+      let voxels = copy_padding(&mut neighbor_data, padding, data);
+      if voxels.is_empty() {
+        continue;
+      }
+      if let Some(mut light) = light {
+        for i in voxels {
+          light.update(&neighbor_data, layout.shape(), i);
+        }
+        relit.push(*neighbor);
+      }
+      changed.insert(entity);
+    }
+  }
+  changed.extend(light::sync_light(
+    layout,
+    tracker,
+    &mut HashMap::new(),
+    chunks,
+    relit,
+  ));
+  changed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    generator::VoxelType,
+    testing::{small_settings, StreamingHarness},
+    vox::placed_sdf,
+    CubicVoxelLayout, HexVoxelLayout, VoxelEdit,
+  };
+  use proptest::prelude::*;
+
+  fn uniform(layout: &impl ChunkLayout, material: VoxelType) -> ChunkVoxelData {
+    let size = layout.shape().usize();
+    ChunkVoxelData::new(&vec![placed_sdf(material); size], &vec![material; size])
+  }
+
+  #[test]
+  fn padding_should_match_the_neighbors_after_copying() {
+    let layout = CubicVoxelLayout::new(ChunkId::default(), 1.0, 3, 6);
+    let chunk = ChunkId::new(0, 0);
+    let mut data = uniform(&layout, VoxelType::Air);
+    let neighbor = uniform(&layout, VoxelType::Dirt);
+
+    let padding = padding_by_owner(&layout, &chunk);
+    assert_eq!(padding.len(), 8);
+    let changed = copy_padding(&mut data, &padding[&ChunkId::new(1, 0)], &neighbor);
+    // one column wide, as high as the voxel data
+    assert_eq!(changed.len(), 7 * 8);
+    for (i, _) in &padding[&ChunkId::new(1, 0)] {
+      assert_eq!(data.material(*i), VoxelType::Dirt);
+    }
+    assert!(copy_padding(&mut data, &padding[&ChunkId::new(1, 0)], &neighbor).is_empty());
+  }
+
+  #[test]
+  fn edits_on_a_border_should_reach_the_neighbors_padding() {
+    let settings = small_settings(1, 2);
+    let mut harness = StreamingHarness::<CubicVoxelLayout>::new(settings);
+    assert!(harness.run_until_meshed(5000).is_some());
+
+    // the +x edge of the origin chunk, high up in the air
+    let voxel = VoxelId::new(4, 20, 0);
+    harness
+      .app
+      .world
+      .get_resource_mut::<Events<VoxelEdit>>()
+      .unwrap()
+      .send(VoxelEdit {
+        voxel,
+        material: VoxelType::Lamp,
+      });
+    harness.step();
+    assert!(harness.run_until_meshed(5000).is_some());
+
+    let world = &mut harness.app.world;
+    let layout = world.get_resource::<CubicVoxelLayout>().unwrap();
+    let containing = chunks_containing(layout, &voxel);
+    assert_eq!(containing.len(), 2);
+    let tracker = world.get_resource::<ChunkTracker>().unwrap();
+    for (chunk, i) in containing {
+      let entity = tracker.entity(&chunk).unwrap();
+      let data = world.get::<ChunkVoxelData>(entity).unwrap();
+      assert_eq!(data.material(i), VoxelType::Lamp, "{:?}", chunk);
+    }
+  }
+
+  // every chunk with the voxel in its padding copies it from the owner
+  fn check_padding_sources(layout: &impl ChunkLayout, voxel: VoxelId) {
+    let owner = layout.voxel_to_chunk(&voxel);
+    let j = voxel_index(layout, &owner, &voxel).unwrap();
+    let chunks = chunks_containing(layout, &voxel);
+    assert_eq!(chunks[0], (owner, j));
+    for (chunk, i) in chunks.into_iter().skip(1) {
+      assert!(padding_by_owner(layout, &chunk)[&owner].contains(&(i, j)));
+    }
+  }
+
+  proptest! {
+      #[test]
+      fn padding_should_come_from_the_chunk_that_owns_it(x in -300i32..=300, y in 0i32..4, z in -300i32..=300, radius in 1u32..=12) {
+          let voxel = VoxelId::new(x, y, z);
+          check_padding_sources(&CubicVoxelLayout::new(ChunkId::default(), 1.0, radius, 4), voxel);
+          check_padding_sources(&HexVoxelLayout::new(ChunkId::default(), 1.0, radius, 4), voxel);
+      }
+  }
+}
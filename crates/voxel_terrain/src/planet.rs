@@ -0,0 +1,166 @@
+use super::{generator::VoxelType, layout::ChunkLayout, voxel_data::ChunkVoxelData, VoxelId};
+use bevy::prelude::*;
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use noise::{Fbm, MultiFractal, NoiseFn, Seedable};
+
+// sdf values further than this many voxels from the surface saturate when they are stored, only
+// voxels closer than this (plus the noise amplitude) need the noise sampled
+const SATURATED_DISTANCE: f32 = 8.0;
+
+/// Shape of a planet for [`VoxelGenerator::with_planet`](super::VoxelGenerator::with_planet).
+///
+/// The density is radial: the distance from `center` minus `radius`, displaced by up to
+/// `amplitude` voxels of noise sampled on the sphere. Planets are meant for a layout that
+/// streams chunks in 3D, like [`VolumeVoxelLayout`](super::VolumeVoxelLayout), and for
+/// [`MeshStyle::Smooth`](super::MeshStyle::Smooth). They don't have oceans (yet).
+///
+/// ```
+/// use bevy::math::Vec3;
+/// use voxel_terrain::{ChunkLayout, PlanetSettings, VolumeVoxelLayout};
+///
+/// let planet = PlanetSettings::default();
+/// let layout = VolumeVoxelLayout::default();
+/// let gravity = planet.gravity_at(&layout, Vec3::new(0.0, 500.0, 0.0));
+/// assert!((gravity - Vec3::new(0.0, -planet.gravity, 0.0)).length() < 1e-5);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanetSettings {
+  pub center: VoxelId,
+  /// Radius of the planet in voxels, before the noise is applied.
+  pub radius: f32,
+  /// How far (in voxels) the surface can be raised or lowered by noise.
+  pub amplitude: f32,
+  /// Frequency of the surface noise, in features per voxel along the surface.
+  pub frequency: f64,
+  /// Acceleration towards the center, in world units per second squared.
+  pub gravity: f32,
+}
+impl Default for PlanetSettings {
+  fn default() -> Self {
+    Self {
+      center: VoxelId::default(),
+      radius: 200.0,
+      amplitude: 20.0,
+      frequency: 0.01,
+      gravity: 9.81,
+    }
+  }
+}
+
+impl PlanetSettings {
+  /// Gravity at a point in world space, pointing towards the center of the planet. Zero at the
+  /// center itself.
+  pub fn gravity_at(&self, layout: &impl ChunkLayout, point: Vec3) -> Vec3 {
+    let center = layout.voxel_to_space(&self.center);
+    (center - point).normalize_or_zero() * self.gravity
+  }
+
+  pub(crate) fn noise(&self, seed: u32) -> Fbm {
+    Fbm::new()
+      .set_seed(seed)
+      .set_frequency(self.frequency)
+      .set_octaves(5)
+  }
+
+  // displacement of the surface in the direction of `surface`, a point on the undisplaced sphere
+  fn displacement(&self, noise: &Fbm, surface: Vec3) -> f32 {
+    let height = noise.get([surface.x as f64, surface.y as f64, surface.z as f64]) as f32;
+    // fbm can overshoot a little, amplitude is a hard limit
+    height.clamp(-1.0, 1.0) * self.amplitude
+  }
+
+  // Height of the top of the planet above a column, following the curvature of the sphere. The
+  // direction depends on the height and the height on the noise in that direction, one round of
+  // refinement is close enough for placing structures. Columns that miss the planet get the
+  // height of its center.
+  pub(crate) fn surface_height(&self, noise: &Fbm, x: i32, z: i32) -> f32 {
+    let (dx, dz) = ((x - self.center.x()) as f32, (z - self.center.z()) as f32);
+    let across = dx * dx + dz * dz;
+    let height_at = |radius: f32| (radius * radius - across).max(0.0).sqrt();
+
+    let up = Vec3::new(dx, height_at(self.radius), dz).normalize_or_zero();
+    let radius = self.radius + self.displacement(noise, up * self.radius);
+    self.center.y() as f32 + height_at(radius)
+  }
+
+  pub(crate) fn generate(
+    &self,
+    seed: u32,
+    origin: VoxelId,
+    shape: &RuntimeShape<u32, 3>,
+  ) -> ChunkVoxelData {
+    let noise = self.noise(seed);
+
+    let mut buffer = Vec::with_capacity(shape.usize());
+    let mut materials = Vec::with_capacity(shape.usize());
+    for i in 0..shape.size() {
+      let [x, y, z] = shape.delinearize(i);
+      let voxel = origin + VoxelId::new(x as i32, y as i32, z as i32) - self.center;
+      let position = Vec3::new(voxel.x() as f32, voxel.y() as f32, voxel.z() as f32);
+      let distance = position.length() - self.radius;
+
+      let sdf = if distance.abs() > self.amplitude + SATURATED_DISTANCE {
+        distance
+      } else {
+        // sample on the undisplaced sphere, so every voxel along a ray gets the same height
+        distance - self.displacement(&noise, position.normalize_or_zero() * self.radius)
+      };
+      buffer.push(sdf);
+      materials.push(if sdf <= 0.0 {
+        VoxelType::Dirt
+      } else {
+        VoxelType::Air
+      });
+    }
+    ChunkVoxelData::new(&buffer, &materials)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::VolumeVoxelLayout;
+  use proptest::prelude::*;
+
+  proptest! {
+      #[test]
+      fn planet_should_be_solid_inside_and_empty_outside(seed in 0u32..100, x in -400i32..400, y in -400i32..400, z in -400i32..400) {
+          let planet = PlanetSettings::default();
+          let shape = RuntimeShape::<u32, 3>::new([4, 4, 4]);
+          let data = planet.generate(seed, VoxelId::new(x, y, z), &shape);
+          for i in 0..shape.size() {
+              let [vx, vy, vz] = shape.delinearize(i);
+              let distance = Vec3::new((x + vx as i32) as f32, (y + vy as i32) as f32, (z + vz as i32) as f32).length();
+              if distance < planet.radius - planet.amplitude {
+                  assert_eq!(data.material(i as usize), VoxelType::Dirt);
+              } else if distance > planet.radius + planet.amplitude {
+                  assert_eq!(data.material(i as usize), VoxelType::Air);
+              }
+          }
+      }
+
+      #[test]
+      fn surface_height_should_follow_the_curvature(seed in 0u32..100, x in -300i32..300, z in -300i32..300) {
+          let planet = PlanetSettings::default();
+          let height = planet.surface_height(&planet.noise(seed), x, z);
+          // somewhere between the lowest and highest sphere the noise can reach
+          let across = (x * x + z * z) as f32;
+          let lowest = ((planet.radius - planet.amplitude).powi(2) - across).max(0.0).sqrt();
+          let highest = ((planet.radius + planet.amplitude).powi(2) - across).max(0.0).sqrt();
+          assert!(height >= lowest - 0.001 && height <= highest + 0.001, "{} at {}, {}", height, x, z);
+      }
+
+      #[test]
+      fn gravity_should_point_to_the_center(x in -1000.0f32..1000.0, y in -1000.0f32..1000.0, z in -1000.0f32..1000.0) {
+          let planet = PlanetSettings { center: VoxelId::new(10, -20, 30), ..Default::default() };
+          let layout = VolumeVoxelLayout::default();
+          let point = Vec3::new(x, y, z);
+          let gravity = planet.gravity_at(&layout, point);
+          let to_center = layout.voxel_to_space(&planet.center) - point;
+          if to_center.length() > 0.001 {
+              assert!((gravity.length() - planet.gravity).abs() < 0.001);
+              assert!(gravity.dot(to_center) > 0.0);
+          }
+      }
+  }
+}
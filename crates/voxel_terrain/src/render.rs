@@ -0,0 +1,156 @@
+use super::{
+  material::TerrainMaterial, visibility, ChunkLayout, ChunkMesh, ChunkWaterMesh, CubicVoxelLayout,
+  LayoutSettings, SettingsError, VoxelTerrainCorePlugin, VoxelTerrainSettings,
+};
+use bevy::prelude::*;
+use std::{convert::TryFrom, marker::PhantomData};
+
+#[derive(Default)]
+pub struct TempTerrainMaterial {
+  pub material: Handle<TerrainMaterial>,
+  pub tex: Handle<Image>,
+  pub normal: Handle<Image>,
+  pub water: Handle<StandardMaterial>,
+}
+
+#[derive(Debug, Default, Component)]
+pub struct ChunkWater;
+
+// the full terrain plugin, the headless core plus meshes and materials for drawing it
+pub struct VoxelTerrainPlugin<L = CubicVoxelLayout> {
+  pub settings: VoxelTerrainSettings,
+  layout: PhantomData<L>,
+}
+impl<L> VoxelTerrainPlugin<L> {
+  pub fn new(settings: VoxelTerrainSettings) -> Self {
+    Self {
+      settings,
+      layout: PhantomData,
+    }
+  }
+}
+impl Default for VoxelTerrainPlugin {
+  fn default() -> Self {
+    Self::new(VoxelTerrainSettings::default())
+  }
+}
+
+impl<L> Plugin for VoxelTerrainPlugin<L>
+where
+  L: ChunkLayout + for<'a> TryFrom<&'a LayoutSettings, Error = SettingsError>,
+{
+  fn build(&self, app: &mut App) {
+    app
+      .add_plugin(VoxelTerrainCorePlugin::<L>::new(self.settings.clone()))
+      .add_plugin(MaterialPlugin::<TerrainMaterial>::default())
+      .init_resource::<TempTerrainMaterial>()
+      .add_startup_system(load_textures)
+      .add_system(attach_chunk_mesh)
+      .add_system(attach_water_mesh)
+      .add_system(set_texture_tiled)
+      .add_system(visibility::cull_chunks::<L>);
+  }
+}
+
+pub fn load_textures(
+  asset_server: Res<AssetServer>,
+  mut terrain_mat: ResMut<TempTerrainMaterial>,
+  mut terrain_materials: ResMut<Assets<TerrainMaterial>>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+  // tempmorary, should load textures separately
+  terrain_mat.tex = asset_server.load("textures/test.png");
+  terrain_mat.normal = asset_server.load("textures/test_n.png");
+
+  terrain_mat.material = terrain_materials.add(TerrainMaterial {
+    base_color_texture: Some(terrain_mat.tex.clone()),
+    ..default()
+  });
+
+  terrain_mat.water = materials.add(StandardMaterial {
+    base_color: Color::rgba(0.1, 0.3, 0.6, 0.7),
+    perceptual_roughness: 0.1,
+    alpha_mode: AlphaMode::Blend,
+    ..default()
+  });
+}
+
+pub fn set_texture_tiled(
+  mut texture_events: EventReader<AssetEvent<Image>>,
+  mut textures: ResMut<Assets<Image>>,
+) {
+  // wgpu's sampler settings are currently hard coded,
+  // quick and dirty way to get a tiled texture
+  for event in texture_events.iter() {
+    match event {
+      AssetEvent::Created { handle } => {
+        if let Some(mut texture) = textures.get_mut(handle) {
+          texture.sampler_descriptor.address_mode_u =
+            bevy::render::render_resource::AddressMode::Repeat;
+          texture.sampler_descriptor.address_mode_v =
+            bevy::render::render_resource::AddressMode::Repeat;
+          texture.sampler_descriptor.address_mode_w =
+            bevy::render::render_resource::AddressMode::Repeat;
+        }
+      }
+      _ => (),
+    }
+  }
+}
+
+pub fn attach_chunk_mesh(
+  mut commands: Commands,
+  mut meshes: ResMut<Assets<Mesh>>,
+  terrain_mat: Res<TempTerrainMaterial>,
+  mut query: Query<(Entity, &Transform, &mut ChunkMesh, Option<&Handle<Mesh>>)>,
+) {
+  for (entity, transform, mut chunk_mesh, old_mesh) in query.iter_mut() {
+    // the mesh asset keeps the vertex data, no need to hold on to the buffers as well
+    let mesh = meshes.add(std::mem::take(&mut chunk_mesh.0).into());
+    let material = terrain_mat.material.clone();
+    let mut entity = commands.entity(entity);
+    entity.remove::<ChunkMesh>();
+    if old_mesh.is_some() {
+      // a remeshed chunk keeps its visibility and transforms, only the mesh changes
+      entity.insert(mesh).insert(material);
+    } else {
+      entity.insert_bundle(MaterialMeshBundle {
+        mesh,
+        material,
+        // the chunk was placed when it was spawned (and moved along with the floating origin)
+        transform: *transform,
+        ..default()
+      });
+    }
+  }
+}
+
+pub fn attach_water_mesh(
+  mut commands: Commands,
+  mut meshes: ResMut<Assets<Mesh>>,
+  terrain_mat: Res<TempTerrainMaterial>,
+  mut query: Query<(Entity, &mut ChunkWaterMesh, Option<&Children>)>,
+  water: Query<(), With<ChunkWater>>,
+) {
+  for (entity, mut water_mesh, children) in query.iter_mut() {
+    // an edited chunk replaces its old water surface
+    for child in children.into_iter().flat_map(|children| children.iter()) {
+      if water.get(*child).is_ok() {
+        commands.entity(*child).despawn_recursive();
+      }
+    }
+    let mesh = meshes.add(std::mem::take(&mut water_mesh.0).into());
+    commands
+      .entity(entity)
+      .remove::<ChunkWaterMesh>()
+      .with_children(|parent| {
+        parent
+          .spawn_bundle(PbrBundle {
+            mesh,
+            material: terrain_mat.water.clone(),
+            ..default()
+          })
+          .insert(ChunkWater);
+      });
+  }
+}
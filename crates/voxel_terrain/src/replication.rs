@@ -0,0 +1,665 @@
+use super::{
+  edit::{self, VoxelEdit},
+  generator::VoxelType,
+  light::ChunkLight,
+  padding,
+  settings::MAX_CHUNK_VOXELS,
+  vox::placed_sdf,
+  voxel_data::VoxelDataError,
+  Chunk, ChunkId, ChunkLayout, ChunkTracker, ChunkVoxelData, CubicVoxelLayout, VoxelId,
+  VoxelTerrainSettings,
+};
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool};
+use std::{
+  collections::{HashMap, VecDeque},
+  fmt,
+  marker::PhantomData,
+  sync::{Arc, Mutex},
+};
+
+const CHUNK: u8 = 0;
+const EDITS: u8 = 1;
+const UNLOAD: u8 = 2;
+
+/// What a server tells a client about the terrain.
+///
+/// Chunks go over the wire with their padding and quantized sdf as the server has them, so
+/// clients mesh exactly what the server would. Encoded messages are a tag byte followed by
+/// little endian numbers and LEB128 varints, the voxel data being run length encoded.
+///
+/// ```
+/// use voxel_terrain::{ReplicationMessage, VoxelEdit, VoxelId, VoxelType};
+///
+/// let message = ReplicationMessage::Edits(vec![VoxelEdit {
+///   voxel: VoxelId::new(-3, 20, 7),
+///   material: VoxelType::Lamp,
+/// }]);
+/// let bytes = message.encode();
+/// assert_eq!(bytes.len(), 6);
+/// assert_eq!(ReplicationMessage::decode(&bytes), Ok(message));
+/// ```
+#[derive(Clone, Debug)]
+pub enum ReplicationMessage {
+  /// A whole chunk, the client spawns it or replaces what it had.
+  Chunk {
+    chunk: ChunkId,
+    data: ChunkVoxelData,
+  },
+  /// Edits applied by the server, in order, to chunks the client already has.
+  Edits(Vec<VoxelEdit>),
+  /// The server stopped tracking the chunk.
+  Unload(ChunkId),
+}
+
+impl PartialEq for ReplicationMessage {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Self::Chunk { chunk: a, data: x }, Self::Chunk { chunk: b, data: y }) => {
+        a == b
+          && x.len() == y.len()
+          && x.sdf_range() == y.sdf_range()
+          && (0..x.len()).all(|i| x.sdf(i) == y.sdf(i) && x.material(i) == y.material(i))
+      }
+      (Self::Edits(a), Self::Edits(b)) => a == b,
+      (Self::Unload(a), Self::Unload(b)) => a == b,
+      _ => false,
+    }
+  }
+}
+
+impl ReplicationMessage {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    match self {
+      Self::Chunk { chunk, data } => {
+        out.push(CHUNK);
+        write_chunk_id(&mut out, chunk);
+        write_varint(&mut out, data.len() as u64);
+        let (min, max) = data.sdf_range();
+        out.extend_from_slice(&min.to_le_bytes());
+        out.extend_from_slice(&max.to_le_bytes());
+        write_runs(
+          &mut out,
+          (0..data.len()).map(|i| data.material(i).to_mat_id()),
+        );
+        write_runs(
+          &mut out,
+          (0..data.len()).map(|i| data.quantized_sdf(i) as u8),
+        );
+      }
+      Self::Edits(edits) => {
+        out.push(EDITS);
+        write_varint(&mut out, edits.len() as u64);
+        for edit in edits {
+          for c in [edit.voxel.x(), edit.voxel.y(), edit.voxel.z()] {
+            write_varint(&mut out, zigzag(c));
+          }
+          out.push(edit.material.to_mat_id());
+        }
+      }
+      Self::Unload(chunk) => {
+        out.push(UNLOAD);
+        write_chunk_id(&mut out, chunk);
+      }
+    }
+    out
+  }
+
+  pub fn decode(bytes: &[u8]) -> Result<Self, ReplicationError> {
+    let mut reader = Reader { bytes };
+    let message = match reader.u8()? {
+      CHUNK => {
+        let chunk = reader.chunk_id()?;
+        let len = reader.varint()?;
+        if len > MAX_CHUNK_VOXELS {
+          return Err(ReplicationError::TooLarge(len));
+        }
+        let len = len as usize;
+        let min = f32::from_le_bytes(reader.array()?);
+        let max = f32::from_le_bytes(reader.array()?);
+        let materials = reader
+          .runs(len)?
+          .into_iter()
+          .map(|id| VoxelType::from_mat_id(id).ok_or(ReplicationError::UnknownMaterial(id)))
+          .collect::<Result<Vec<_>, _>>()?;
+        let sdf = reader.runs(len)?;
+        let data =
+          ChunkVoxelData::from_quantized(sdf.into_iter().map(|v| v as i8), &materials, (min, max))
+            .map_err(ReplicationError::VoxelData)?;
+        Self::Chunk { chunk, data }
+      }
+      EDITS => {
+        let count = reader.varint()? as usize;
+        // every edit takes at least 4 bytes, don't trust the count with the allocation
+        let mut edits = Vec::with_capacity(count.min(reader.bytes.len() / 4));
+        for _ in 0..count {
+          let x = unzigzag(reader.varint()?);
+          let y = unzigzag(reader.varint()?);
+          let z = unzigzag(reader.varint()?);
+          let id = reader.u8()?;
+          let material = VoxelType::from_mat_id(id).ok_or(ReplicationError::UnknownMaterial(id))?;
+          edits.push(VoxelEdit {
+            voxel: VoxelId::new(x, y, z),
+            material,
+          });
+        }
+        Self::Edits(edits)
+      }
+      UNLOAD => Self::Unload(reader.chunk_id()?),
+      tag => return Err(ReplicationError::UnknownMessage(tag)),
+    };
+    if !reader.bytes.is_empty() {
+      return Err(ReplicationError::TrailingBytes(reader.bytes.len()));
+    }
+    Ok(message)
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplicationError {
+  Truncated,
+  UnknownMessage(u8),
+  UnknownMaterial(u8),
+  /// The runs of a chunk don't add up to its length.
+  BadRuns,
+  TooLarge(u64),
+  TrailingBytes(usize),
+  VoxelData(VoxelDataError),
+}
+impl fmt::Display for ReplicationError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ReplicationError::Truncated => write!(f, "message ends in the middle of a value"),
+      ReplicationError::UnknownMessage(tag) => write!(f, "unknown message type {}", tag),
+      ReplicationError::UnknownMaterial(id) => write!(f, "unknown material {}", id),
+      ReplicationError::BadRuns => write!(f, "voxel runs don't add up to the chunk size"),
+      ReplicationError::TooLarge(len) => write!(f, "chunk of {} voxels is too large", len),
+      ReplicationError::TrailingBytes(len) => write!(f, "{} bytes left after the message", len),
+      ReplicationError::VoxelData(err) => write!(f, "{}", err),
+    }
+  }
+}
+impl std::error::Error for ReplicationError {}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    out.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+#[inline]
+fn zigzag(value: i32) -> u64 {
+  ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+#[inline]
+fn unzigzag(value: u64) -> i32 {
+  let value = value as u32;
+  (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+fn write_chunk_id(out: &mut Vec<u8>, chunk: &ChunkId) {
+  for c in [chunk.x(), chunk.y(), chunk.layer()] {
+    write_varint(out, zigzag(c));
+  }
+}
+
+// a varint run length followed by the value, for every run of equal values
+fn write_runs(out: &mut Vec<u8>, values: impl Iterator<Item = u8>) {
+  let mut run: Option<(u8, u64)> = None;
+  for value in values {
+    run = match run {
+      Some((current, len)) if current == value => Some((current, len + 1)),
+      Some((current, len)) => {
+        write_varint(out, len);
+        out.push(current);
+        Some((value, 1))
+      }
+      None => Some((value, 1)),
+    };
+  }
+  if let Some((value, len)) = run {
+    write_varint(out, len);
+    out.push(value);
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], ReplicationError> {
+    if self.bytes.len() < len {
+      return Err(ReplicationError::Truncated);
+    }
+    let (taken, rest) = self.bytes.split_at(len);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn u8(&mut self) -> Result<u8, ReplicationError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn array<const N: usize>(&mut self) -> Result<[u8; N], ReplicationError> {
+    let mut array = [0; N];
+    array.copy_from_slice(self.take(N)?);
+    Ok(array)
+  }
+
+  fn varint(&mut self) -> Result<u64, ReplicationError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+      let byte = self.u8()?;
+      value |= ((byte & 0x7f) as u64) << shift;
+      if byte & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err(ReplicationError::Truncated)
+  }
+
+  fn chunk_id(&mut self) -> Result<ChunkId, ReplicationError> {
+    let x = unzigzag(self.varint()?);
+    let y = unzigzag(self.varint()?);
+    let layer = unzigzag(self.varint()?);
+    Ok(ChunkId::new(x, y).with_layer(layer))
+  }
+
+  fn runs(&mut self, len: usize) -> Result<Vec<u8>, ReplicationError> {
+    let mut values = Vec::new();
+    while values.len() < len {
+      let run = self.varint()?;
+      let value = self.u8()?;
+      if run == 0 || run > (len - values.len()) as u64 {
+        return Err(ReplicationError::BadRuns);
+      }
+      values.resize(values.len() + run as usize, value);
+    }
+    Ok(values)
+  }
+}
+
+/// Carries encoded [`ReplicationMessage`]s between the server and one client. Messages have to
+/// arrive whole and in the order they were sent.
+pub trait ReplicationTransport: Send + Sync + 'static {
+  fn send(&mut self, message: Vec<u8>);
+
+  /// The next message that arrived, `None` when there are no more for now.
+  fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Both ends of an in-process connection, for tests and single player.
+///
+/// ```
+/// use voxel_terrain::{LoopbackTransport, ReplicationTransport};
+///
+/// let (mut server, mut client) = LoopbackTransport::pair();
+/// server.send(vec![1, 2, 3]);
+/// assert_eq!(client.receive(), Some(vec![1, 2, 3]));
+/// assert_eq!(client.receive(), None);
+/// assert_eq!(server.receive(), None);
+/// ```
+pub struct LoopbackTransport {
+  outgoing: Arc<Mutex<VecDeque<Vec<u8>>>>,
+  incoming: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+impl LoopbackTransport {
+  pub fn pair() -> (Self, Self) {
+    let (a, b) = (Arc::default(), Arc::default());
+    (
+      Self {
+        outgoing: Arc::clone(&a),
+        incoming: Arc::clone(&b),
+      },
+      Self {
+        outgoing: b,
+        incoming: a,
+      },
+    )
+  }
+}
+impl ReplicationTransport for LoopbackTransport {
+  fn send(&mut self, message: Vec<u8>) {
+    self.outgoing.lock().unwrap().push_back(message);
+  }
+
+  fn receive(&mut self) -> Option<Vec<u8>> {
+    self.incoming.lock().unwrap().pop_front()
+  }
+}
+
+struct ReplicatedClient<T> {
+  transport: T,
+  // chunks the client has been sent and not told to unload, with the entity they were sent from
+  chunks: HashMap<ChunkId, Entity>,
+}
+
+/// The clients a server replicates its terrain to. Every generated chunk the server has loaded is
+/// sent to every client, followed by the edits made to it.
+pub struct ReplicationServer<T> {
+  clients: Vec<ReplicatedClient<T>>,
+}
+impl<T> Default for ReplicationServer<T> {
+  fn default() -> Self {
+    Self {
+      clients: Vec::new(),
+    }
+  }
+}
+impl<T: ReplicationTransport> ReplicationServer<T> {
+  pub fn connect(&mut self, transport: T) {
+    self.clients.push(ReplicatedClient {
+      transport,
+      chunks: HashMap::new(),
+    });
+  }
+
+  #[inline]
+  pub fn client_count(&self) -> usize {
+    self.clients.len()
+  }
+}
+
+/// The connection of a client to its server, insert it as a resource to start receiving terrain.
+pub struct ReplicationClient<T> {
+  pub transport: T,
+}
+impl<T: ReplicationTransport> ReplicationClient<T> {
+  pub fn new(transport: T) -> Self {
+    Self { transport }
+  }
+}
+
+/// Sends terrain to the clients in [`ReplicationServer`], next to `VoxelTerrainCorePlugin`.
+pub struct ReplicationServerPlugin<T, L = CubicVoxelLayout> {
+  types: PhantomData<(T, L)>,
+}
+impl<T, L> Default for ReplicationServerPlugin<T, L> {
+  fn default() -> Self {
+    Self { types: PhantomData }
+  }
+}
+impl<T: ReplicationTransport, L: ChunkLayout> Plugin for ReplicationServerPlugin<T, L> {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<ReplicationServer<T>>()
+      .add_system_to_stage(CoreStage::PostUpdate, replicate_to_clients::<L, T>);
+  }
+}
+
+/// Builds chunks from what the server in [`ReplicationClient`] sends. The client app runs
+/// `VoxelTerrainCorePlugin` without any `ChunkSpawner`, so chunks only come from the server.
+pub struct ReplicationClientPlugin<T, L = CubicVoxelLayout> {
+  types: PhantomData<(T, L)>,
+}
+impl<T, L> Default for ReplicationClientPlugin<T, L> {
+  fn default() -> Self {
+    Self { types: PhantomData }
+  }
+}
+impl<T: ReplicationTransport, L: ChunkLayout> Plugin for ReplicationClientPlugin<T, L> {
+  fn build(&self, app: &mut App) {
+    app.add_system(receive_replication::<L, T>.before(edit::apply_voxel_edits::<L>));
+  }
+}
+
+// Runs in PostUpdate, once this frame's edits and generated chunks are in. Edits go out before
+// new chunks: chunks generated this frame already have them, and the server dropped the edits to
+// chunks that weren't generated in time
+pub fn replicate_to_clients<L: ChunkLayout, T: ReplicationTransport>(
+  mut server: ResMut<ReplicationServer<T>>,
+  layout: Res<L>,
+  tracker: Res<ChunkTracker>,
+  mut edits: EventReader<VoxelEdit>,
+  chunks: Query<(Entity, &Chunk, &ChunkVoxelData)>,
+) {
+  let edits: Vec<_> = edits.iter().copied().collect();
+  for client in server.clients.iter_mut() {
+    // chunks that were unloaded, or unloaded and loaded again
+    let unloaded: Vec<_> = client
+      .chunks
+      .iter()
+      .filter(|(chunk, entity)| tracker.entity(chunk) != Some(**entity))
+      .map(|(chunk, _)| *chunk)
+      .collect();
+    for chunk in unloaded {
+      client.chunks.remove(&chunk);
+      let message = ReplicationMessage::Unload(chunk);
+      client.transport.send(message.encode());
+    }
+
+    let client_edits: Vec<_> = edits
+      .iter()
+      .filter(|edit| {
+        let owner = layout.voxel_to_chunk(&edit.voxel);
+        client.chunks.contains_key(&owner)
+      })
+      .copied()
+      .collect();
+    if !client_edits.is_empty() {
+      let message = ReplicationMessage::Edits(client_edits);
+      client.transport.send(message.encode());
+    }
+
+    for (entity, chunk, data) in chunks.iter() {
+      if client.chunks.get(&chunk.id) != Some(&entity) {
+        client.chunks.insert(chunk.id, entity);
+        let message = ReplicationMessage::Chunk {
+          chunk: chunk.id,
+          data: data.clone(),
+        };
+        client.transport.send(message.encode());
+      }
+    }
+  }
+}
+
+pub fn receive_replication<L: ChunkLayout, T: ReplicationTransport>(
+  mut commands: Commands,
+  layout: Res<L>,
+  thread_pool: Res<AsyncComputeTaskPool>,
+  settings: Res<VoxelTerrainSettings>,
+  client: Option<ResMut<ReplicationClient<T>>>,
+  mut tracker: ResMut<ChunkTracker>,
+  mut edit_events: EventWriter<VoxelEdit>,
+  mut chunks: Query<(&mut ChunkVoxelData, Option<&mut ChunkLight>)>,
+) {
+  let mut client = match client {
+    Some(client) => client,
+    None => return,
+  };
+
+  let mut received = HashMap::new();
+  while let Some(bytes) = client.transport.receive() {
+    let message = match ReplicationMessage::decode(&bytes) {
+      Ok(message) => message,
+      Err(error) => {
+        error!("dropping replication message: {}", error);
+        continue;
+      }
+    };
+    match message {
+      ReplicationMessage::Chunk { chunk, data } => {
+        let entity = match tracker.entity(&chunk) {
+          Some(entity) => entity,
+          None => {
+            let origin = layout.get_origin(&chunk);
+            let entity = commands
+              .spawn()
+              .insert(Transform::from_translation(layout.voxel_to_space(&origin)))
+              .insert(GlobalTransform::default())
+              .insert(Chunk {
+                id: chunk,
+                distance_to_nearest_spawner: 0.,
+              })
+              .id();
+            tracker.try_spawn(&chunk, entity);
+            entity
+          }
+        };
+        received.insert(chunk, (entity, data));
+      }
+      ReplicationMessage::Edits(edits) => {
+        for edit in edits {
+          // chunks received this frame only get their voxel data at the end of the stage, too
+          // late for apply_voxel_edits
+          for (chunk, i) in padding::chunks_containing(&*layout, &edit.voxel) {
+            if let Some((_, data)) = received.get_mut(&chunk) {
+              data.set(i, placed_sdf(edit.material), edit.material);
+            }
+          }
+          edit_events.send(edit);
+        }
+      }
+      ReplicationMessage::Unload(chunk) => {
+        received.remove(&chunk);
+        if let Some(entity) = tracker.try_despawn(&chunk) {
+          commands.entity(entity).despawn_recursive();
+        }
+      }
+    }
+  }
+  if received.is_empty() {
+    return;
+  }
+
+  let paddings = received
+    .keys()
+    .map(|chunk| (*chunk, padding::ChunkPadding::new(&*layout, chunk)))
+    .collect();
+  let changed =
+    padding::sync_generated_padding(&*layout, &tracker, &mut received, &paddings, &mut chunks);
+  for entity in changed {
+    let (data, light) = chunks.get(entity).expect("changed chunks are loaded");
+    let id = tracker.chunk(entity).expect("changed chunks are tracked");
+    let mut chunk = commands.entity(entity);
+    edit::invalidate_chunk(
+      &mut chunk,
+      &id,
+      data,
+      light.is_some(),
+      &settings,
+      &thread_pool,
+      &*layout,
+    );
+  }
+  for (id, (entity, data)) in received {
+    // whatever was built from the old voxel data, light included, is out of date
+    let mut chunk = commands.entity(entity);
+    chunk.remove::<ChunkLight>();
+    edit::invalidate_chunk(
+      &mut chunk,
+      &id,
+      &data,
+      false,
+      &settings,
+      &thread_pool,
+      &*layout,
+    );
+    chunk.insert(data);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    testing::{small_settings, StreamingHarness, MATERIALS},
+    ChunkMeshed, VoxelTerrainCorePlugin,
+  };
+  use proptest::prelude::*;
+
+  proptest! {
+      #[test]
+      fn chunks_should_round_trip_through_bytes(x in -1000i32..1000, y in -1000i32..1000, layer in -10i32..10, sdf in prop::collection::vec(-10.0f32..10.0, 1..600), materials in prop::collection::vec(0usize..4, 600)) {
+          let materials: Vec<_> = sdf.iter().zip(&materials).map(|(_, m)| MATERIALS[*m]).collect();
+          let message = ReplicationMessage::Chunk {
+              chunk: ChunkId::new(x, y).with_layer(layer),
+              data: ChunkVoxelData::new(&sdf, &materials),
+          };
+          let bytes = message.encode();
+          assert_eq!(ReplicationMessage::decode(&bytes), Ok(message));
+          // cutting a message short never decodes into something else
+          for len in 0..bytes.len() {
+              assert!(ReplicationMessage::decode(&bytes[..len]).is_err());
+          }
+      }
+  }
+
+  #[test]
+  fn clients_should_mirror_the_server() {
+    let settings = small_settings(1, 2);
+    let (server_end, client_end) = LoopbackTransport::pair();
+    let mut server = StreamingHarness::<CubicVoxelLayout>::new(settings.clone());
+    server
+      .app
+      .add_plugin(ReplicationServerPlugin::<LoopbackTransport>::default());
+    server
+      .app
+      .world
+      .get_resource_mut::<ReplicationServer<LoopbackTransport>>()
+      .unwrap()
+      .connect(server_end);
+
+    let mut client = App::new();
+    client
+      .add_plugins(MinimalPlugins)
+      .add_plugin(VoxelTerrainCorePlugin::<CubicVoxelLayout>::new(settings))
+      .add_plugin(ReplicationClientPlugin::<LoopbackTransport>::default())
+      .insert_resource(ReplicationClient::new(client_end));
+
+    assert!(server.run_until_meshed(5000).is_some());
+    let voxel = VoxelId::new(4, 20, 0);
+    server
+      .app
+      .world
+      .get_resource_mut::<Events<VoxelEdit>>()
+      .unwrap()
+      .send(VoxelEdit {
+        voxel,
+        material: VoxelType::Lamp,
+      });
+    for _ in 0..5000 {
+      server.step();
+      client.update();
+      let meshed = client
+        .world
+        .query_filtered::<&Chunk, With<ChunkMeshed>>()
+        .iter(&client.world)
+        .count();
+      if meshed == 9 {
+        break;
+      }
+    }
+
+    let server_world = &mut server.app.world;
+    let server_chunks: HashMap<_, _> = server_world
+      .query::<(&Chunk, &ChunkVoxelData)>()
+      .iter(server_world)
+      .map(|(chunk, data)| (chunk.id, data.clone()))
+      .collect();
+    let client_chunks: HashMap<_, _> = client
+      .world
+      .query_filtered::<(&Chunk, &ChunkVoxelData), With<ChunkMeshed>>()
+      .iter(&client.world)
+      .map(|(chunk, data)| (chunk.id, data.clone()))
+      .collect();
+    assert_eq!(client_chunks.len(), 9);
+    for (chunk, data) in client_chunks {
+      let expected = &server_chunks[&chunk];
+      assert!((0..data.len())
+        .all(|i| { data.sdf(i) == expected.sdf(i) && data.material(i) == expected.material(i) }));
+    }
+    // the edit made it into the chunk and into its neighbor's padding
+    let layout = client.world.get_resource::<CubicVoxelLayout>().unwrap();
+    let tracker = client.world.get_resource::<ChunkTracker>().unwrap();
+    for (chunk, i) in padding::chunks_containing(layout, &voxel) {
+      let entity = tracker.entity(&chunk).unwrap();
+      let data = client.world.get::<ChunkVoxelData>(entity).unwrap();
+      assert_eq!(data.material(i), VoxelType::Lamp);
+    }
+  }
+}
@@ -0,0 +1,400 @@
+use super::{ChunkId, ChunkLayout, CubicVoxelLayout, HexVoxelLayout, VolumeVoxelLayout, VoxelId};
+use block_mesh::ndshape::Shape;
+use std::{convert::TryFrom, fmt};
+
+// voxels in a chunk's padded voxel data. Every chunk allocates this many voxels while it is
+// generated and meshed, and the replication decoder won't accept more
+pub(crate) const MAX_CHUNK_VOXELS: u64 = 1 << 24;
+
+fn check_voxel_count(shape: [u64; 3]) -> Result<(), SettingsError> {
+  let count = shape
+    .iter()
+    .fold(1u64, |count, side| count.saturating_mul(*side));
+  if count > MAX_CHUNK_VOXELS {
+    return Err(SettingsError::ChunkVoxelCount(count));
+  }
+  Ok(())
+}
+
+/// Dimensions of the chunk grid, see [`CubicVoxelLayout`], [`HexVoxelLayout`] and
+/// [`VolumeVoxelLayout`]. `chunk_voxel_length` is the distance from a chunk's center to its edge
+/// for square and cube chunks and to its corners for hexagonal chunks. Cube chunks ignore
+/// `chunk_voxel_height`.
+///
+/// ```
+/// use std::convert::TryFrom;
+/// use voxel_terrain::{ChunkLayout, CubicVoxelLayout, LayoutSettings};
+///
+/// let settings = LayoutSettings {
+///   voxel_side_length: 0.5,
+///   chunk_voxel_length: 16,
+///   ..Default::default()
+/// };
+/// let layout = CubicVoxelLayout::try_from(&settings).unwrap();
+/// assert_eq!(layout.chunk_side_length(), 16.5);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayoutSettings {
+  pub origin: ChunkId,
+  pub voxel_side_length: f32,
+  pub chunk_voxel_length: u32,
+  pub chunk_voxel_height: u32,
+}
+impl Default for LayoutSettings {
+  fn default() -> Self {
+    Self {
+      origin: ChunkId::default(),
+      voxel_side_length: 1.0,
+      chunk_voxel_length: 50,
+      chunk_voxel_height: 50,
+    }
+  }
+}
+impl LayoutSettings {
+  pub fn validate(&self) -> Result<(), SettingsError> {
+    if !self.voxel_side_length.is_finite() || self.voxel_side_length <= 0.0 {
+      return Err(SettingsError::VoxelSideLength(self.voxel_side_length));
+    }
+    if self.chunk_voxel_height == 0 {
+      return Err(SettingsError::ChunkVoxelHeight);
+    }
+    // chunk sizes are converted to i32 all over the layout
+    if self.chunk_voxel_length > (i32::MAX as u32 - 1) / 2 {
+      return Err(SettingsError::ChunkVoxelLength(self.chunk_voxel_length));
+    }
+    // the padded voxel data of a column, hexagons are narrower than squares of the same length
+    let side = self.chunk_voxel_length as u64 * 2 + 3;
+    check_voxel_count([side, self.chunk_voxel_height as u64 + 2, side])
+  }
+}
+
+impl TryFrom<&LayoutSettings> for CubicVoxelLayout {
+  type Error = SettingsError;
+
+  fn try_from(settings: &LayoutSettings) -> Result<Self, Self::Error> {
+    settings.validate()?;
+    Ok(CubicVoxelLayout::new(
+      settings.origin,
+      settings.voxel_side_length,
+      settings.chunk_voxel_length,
+      settings.chunk_voxel_height,
+    ))
+  }
+}
+
+impl TryFrom<&LayoutSettings> for HexVoxelLayout {
+  type Error = SettingsError;
+
+  fn try_from(settings: &LayoutSettings) -> Result<Self, Self::Error> {
+    settings.validate()?;
+    // a hexagon needs a corner to be a hexagon
+    if settings.chunk_voxel_length == 0 {
+      return Err(SettingsError::ChunkVoxelLength(0));
+    }
+    Ok(HexVoxelLayout::new(
+      settings.origin,
+      settings.voxel_side_length,
+      settings.chunk_voxel_length,
+      settings.chunk_voxel_height,
+    ))
+  }
+}
+
+impl TryFrom<&LayoutSettings> for VolumeVoxelLayout {
+  type Error = SettingsError;
+
+  fn try_from(settings: &LayoutSettings) -> Result<Self, Self::Error> {
+    settings.validate()?;
+    // cubes are as tall as they are wide
+    check_voxel_count([settings.chunk_voxel_length as u64 * 2 + 3; 3])?;
+    Ok(VolumeVoxelLayout::new(
+      settings.origin,
+      settings.voxel_side_length,
+      settings.chunk_voxel_length,
+    ))
+  }
+}
+
+/// How chunk surfaces are turned into meshes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MeshStyle {
+  /// Block faces, merged where possible.
+  #[default]
+  Blocky,
+  /// A smooth surface through the sdf. Chunks have to fill their voxel data, so this doesn't
+  /// work with hexagonal chunks.
+  Smooth,
+}
+
+/// Configuration for the terrain plugins.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxelTerrainSettings {
+  pub layout: LayoutSettings,
+  /// Chunks within this many rings of a `ChunkSpawner` are loaded.
+  pub load_radius: i32,
+  /// Chunks further than this many chunk lengths from every `ChunkSpawner` are unloaded.
+  pub unload_radius: i32,
+  /// World space is re-centered on the `ChunkSpawner` once it is more than this many chunks
+  /// away from the origin chunk. `None` keeps the origin fixed, as does having more than one
+  /// spawner. Every root `Transform` is moved along, see `IgnoreFloatingOrigin`.
+  pub floating_origin_threshold: Option<i32>,
+  pub mesh_style: MeshStyle,
+  /// Bake skylight and the light of `Lamp` voxels into chunk meshes. The top of each chunk's
+  /// voxel data is treated as open sky, so layouts that stack chunks on top of each other are
+  /// rejected by [`VoxelTerrainSettings::validate_for`]. Off by default.
+  pub lighting: bool,
+}
+impl Default for VoxelTerrainSettings {
+  fn default() -> Self {
+    Self {
+      layout: LayoutSettings::default(),
+      load_radius: 4,
+      unload_radius: 10,
+      floating_origin_threshold: None,
+      mesh_style: MeshStyle::default(),
+      lighting: false,
+    }
+  }
+}
+impl VoxelTerrainSettings {
+  /// Checks the settings that don't depend on the layout, see
+  /// [`VoxelTerrainSettings::validate_for`].
+  pub fn validate(&self) -> Result<(), SettingsError> {
+    self.layout.validate()?;
+    if self.load_radius < 0 {
+      return Err(SettingsError::LoadRadius(self.load_radius));
+    }
+    if let Some(threshold) = self.floating_origin_threshold {
+      if threshold < 1 {
+        return Err(SettingsError::FloatingOriginThreshold(threshold));
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks the settings for terrain laid out by `layout`.
+  pub fn validate_for<L: ChunkLayout>(&self, layout: &L) -> Result<(), SettingsError> {
+    self.validate()?;
+    // the chunks in the corners of the load radius are further away than its edges, sqrt(2)
+    // times for square chunks and sqrt(3) for cubes. Anything closer than that would unload
+    // chunks that were just loaded
+    let origin = layout.origin();
+    let reach = layout
+      .get_chunk_neighbors(&origin, self.load_radius)
+      .iter()
+      .map(|chunk| layout.get_chunk_distance(&origin, chunk))
+      .fold(0.0, f32::max);
+    if (self.unload_radius as f32) * layout.chunk_side_length() < reach {
+      return Err(SettingsError::UnloadRadius(self.unload_radius));
+    }
+    // surface nets meshes the whole voxel data, it can't leave out columns of other chunks
+    if self.mesh_style == MeshStyle::Smooth && layout.chunk_column_mask(&origin).is_some() {
+      return Err(SettingsError::MeshStyle(self.mesh_style));
+    }
+    // a chunk below another one would get skylight through its ceiling
+    let above =
+      layout.get_center_voxel(&origin) + VoxelId::new(0, layout.shape().as_array()[1] as i32, 0);
+    if self.lighting && layout.voxel_to_chunk(&above) != origin {
+      return Err(SettingsError::Lighting);
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SettingsError {
+  VoxelSideLength(f32),
+  ChunkVoxelLength(u32),
+  ChunkVoxelHeight,
+  ChunkVoxelCount(u64),
+  LoadRadius(i32),
+  UnloadRadius(i32),
+  FloatingOriginThreshold(i32),
+  MeshStyle(MeshStyle),
+  Lighting,
+  ErosionRegionSize(u32),
+  ErosionBorder(u32),
+}
+impl fmt::Display for SettingsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SettingsError::VoxelSideLength(length) => {
+        write!(f, "voxel side length must be positive, got {}", length)
+      }
+      SettingsError::ChunkVoxelLength(length) => {
+        write!(
+          f,
+          "chunk voxel length {} is out of range for the layout",
+          length
+        )
+      }
+      SettingsError::ChunkVoxelHeight => write!(f, "chunk voxel height must be at least 1"),
+      SettingsError::ChunkVoxelCount(count) => write!(
+        f,
+        "chunks of {} voxels are larger than the limit of {}",
+        count, MAX_CHUNK_VOXELS
+      ),
+      SettingsError::LoadRadius(radius) => {
+        write!(f, "load radius must not be negative, got {}", radius)
+      }
+      SettingsError::UnloadRadius(radius) => write!(
+        f,
+        "unload radius {} would unload chunks inside the load radius",
+        radius
+      ),
+      SettingsError::FloatingOriginThreshold(threshold) => {
+        write!(
+          f,
+          "floating origin threshold must be at least 1, got {}",
+          threshold
+        )
+      }
+      SettingsError::MeshStyle(style) => {
+        write!(f, "{:?} meshes don't work with the chunk layout", style)
+      }
+      SettingsError::Lighting => write!(f, "lighting needs chunks that span whole columns"),
+      SettingsError::ErosionRegionSize(size) => {
+        write!(f, "erosion region size {} is out of range", size)
+      }
+      SettingsError::ErosionBorder(border) => {
+        write!(f, "erosion border {} is wider than half a region", border)
+      }
+    }
+  }
+}
+impl std::error::Error for SettingsError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  proptest! {
+      #[test]
+      fn layout_should_use_configured_dimensions(voxel_side_length in 0.01f32..10.0, voxel_length in 0u32..=50, height in 1u32..=50) {
+          let settings = LayoutSettings { voxel_side_length, chunk_voxel_length: voxel_length, chunk_voxel_height: height, ..Default::default() };
+          let layout = CubicVoxelLayout::try_from(&settings).unwrap();
+          assert_eq!(layout.voxel_side_length(), voxel_side_length);
+          assert_eq!(layout.chunk_voxel_length(), voxel_length);
+          assert_eq!(layout.chunk_voxel_height(), height);
+          assert_eq!(layout.shape().as_array(), [voxel_length * 2 + 3, height + 2, voxel_length * 2 + 3]);
+      }
+  }
+
+  #[test]
+  fn invalid_settings_should_be_rejected() {
+    let layout = |voxel_side_length, chunk_voxel_height| VoxelTerrainSettings {
+      layout: LayoutSettings {
+        voxel_side_length,
+        chunk_voxel_height,
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    assert!(VoxelTerrainSettings::default().validate().is_ok());
+    assert_eq!(
+      layout(0.0, 50).validate(),
+      Err(SettingsError::VoxelSideLength(0.0))
+    );
+    assert!(layout(f32::NAN, 50).validate().is_err());
+    assert_eq!(
+      layout(1.0, 0).validate(),
+      Err(SettingsError::ChunkVoxelHeight)
+    );
+    let cubic = CubicVoxelLayout::default();
+    assert_eq!(
+      VoxelTerrainSettings {
+        load_radius: 8,
+        unload_radius: 8,
+        ..Default::default()
+      }
+      .validate_for(&cubic),
+      Err(SettingsError::UnloadRadius(8))
+    );
+    let point = LayoutSettings {
+      chunk_voxel_length: 0,
+      ..Default::default()
+    };
+    assert!(CubicVoxelLayout::try_from(&point).is_ok());
+    assert!(HexVoxelLayout::try_from(&point).is_err());
+  }
+
+  #[test]
+  fn huge_chunks_should_be_rejected() {
+    let layout = |chunk_voxel_length, chunk_voxel_height| LayoutSettings {
+      chunk_voxel_length,
+      chunk_voxel_height,
+      ..Default::default()
+    };
+    // would overflow the u32 shape
+    assert_eq!(
+      layout(1 << 15, 1 << 16).validate(),
+      Err(SettingsError::ChunkVoxelCount(
+        ((1u64 << 16) + 3).pow(2) * ((1 << 16) + 2)
+      ))
+    );
+    assert_eq!(
+      layout(i32::MAX as u32 / 2 - 1, u32::MAX - 1).validate(),
+      Err(SettingsError::ChunkVoxelCount(u64::MAX))
+    );
+    assert!(layout(500, 50).validate().is_err());
+    // flat chunks can be wider than cubes
+    assert!(CubicVoxelLayout::try_from(&layout(200, 50)).is_ok());
+    assert_eq!(
+      VolumeVoxelLayout::try_from(&layout(200, 50)).err(),
+      Some(SettingsError::ChunkVoxelCount(403u64.pow(3)))
+    );
+  }
+
+  #[test]
+  fn unload_radius_should_cover_the_corners_of_the_load_radius() {
+    let settings = |unload_radius| VoxelTerrainSettings {
+      load_radius: 4,
+      unload_radius,
+      ..Default::default()
+    };
+    let cubic = CubicVoxelLayout::default();
+    let volume = VolumeVoxelLayout::new(ChunkId::default(), 1.0, 8);
+    // 4 * sqrt(2) = 5.7 chunks for squares, 4 * sqrt(3) = 6.9 for cubes
+    assert_eq!(
+      settings(5).validate_for(&cubic),
+      Err(SettingsError::UnloadRadius(5))
+    );
+    assert!(settings(6).validate_for(&cubic).is_ok());
+    assert_eq!(
+      settings(6).validate_for(&volume),
+      Err(SettingsError::UnloadRadius(6))
+    );
+    assert!(settings(7).validate_for(&volume).is_ok());
+  }
+
+  #[test]
+  fn smooth_meshes_should_need_whole_chunks() {
+    let smooth = VoxelTerrainSettings {
+      mesh_style: MeshStyle::Smooth,
+      ..Default::default()
+    };
+    assert!(smooth.validate_for(&CubicVoxelLayout::default()).is_ok());
+    assert_eq!(
+      smooth.validate_for(&HexVoxelLayout::default()),
+      Err(SettingsError::MeshStyle(MeshStyle::Smooth))
+    );
+  }
+
+  #[test]
+  fn lighting_should_need_whole_columns() {
+    let lit = VoxelTerrainSettings {
+      lighting: true,
+      ..Default::default()
+    };
+    let volume = VolumeVoxelLayout::new(ChunkId::default(), 1.0, 8);
+    assert!(lit.validate_for(&CubicVoxelLayout::default()).is_ok());
+    assert!(lit.validate_for(&HexVoxelLayout::default()).is_ok());
+    assert_eq!(lit.validate_for(&volume), Err(SettingsError::Lighting));
+    let unlit = VoxelTerrainSettings {
+      lighting: false,
+      ..lit
+    };
+    assert!(unlit.validate_for(&volume).is_ok());
+  }
+}
@@ -0,0 +1,511 @@
+use super::{
+  cache::LruCache,
+  erosion::Rng,
+  generator::VoxelType,
+  vox::{placed_sdf, VoxModel, VoxStamp},
+  voxel_data::ChunkVoxelData,
+  VoxelId,
+};
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use std::{f32::consts::TAU, sync::Arc};
+
+// Chunks are generated independently and in any order, so anything bigger than a chunk has to be
+// decided without looking at other chunks. The world is split into square regions much larger
+// than a chunk and structures are planned once per region, using nothing but the seed, the region
+// and the terrain height. A chunk asks every region that can reach it for its plan and writes the
+// pieces that overlap it, so every chunk sees the same structures.
+
+/// Part of a structure, written over the terrain by every chunk it overlaps.
+#[derive(Clone, Debug)]
+pub enum StructurePiece {
+  /// Every voxel between the two corners (inclusive) becomes `material`, `Air` digs a hole.
+  Fill {
+    min: VoxelId,
+    max: VoxelId,
+    material: VoxelType,
+  },
+  Stamp(VoxStamp),
+}
+
+impl StructurePiece {
+  /// First and last voxel the piece can change.
+  pub fn bounds(&self) -> (VoxelId, VoxelId) {
+    match self {
+      StructurePiece::Fill { min, max, .. } => (*min, *max),
+      StructurePiece::Stamp(stamp) => stamp.bounds(),
+    }
+  }
+
+  pub fn intersects(&self, min: VoxelId, max: VoxelId) -> bool {
+    let (piece_min, piece_max) = self.bounds();
+    piece_min.x() <= max.x()
+      && piece_min.y() <= max.y()
+      && piece_min.z() <= max.z()
+      && piece_max.x() >= min.x()
+      && piece_max.y() >= min.y()
+      && piece_max.z() >= min.z()
+  }
+
+  /// Writes the piece into voxel data that starts at `origin`.
+  pub fn apply(&self, data: &mut ChunkVoxelData, origin: VoxelId, shape: &RuntimeShape<u32, 3>) {
+    let (min, max, material) = match self {
+      StructurePiece::Fill { min, max, material } => (*min, *max, *material),
+      StructurePiece::Stamp(stamp) => {
+        stamp.stamp(data, origin, shape);
+        return;
+      }
+    };
+
+    // clip the box to the voxel data
+    let [width, height, depth] = shape.as_array();
+    let min = min - origin;
+    let max = max - origin;
+    let (x0, x1) = (min.x().max(0), max.x().min(width as i32 - 1));
+    let (y0, y1) = (min.y().max(0), max.y().min(height as i32 - 1));
+    let (z0, z1) = (min.z().max(0), max.z().min(depth as i32 - 1));
+    for z in z0..=z1 {
+      for y in y0..=y1 {
+        for x in x0..=x1 {
+          let i = shape.linearize([x as u32, y as u32, z as u32]);
+          data.set(i as usize, placed_sdf(material), material);
+        }
+      }
+    }
+  }
+}
+
+/// A square of columns structures are planned for, `size` voxels along x and z.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StructureRegion {
+  pub x: i32,
+  pub z: i32,
+  pub size: u32,
+  pub seed: u32,
+}
+
+impl StructureRegion {
+  /// First column of the region.
+  pub fn min(&self) -> (i32, i32) {
+    (self.x * self.size as i32, self.z * self.size as i32)
+  }
+
+  pub fn neighbor(&self, dx: i32, dz: i32) -> Self {
+    Self {
+      x: self.x + dx,
+      z: self.z + dz,
+      ..*self
+    }
+  }
+
+  /// A column somewhere in the middle of the region that every planner agrees on, where roads
+  /// meet and villages are built.
+  pub fn hub(&self) -> (i32, i32) {
+    let mut rng = Rng::new(self.seed, self.x, self.z);
+    let (min_x, min_z) = self.min();
+    let quarter = self.size as f32 / 4.0;
+    (
+      min_x + (quarter + rng.next_f32() * quarter * 2.0) as i32,
+      min_z + (quarter + rng.next_f32() * quarter * 2.0) as i32,
+    )
+  }
+
+  /// Random numbers for one kind of structure in this region, `salt` keeps the kinds apart.
+  pub fn rng(&self, salt: u32) -> Rng {
+    Rng::new(self.seed ^ salt, self.x, self.z)
+  }
+
+  fn key(&self) -> (u32, i32, i32) {
+    (self.seed, self.x, self.z)
+  }
+}
+
+/// Decides where one kind of structure goes.
+///
+/// `plan` has to be deterministic: the same region and heights always give the same pieces.
+pub trait StructurePlanner: Send + Sync + 'static {
+  /// Pieces for a region. `height` is the terrain height of a column, it can be sampled
+  /// anywhere but isn't free.
+  fn plan(&self, region: &StructureRegion, height: &dyn Fn(i32, i32) -> f32)
+    -> Vec<StructurePiece>;
+
+  /// How far pieces can reach outside the region they were planned for, in voxels.
+  fn reach(&self, _region_size: u32) -> u32 {
+    0
+  }
+}
+
+/// Every structure planner of a world, see
+/// [`VoxelGenerator::with_structures`](super::VoxelGenerator::with_structures).
+///
+/// ```
+/// use voxel_terrain::{Dungeons, Roads, Structures, VoxelGenerator, Villages};
+///
+/// let generator = VoxelGenerator::default().with_structures(
+///   Structures::new(256)
+///     .with(Villages::default())
+///     .with(Roads::default())
+///     .with(Dungeons::default()),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct Structures {
+  pub region_size: u32,
+  // planned regions kept around for chunks that need them later, the least recently used region
+  // is dropped first
+  pub max_cached_regions: usize,
+  planners: Vec<Arc<dyn StructurePlanner>>,
+  cache: LruCache<(u32, i32, i32), Vec<StructurePiece>>,
+}
+
+impl Structures {
+  pub fn new(region_size: u32) -> Self {
+    assert!(
+      region_size > 0,
+      "structure regions need at least one column"
+    );
+    Self {
+      region_size,
+      max_cached_regions: 256,
+      planners: Vec::new(),
+      cache: Default::default(),
+    }
+  }
+
+  /// Adds a planner, pieces of later planners are written over earlier ones.
+  pub fn with(mut self, planner: impl StructurePlanner) -> Self {
+    self.planners.push(Arc::new(planner));
+    // clones share the cache, they still plan with the old planners
+    self.cache = LruCache::default();
+    self
+  }
+
+  /// Pieces that overlap the box between two voxels, in the order they should be applied.
+  pub fn pieces_in(
+    &self,
+    seed: u32,
+    min: VoxelId,
+    max: VoxelId,
+    height: &dyn Fn(i32, i32) -> f32,
+  ) -> Vec<StructurePiece> {
+    let size = self.region_size as i32;
+    let reach = self
+      .planners
+      .iter()
+      .map(|planner| planner.reach(self.region_size))
+      .max()
+      .unwrap_or(0) as i32;
+
+    // regions are always visited in the same order, so overlapping pieces from different regions
+    // end up the same in every chunk
+    let mut pieces = Vec::new();
+    for z in (min.z() - reach).div_euclid(size)..=(max.z() + reach).div_euclid(size) {
+      for x in (min.x() - reach).div_euclid(size)..=(max.x() + reach).div_euclid(size) {
+        let region = StructureRegion {
+          x,
+          z,
+          size: self.region_size,
+          seed,
+        };
+        let plan = self.plan(&region, height);
+        pieces.extend(plan.iter().filter(|p| p.intersects(min, max)).cloned());
+      }
+    }
+    pieces
+  }
+
+  fn plan(
+    &self,
+    region: &StructureRegion,
+    height: &dyn Fn(i32, i32) -> f32,
+  ) -> Arc<Vec<StructurePiece>> {
+    self
+      .cache
+      .get_or_insert_with(region.key(), self.max_cached_regions, || {
+        self
+          .planners
+          .iter()
+          .flat_map(|planner| planner.plan(region, height))
+          .collect()
+      })
+  }
+}
+
+/// Roads connecting the hub of every region to its neighbors along x and z, following the
+/// terrain.
+#[derive(Clone, Debug)]
+pub struct Roads {
+  /// Voxels on each side of the center line.
+  pub half_width: i32,
+  pub material: VoxelType,
+  /// Voxels cleared above the road.
+  pub clearance: i32,
+}
+impl Default for Roads {
+  fn default() -> Self {
+    Self {
+      half_width: 1,
+      material: VoxelType::Dirt,
+      clearance: 4,
+    }
+  }
+}
+impl StructurePlanner for Roads {
+  fn plan(
+    &self,
+    region: &StructureRegion,
+    height: &dyn Fn(i32, i32) -> f32,
+  ) -> Vec<StructurePiece> {
+    let (x0, z0) = region.hub();
+    let mut pieces = Vec::new();
+    for (dx, dz) in [(1, 0), (0, 1)] {
+      let (x1, z1) = region.neighbor(dx, dz).hub();
+      let steps = (x1 - x0).abs().max((z1 - z0).abs()).max(1);
+      for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let x = x0 + ((x1 - x0) as f32 * t).round() as i32;
+        let z = z0 + ((z1 - z0) as f32 * t).round() as i32;
+        let y = height(x, z).round() as i32;
+        let w = self.half_width;
+        pieces.push(StructurePiece::Fill {
+          min: VoxelId::new(x - w, y - 2, z - w),
+          max: VoxelId::new(x + w, y, z + w),
+          material: self.material,
+        });
+        pieces.push(StructurePiece::Fill {
+          min: VoxelId::new(x - w, y + 1, z - w),
+          max: VoxelId::new(x + w, y + self.clearance, z + w),
+          material: VoxelType::Air,
+        });
+      }
+    }
+    pieces
+  }
+
+  fn reach(&self, region_size: u32) -> u32 {
+    // all the way to the next region's hub
+    region_size + self.half_width.max(0) as u32
+  }
+}
+
+/// A ring of houses around some of the region hubs. Houses are simple boxes unless `models`
+/// has some to pick from.
+#[derive(Clone, Debug)]
+pub struct Villages {
+  /// Chance of a region having a village.
+  pub chance: f32,
+  pub houses: u32,
+  pub models: Vec<Arc<VoxModel>>,
+}
+impl Default for Villages {
+  fn default() -> Self {
+    Self {
+      chance: 0.3,
+      houses: 5,
+      models: Vec::new(),
+    }
+  }
+}
+impl Villages {
+  // houses are at most this far from the hub
+  const RADIUS: f32 = 24.0;
+}
+impl StructurePlanner for Villages {
+  fn plan(
+    &self,
+    region: &StructureRegion,
+    height: &dyn Fn(i32, i32) -> f32,
+  ) -> Vec<StructurePiece> {
+    let mut rng = region.rng(0x5111_a9e5);
+    if rng.next_f32() >= self.chance {
+      return Vec::new();
+    }
+
+    let (hub_x, hub_z) = region.hub();
+    let mut pieces = Vec::new();
+    for i in 0..self.houses {
+      let angle = (i as f32 + rng.next_f32() * 0.5) / self.houses as f32 * TAU;
+      let distance = Self::RADIUS * (0.5 + rng.next_f32() * 0.5);
+      let x = hub_x + (angle.cos() * distance) as i32;
+      let z = hub_z + (angle.sin() * distance) as i32;
+      let y = height(x, z).round() as i32;
+
+      if !self.models.is_empty() {
+        let model = &self.models[(rng.next_u64() % self.models.len() as u64) as usize];
+        let stamp =
+          VoxStamp::new(model.clone(), VoxelId::default()).rotated((rng.next_u64() % 4) as u8);
+        let [width, _, depth] = stamp.size();
+        let position = VoxelId::new(x - width as i32 / 2, y + 1, z - depth as i32 / 2);
+        pieces.push(StructurePiece::Stamp(VoxStamp { position, ..stamp }));
+        continue;
+      }
+
+      let half_x = 2 + (rng.next_f32() * 2.0) as i32;
+      let half_z = 2 + (rng.next_f32() * 2.0) as i32;
+      // walls and a foundation, hollowed out, with a door facing the hub
+      pieces.push(StructurePiece::Fill {
+        min: VoxelId::new(x - half_x, y - 1, z - half_z),
+        max: VoxelId::new(x + half_x, y + 4, z + half_z),
+        material: VoxelType::Dirt,
+      });
+      pieces.push(StructurePiece::Fill {
+        min: VoxelId::new(x - half_x + 1, y + 1, z - half_z + 1),
+        max: VoxelId::new(x + half_x - 1, y + 3, z + half_z - 1),
+        material: VoxelType::Air,
+      });
+      let door_z = if z > hub_z { z - half_z } else { z + half_z };
+      pieces.push(StructurePiece::Fill {
+        min: VoxelId::new(x, y + 1, door_z),
+        max: VoxelId::new(x, y + 2, door_z),
+        material: VoxelType::Air,
+      });
+    }
+    pieces
+  }
+
+  fn reach(&self, region_size: u32) -> u32 {
+    let model_size = self
+      .models
+      .iter()
+      .map(|model| model.size().into_iter().max().unwrap_or(0))
+      .max()
+      .unwrap_or(8);
+    // the hub is at least a quarter region away from the edges
+    (Self::RADIUS as u32 + model_size).saturating_sub(region_size / 4)
+  }
+}
+
+/// Rooms carved out below the surface, connected by corridors.
+#[derive(Clone, Debug)]
+pub struct Dungeons {
+  /// Chance of a region having a dungeon.
+  pub chance: f32,
+  pub rooms: u32,
+  /// Voxels between the surface and the floor of a room.
+  pub depth: i32,
+}
+impl Default for Dungeons {
+  fn default() -> Self {
+    Self {
+      chance: 0.5,
+      rooms: 4,
+      depth: 20,
+    }
+  }
+}
+impl Dungeons {
+  // rooms reach at most this far from their center
+  const MAX_HALF_SIZE: i32 = 6;
+}
+impl StructurePlanner for Dungeons {
+  fn plan(
+    &self,
+    region: &StructureRegion,
+    height: &dyn Fn(i32, i32) -> f32,
+  ) -> Vec<StructurePiece> {
+    let mut rng = region.rng(0xd0d9_e0f5);
+    if rng.next_f32() >= self.chance {
+      return Vec::new();
+    }
+
+    let (min_x, min_z) = region.min();
+    let size = region.size as f32;
+    let mut pieces = Vec::new();
+    let mut previous: Option<VoxelId> = None;
+    for _ in 0..self.rooms {
+      let x = min_x + (rng.next_f32() * size) as i32;
+      let z = min_z + (rng.next_f32() * size) as i32;
+      let floor = height(x, z).floor() as i32 - self.depth;
+      let half_x = 2 + (rng.next_f32() * (Self::MAX_HALF_SIZE - 1) as f32) as i32;
+      let half_z = 2 + (rng.next_f32() * (Self::MAX_HALF_SIZE - 1) as f32) as i32;
+      pieces.push(air(
+        VoxelId::new(x - half_x, floor, z - half_z),
+        VoxelId::new(x + half_x, floor + 4, z + half_z),
+      ));
+
+      // an L shaped corridor from the previous room, with a shaft where the floors differ
+      if let Some(from) = previous {
+        let low = from.y().min(floor);
+        let high = from.y().max(floor);
+        pieces.push(air(
+          VoxelId::new(from.x().min(x), from.y(), from.z() - 1),
+          VoxelId::new(from.x().max(x), from.y() + 2, from.z() + 1),
+        ));
+        pieces.push(air(
+          VoxelId::new(x - 1, low, from.z() - 1),
+          VoxelId::new(x + 1, high + 2, from.z() + 1),
+        ));
+        pieces.push(air(
+          VoxelId::new(x - 1, floor, from.z().min(z)),
+          VoxelId::new(x + 1, floor + 2, from.z().max(z)),
+        ));
+      }
+      previous = Some(VoxelId::new(x, floor, z));
+    }
+    pieces
+  }
+
+  fn reach(&self, _region_size: u32) -> u32 {
+    Self::MAX_HALF_SIZE as u32 + 1
+  }
+}
+
+fn air(min: VoxelId, max: VoxelId) -> StructurePiece {
+  StructurePiece::Fill {
+    min,
+    max,
+    material: VoxelType::Air,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  fn height(x: i32, z: i32) -> f32 {
+    ((x as f32 * 0.05).sin() + (z as f32 * 0.03).cos()) * 10.0 + 20.0
+  }
+
+  fn structures() -> Structures {
+    Structures::new(64)
+      .with(Villages {
+        chance: 1.0,
+        ..Default::default()
+      })
+      .with(Roads::default())
+      .with(Dungeons {
+        chance: 1.0,
+        ..Default::default()
+      })
+  }
+
+  proptest! {
+      #[test]
+      fn chunks_should_see_the_same_pieces_in_any_order(seed in 0u32..100, x in -200i32..200, z in -200i32..200, size in 1i32..40) {
+          let min = VoxelId::new(x, -20, z);
+          let max = VoxelId::new(x + size, 60, z + size);
+          // a fresh cache that planned a much larger area first
+          let warm = structures();
+          let everything = warm.pieces_in(seed, min - VoxelId::new(100, 0, 100), max + VoxelId::new(100, 0, 100), &height);
+          let expected: Vec<_> = everything.iter().filter(|p| p.intersects(min, max)).map(|p| p.bounds()).collect();
+
+          let cold: Vec<_> = structures().pieces_in(seed, min, max, &height).iter().map(|p| p.bounds()).collect();
+          assert_eq!(cold, expected);
+      }
+
+      #[test]
+      fn pieces_should_stay_within_reach_of_their_region(seed in 0u32..100, x in -20i32..20, z in -20i32..20) {
+          let region = StructureRegion { x, z, size: 64, seed };
+          let (min_x, min_z) = region.min();
+          let planners: [Box<dyn StructurePlanner>; 3] = [Box::new(Villages { chance: 1.0, ..Default::default() }), Box::new(Roads::default()), Box::new(Dungeons { chance: 1.0, ..Default::default() })];
+          for planner in planners.iter() {
+              let reach = planner.reach(64) as i32;
+              for piece in planner.plan(&region, &height) {
+                  let (min, max) = piece.bounds();
+                  assert!(min.x() >= min_x - reach && min.z() >= min_z - reach);
+                  assert!(max.x() < min_x + 64 + reach && max.z() < min_z + 64 + reach);
+              }
+          }
+      }
+  }
+}
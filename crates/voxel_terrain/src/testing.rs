@@ -0,0 +1,405 @@
+//! Assertions every [`ChunkLayout`] should pass, for property testing layouts outside this crate.
+//! Enable the `testing` feature to use them. Each helper panics on the first violation, so they
+//! can be called straight from a `proptest!` body with arbitrary voxels and chunks.
+//!
+//! [`StreamingHarness`] runs the chunk streaming pipeline headless for stress tests and benchmarks,
+//! checking the bookkeeping after every frame unless it's told not to.
+
+use super::{
+  Chunk, ChunkId, ChunkLayout, ChunkMeshed, ChunkSpawner, ChunkState, ChunkTracker,
+  CubicVoxelLayout, LayoutSettings, SettingsError, VoxelId, VoxelTerrainCorePlugin,
+  VoxelTerrainSettings,
+};
+use bevy::{
+  prelude::*,
+  tasks::{AsyncComputeTaskPool, TaskPoolBuilder},
+};
+use block_mesh::ndshape::Shape;
+use futures_lite::future;
+use std::{collections::HashSet, convert::TryFrom, marker::PhantomData};
+
+/// Runs every check that only needs a voxel and a neighbor distance.
+pub fn check_layout(layout: &impl ChunkLayout, voxel: VoxelId, distance: i32) {
+  check_origin_at_zero(layout);
+  check_voxel_space_reversible(layout, voxel);
+  check_chunk_space_reversible(layout, voxel);
+  check_voxel_chunk_in_space(layout, voxel);
+  check_neighbor_distance(layout, voxel, distance);
+  check_neighbor_mutual(layout, voxel, distance);
+
+  let chunk = layout.voxel_to_chunk(&voxel);
+  check_chunk_voxels(layout, &chunk);
+  check_chunk_data(layout, &chunk);
+  check_chunk_padding(layout, &chunk);
+}
+
+/// The voxel's chunk has `expected` neighbors within `distance` rings, without duplicates.
+pub fn check_neighbor_count(
+  layout: &impl ChunkLayout,
+  voxel: VoxelId,
+  distance: i32,
+  expected: usize,
+) {
+  let chunk = layout.voxel_to_chunk(&voxel);
+  let neighbors = layout.get_chunk_neighbors(&chunk, distance);
+  assert_eq!(expected, neighbors.len());
+  let mut unique = neighbors.clone();
+  unique.sort_by_key(|n| (n.x(), n.y(), n.layer()));
+  unique.dedup();
+  assert_eq!(unique.len(), neighbors.len());
+}
+
+/// Neighbors are at most `distance` rings away, nearer rings first.
+pub fn check_neighbor_distance(layout: &impl ChunkLayout, voxel: VoxelId, distance: i32) {
+  let chunk = layout.voxel_to_chunk(&voxel);
+  let mut last_ring = 1;
+  for neighbor in layout.get_chunk_neighbors(&chunk, distance) {
+    let ring = layout.chunk_ring_distance(&neighbor, &chunk);
+    assert!(
+      ring >= last_ring && ring <= distance,
+      "{:?} is {} rings away",
+      neighbor,
+      ring
+    );
+    last_ring = ring;
+  }
+}
+
+/// Every neighbor has the voxel's chunk as a neighbor exactly once.
+pub fn check_neighbor_mutual(layout: &impl ChunkLayout, voxel: VoxelId, distance: i32) {
+  let chunk = layout.voxel_to_chunk(&voxel);
+  for neighbor in layout.get_chunk_neighbors(&chunk, distance) {
+    let ns: Vec<_> = layout.get_chunk_neighbors(&neighbor, distance);
+    let original: Vec<_> = ns.clone().into_iter().filter(|n| *n == chunk).collect();
+    assert_eq!(original.len(), 1);
+    assert_eq!(original[0], chunk);
+  }
+}
+
+pub fn check_origin_at_zero(layout: &impl ChunkLayout) {
+  let coords = layout.chunk_to_space(&layout.origin());
+  assert_eq!(coords.x, 0.0);
+  assert_eq!(coords.y, 0.0);
+  assert_eq!(coords.z, 0.0);
+}
+
+pub fn check_voxel_space_reversible(layout: &impl ChunkLayout, voxel: VoxelId) {
+  let space_coords = layout.voxel_to_space(&voxel);
+  let result = layout.space_to_voxel(&space_coords);
+  assert_eq!(result, voxel, "Coords: {:?}", space_coords);
+}
+
+pub fn check_chunk_space_reversible(layout: &impl ChunkLayout, voxel: VoxelId) {
+  let chunk = layout.voxel_to_chunk(&voxel);
+  let space_coords = layout.chunk_to_space(&chunk);
+  let result = layout.space_to_chunk(&space_coords);
+  assert_eq!(result, chunk, "Chunk coords: {:?}", space_coords);
+}
+
+pub fn check_voxel_chunk_in_space(layout: &impl ChunkLayout, voxel: VoxelId) {
+  let space_coords = layout.voxel_to_space(&voxel);
+  let space_chunk = layout.space_to_chunk(&space_coords);
+  let voxel_chunk = layout.voxel_to_chunk(&voxel);
+  assert_eq!(space_chunk, voxel_chunk);
+}
+
+/// Every voxel of the chunk maps back to the chunk.
+pub fn check_chunk_voxels(layout: &impl ChunkLayout, chunk: &ChunkId) {
+  for voxel in layout.get_chunk_voxels(chunk) {
+    let result = layout.voxel_to_chunk(&voxel);
+    assert_eq!(
+      result, *chunk,
+      "Voxel: {:?}, expected chunk: {:?}, actual: {:?}",
+      voxel, chunk, result
+    );
+  }
+}
+
+/// Padding voxels are inside the chunk's voxel data and belong to chunks at most two rings away.
+pub fn check_chunk_padding(layout: &impl ChunkLayout, chunk: &ChunkId) {
+  let origin = layout.get_origin(chunk);
+  let [width, height, depth] = layout.shape().as_array();
+  for voxel in layout.get_padding_voxels(chunk) {
+    let local = voxel - origin;
+    for (value, size) in [(local.x(), width), (local.y(), height), (local.z(), depth)] {
+      assert!(
+        (0..size as i32).contains(&value),
+        "{:?} is not inside the voxel data of {:?}",
+        voxel,
+        chunk
+      );
+    }
+    let owner = layout.voxel_to_chunk(&voxel);
+    let distance = layout.chunk_ring_distance(chunk, &owner);
+    assert!(
+      (1..=2).contains(&distance),
+      "{:?} belongs to {:?}, which is too far from {:?}",
+      voxel,
+      owner,
+      chunk
+    );
+  }
+}
+
+/// Every voxel of the chunk is inside its voxel data with room for padding, and the column mask
+/// covers exactly the chunk's columns.
+pub fn check_chunk_data(layout: &impl ChunkLayout, chunk: &ChunkId) {
+  let origin = layout.get_origin(chunk);
+  let [width, height, depth] = layout.shape().as_array();
+  for voxel in layout.get_chunk_voxels(chunk) {
+    let local = voxel - origin;
+    for (value, size) in [(local.x(), width), (local.y(), height), (local.z(), depth)] {
+      assert!(
+        value >= 1 && value <= size as i32 - 2,
+        "{:?} is not inside the voxel data of {:?}",
+        voxel,
+        chunk
+      );
+    }
+  }
+
+  if let Some(mask) = layout.chunk_column_mask(chunk) {
+    assert_eq!(mask.len(), (width * depth) as usize);
+    for z in 0..depth {
+      for x in 0..width {
+        let voxel = origin + VoxelId::new(x as i32, 0, z as i32);
+        assert_eq!(
+          mask[(x + z * width) as usize],
+          layout.voxel_to_chunk(&voxel) == *chunk,
+          "column {:?} of {:?}",
+          voxel,
+          chunk
+        );
+      }
+    }
+  }
+}
+
+/// A headless app with [`VoxelTerrainCorePlugin`] and a single [`ChunkSpawner`] that can be moved
+/// around between frames.
+///
+/// Every [`step`](Self::step) panics if a chunk is spawned twice or if the [`ChunkTracker`]
+/// disagrees with the chunk entities, unless the harness was made
+/// [`without_checks`](Self::without_checks).
+pub struct StreamingHarness<L = CubicVoxelLayout> {
+  pub app: App,
+  spawner: Entity,
+  checks: bool,
+  layout: PhantomData<L>,
+}
+
+impl<L> StreamingHarness<L>
+where
+  L: ChunkLayout + for<'a> TryFrom<&'a LayoutSettings, Error = SettingsError>,
+{
+  pub fn new(settings: VoxelTerrainSettings) -> Self {
+    let mut app = App::new();
+    // the chunk tasks run on a single thread, see drive_tasks
+    app
+      .insert_resource(AsyncComputeTaskPool(
+        TaskPoolBuilder::new().num_threads(1).build(),
+      ))
+      .add_plugins(MinimalPlugins)
+      .add_plugin(VoxelTerrainCorePlugin::<L>::new(settings));
+    let spawner = app
+      .world
+      .spawn()
+      .insert(Transform::default())
+      .insert(GlobalTransform::default())
+      .insert(ChunkSpawner::default())
+      .id();
+    Self {
+      app,
+      spawner,
+      checks: true,
+      layout: PhantomData,
+    }
+  }
+
+  /// Skips the invariant checks after each frame, for benchmarks.
+  pub fn without_checks(mut self) -> Self {
+    self.checks = false;
+    self
+  }
+
+  pub fn move_spawner(&mut self, translation: Vec3) {
+    let mut transform = self
+      .app
+      .world
+      .get_mut::<Transform>(self.spawner)
+      .expect("the spawner is never despawned");
+    transform.translation = translation;
+  }
+
+  /// Runs one frame, helps the task pool with the tasks it spawned and checks the invariants.
+  pub fn step(&mut self) {
+    self.app.update();
+    self.drive_tasks();
+    if self.checks {
+      self.check_invariants();
+    }
+  }
+
+  // Frames without rendering are very short, the chunk tasks would hardly get anywhere between
+  // them. The only thread of the pool runs its tasks one after the other in the order they were
+  // spawned, so once a task spawned now is done so is every chunk task spawned before it
+  fn drive_tasks(&self) {
+    let pool = self
+      .app
+      .world
+      .get_resource::<AsyncComputeTaskPool>()
+      .expect("the harness adds the task pool");
+    future::block_on(pool.spawn(async {}));
+  }
+
+  /// Moves the spawner through each point in turn, staying there for `frames_per_point` frames.
+  pub fn follow(&mut self, path: &[Vec3], frames_per_point: usize) {
+    for point in path {
+      self.move_spawner(*point);
+      for _ in 0..frames_per_point {
+        self.step();
+      }
+    }
+  }
+
+  /// Steps until every chunk within the load radius of the spawner is meshed, returning the
+  /// number of frames it took or `None` if it took more than `max_frames`.
+  pub fn run_until_meshed(&mut self, max_frames: usize) -> Option<usize> {
+    for frame in 1..=max_frames {
+      self.step();
+      if self.unmeshed_in_radius().is_empty() {
+        return Some(frame);
+      }
+    }
+    None
+  }
+
+  /// Chunks within the load radius of the spawner that aren't meshed yet, loaded or not.
+  pub fn unmeshed_in_radius(&mut self) -> Vec<ChunkId> {
+    let world = &mut self.app.world;
+    let radius = world
+      .get_resource::<VoxelTerrainSettings>()
+      .expect("the core plugin adds the settings")
+      .load_radius;
+    let translation = world
+      .get::<Transform>(self.spawner)
+      .expect("the spawner is never despawned")
+      .translation;
+    let layout = world
+      .get_resource::<L>()
+      .expect("the core plugin adds the layout");
+    let center = layout.space_to_chunk(&translation);
+    let mut wanted: HashSet<_> = layout
+      .get_chunk_neighbors(&center, radius)
+      .into_iter()
+      .collect();
+    wanted.insert(center);
+
+    for chunk in world
+      .query_filtered::<&Chunk, With<ChunkMeshed>>()
+      .iter(world)
+    {
+      wanted.remove(&chunk.id);
+    }
+    wanted.into_iter().collect()
+  }
+
+  /// Number of chunk entities.
+  pub fn chunk_count(&mut self) -> usize {
+    let world = &mut self.app.world;
+    world.query::<&Chunk>().iter(world).count()
+  }
+
+  /// No chunk has two entities, and the tracker has exactly the chunks that have an entity, on
+  /// that entity.
+  pub fn check_invariants(&mut self) {
+    let world = &mut self.app.world;
+    let chunks: Vec<_> = world
+      .query::<(Entity, &Chunk, Option<&ChunkMeshed>)>()
+      .iter(world)
+      .map(|(entity, chunk, meshed)| (entity, chunk.id, meshed.is_some()))
+      .collect();
+    let tracker = world
+      .get_resource::<ChunkTracker>()
+      .expect("the core plugin adds the tracker");
+
+    let mut ids = HashSet::new();
+    for (entity, chunk, meshed) in chunks {
+      assert!(ids.insert(chunk), "{:?} has more than one entity", chunk);
+      assert_eq!(
+        tracker.entity(&chunk),
+        Some(entity),
+        "{:?} is on another entity",
+        chunk
+      );
+      if meshed {
+        assert_eq!(
+          tracker.state(&chunk),
+          Some(ChunkState::Meshed),
+          "{:?}",
+          chunk
+        );
+      }
+    }
+    assert_eq!(
+      tracker.len(),
+      ids.len(),
+      "the tracker has chunks without entities"
+    );
+  }
+
+  /// Despawns a loaded chunk behind the terrain plugin's back.
+  pub fn despawn_chunk(&mut self, chunk: &ChunkId) -> bool {
+    let world = &mut self.app.world;
+    let entity = world
+      .query::<(Entity, &Chunk)>()
+      .iter(world)
+      .find(|(_, c)| c.id == *chunk)
+      .map(|(entity, _)| entity);
+    entity.map_or(false, |entity| world.despawn(entity))
+  }
+}
+
+/// Small chunks with the floating origin off, quick to stream in tests.
+#[cfg(test)]
+pub(crate) fn small_settings(load_radius: i32, unload_radius: i32) -> VoxelTerrainSettings {
+  VoxelTerrainSettings {
+    layout: LayoutSettings {
+      chunk_voxel_length: 4,
+      chunk_voxel_height: 24,
+      ..Default::default()
+    },
+    load_radius,
+    unload_radius,
+    floating_origin_threshold: None,
+    ..Default::default()
+  }
+}
+
+/// The materials proptests pick from.
+#[cfg(test)]
+pub(crate) const MATERIALS: [super::VoxelType; 4] = {
+  use super::VoxelType::*;
+  [Air, Dirt, Water, Lamp]
+};
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn streaming_should_keep_one_entity_per_chunk_and_mesh_everything_nearby() {
+    let settings = small_settings(2, 3);
+    let mut harness = StreamingHarness::<CubicVoxelLayout>::new(settings);
+    assert!(harness.run_until_meshed(5000).is_some());
+
+    // back and forth across chunk borders, faster than chunks finish loading
+    let path: Vec<_> = (0..40)
+      .map(|i| Vec3::new((i % 13) as f32 * 7.0, 0.0, (i % 7) as f32 * -5.0))
+      .collect();
+    harness.follow(&path, 2);
+    harness.move_spawner(Vec3::new(60.0, 0.0, -30.0));
+    assert!(harness.run_until_meshed(5000).is_some());
+    assert!(harness.chunk_count() >= 25);
+  }
+}
@@ -1,22 +1,183 @@
-use super::ChunkId;
+use super::{Chunk, ChunkId, ChunkMeshed, ChunkSpawner, ChunkVoxelData};
 use bevy::prelude::*;
-use std::collections::HashSet;
+use std::collections::HashMap;
 
+/// How far along a loaded chunk is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChunkState {
+  /// Voxel data is being generated.
+  Generating,
+  /// The chunk has voxel data and is waiting for a mesh, either for the first time or after an
+  /// edit.
+  Meshing,
+  /// The chunk has a mesh, or doesn't need one.
+  Meshed,
+}
+
+/// Index of the loaded chunks and the entities they live on.
+///
+/// Chunks are added when they are spawned. Chunk entities that get despawned by something other
+/// than the terrain plugin are dropped from the index at the end of the frame, and spawners reload
+/// the chunks around them.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use voxel_terrain::{ChunkId, ChunkState, ChunkTracker};
+///
+/// let mut tracker = ChunkTracker::default();
+/// let entity = Entity::from_raw(7);
+/// assert!(tracker.try_spawn(&ChunkId::new(0, 0), entity));
+/// assert!(!tracker.try_spawn(&ChunkId::new(0, 0), Entity::from_raw(8)));
+/// assert_eq!(tracker.entity(&ChunkId::new(0, 0)), Some(entity));
+/// assert_eq!(tracker.chunk(entity), Some(ChunkId::new(0, 0)));
+/// assert_eq!(tracker.state(&ChunkId::new(0, 0)), Some(ChunkState::Generating));
+/// assert_eq!(tracker.loaded_chunks().count(), 1);
+///
+/// assert_eq!(tracker.try_despawn(&ChunkId::new(0, 0)), Some(entity));
+/// assert!(tracker.is_empty());
+/// ```
 #[derive(Default)]
 pub struct ChunkTracker {
-  pub loaded_chunks: HashSet<ChunkId>,
+  chunks: HashMap<ChunkId, (Entity, ChunkState)>,
+  entities: HashMap<Entity, ChunkId>,
 }
 impl ChunkTracker {
-  pub fn try_spawn(&mut self, chunk: &ChunkId) -> bool {
-    if !self.loaded_chunks.contains(chunk) {
-      self.loaded_chunks.insert(chunk.clone());
-      true
-    } else {
-      false
+  /// Records the entity of a newly spawned chunk, returns false if the chunk is already loaded.
+  pub fn try_spawn(&mut self, chunk: &ChunkId, entity: Entity) -> bool {
+    if self.chunks.contains_key(chunk) {
+      return false;
     }
+    self.chunks.insert(*chunk, (entity, ChunkState::Generating));
+    self.entities.insert(entity, *chunk);
+    true
+  }
+
+  /// Forgets the chunk, returning the entity it was on if it was loaded.
+  pub fn try_despawn(&mut self, chunk: &ChunkId) -> Option<Entity> {
+    let (entity, _) = self.chunks.remove(chunk)?;
+    self.entities.remove(&entity);
+    Some(entity)
+  }
+
+  #[inline]
+  pub fn is_loaded(&self, chunk: &ChunkId) -> bool {
+    self.chunks.contains_key(chunk)
+  }
+
+  /// The entity the chunk lives on.
+  #[inline]
+  pub fn entity(&self, chunk: &ChunkId) -> Option<Entity> {
+    self.chunks.get(chunk).map(|(entity, _)| *entity)
+  }
+
+  /// The chunk living on an entity.
+  #[inline]
+  pub fn chunk(&self, entity: Entity) -> Option<ChunkId> {
+    self.entities.get(&entity).copied()
   }
 
-  pub fn try_despawn(&mut self, chunk: &ChunkId) -> bool {
-    self.loaded_chunks.remove(chunk)
+  #[inline]
+  pub fn state(&self, chunk: &ChunkId) -> Option<ChunkState> {
+    self.chunks.get(chunk).map(|(_, state)| *state)
+  }
+
+  /// Loaded chunks, in no particular order.
+  pub fn loaded_chunks(&self) -> impl Iterator<Item = &ChunkId> {
+    self.chunks.keys()
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.chunks.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.chunks.is_empty()
+  }
+
+  fn set_state(&mut self, entity: Entity, state: ChunkState) {
+    if let Some(chunk) = self.entities.get(&entity) {
+      if let Some(entry) = self.chunks.get_mut(chunk) {
+        entry.1 = state;
+      }
+    }
+  }
+}
+
+// Runs in PostUpdate, after the commands of the terrain systems were applied. Removals are only
+// visible until the end of the frame, chunks despawned later than this are caught a frame late.
+pub fn sync_chunk_tracker(
+  mut tracker: ResMut<ChunkTracker>,
+  removed_chunks: RemovedComponents<Chunk>,
+  removed_meshes: RemovedComponents<ChunkMeshed>,
+  generated: Query<Entity, Added<ChunkVoxelData>>,
+  meshed: Query<Entity, Added<ChunkMeshed>>,
+  mut spawners: Query<&mut ChunkSpawner>,
+) {
+  // chunks despawned by despawn_chunks are already gone from the tracker
+  let mut lost = false;
+  for entity in removed_chunks.iter() {
+    if let Some(chunk) = tracker.chunk(entity) {
+      tracker.try_despawn(&chunk);
+      lost = true;
+    }
+  }
+  if lost {
+    // load whatever went missing around the spawners
+    for mut spawner in spawners.iter_mut() {
+      spawner.last_loaded_chunk = None;
+    }
+  }
+
+  // edits take the mesh away, meshing again adds it back in the same or a later frame
+  for entity in removed_meshes.iter() {
+    if tracker.entities.contains_key(&entity) {
+      tracker.set_state(entity, ChunkState::Meshing);
+    }
+  }
+  for entity in generated.iter() {
+    let state = tracker
+      .chunk(entity)
+      .and_then(|chunk| tracker.state(&chunk));
+    if state == Some(ChunkState::Generating) {
+      tracker.set_state(entity, ChunkState::Meshing);
+    }
+  }
+  for entity in meshed.iter() {
+    if tracker.entities.contains_key(&entity) {
+      tracker.set_state(entity, ChunkState::Meshed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    testing::{small_settings, StreamingHarness},
+    CubicVoxelLayout,
+  };
+
+  #[test]
+  fn chunks_despawned_elsewhere_should_be_forgotten_and_reloaded() {
+    let settings = small_settings(1, 2);
+    let mut harness = StreamingHarness::<CubicVoxelLayout>::new(settings);
+    assert!(harness.run_until_meshed(5000).is_some());
+
+    let chunk = ChunkId::new(1, 0);
+    let before = harness
+      .app
+      .world
+      .get_resource::<ChunkTracker>()
+      .unwrap()
+      .entity(&chunk);
+    assert!(harness.despawn_chunk(&chunk));
+    harness.step();
+    assert!(harness.run_until_meshed(5000).is_some());
+    let tracker = harness.app.world.get_resource::<ChunkTracker>().unwrap();
+    assert!(tracker.entity(&chunk).is_some());
+    assert_ne!(tracker.entity(&chunk), before);
+    assert_eq!(tracker.state(&chunk), Some(ChunkState::Meshed));
   }
 }
@@ -0,0 +1,272 @@
+use super::{render::ChunkWater, Chunk, ChunkId, ChunkLayout, ChunkVoxelData};
+use bevy::{
+  prelude::*,
+  render::primitives::{Aabb, Frustum},
+  tasks::{AsyncComputeTaskPool, Task},
+};
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use futures_lite::future;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// the face on the other side of the border, faces go -x, +x, -y, +y, -z, +z
+#[inline]
+fn opposite(face: usize) -> usize {
+  face ^ 1
+}
+
+/// Which faces of a chunk can see each other through the air and water inside it.
+///
+/// Faces are numbered in the order of [`ChunkLayout::chunk_face_neighbors`].
+///
+/// ```
+/// use voxel_terrain::ChunkConnectivity;
+///
+/// assert!(ChunkConnectivity::OPEN.connects(0, 1));
+/// assert!(!ChunkConnectivity::default().connects(0, 1));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Component)]
+pub struct ChunkConnectivity(u64);
+
+impl ChunkConnectivity {
+  /// Every face sees every other face, used for chunks that haven't been checked yet.
+  pub const OPEN: Self = Self(u64::MAX);
+
+  /// Flood fills the transparent voxels of the chunk, padding excluded.
+  pub fn new(data: &ChunkVoxelData, shape: &RuntimeShape<u32, 3>) -> Self {
+    if data.is_uniform() {
+      return if data.material(0).is_solid() {
+        Self::default()
+      } else {
+        Self::OPEN
+      };
+    }
+
+    let [width, height, depth] = shape.as_array();
+    let size = [width, height, depth];
+    let mut connectivity = Self::default();
+    let mut visited = vec![false; shape.usize()];
+    let mut stack = Vec::new();
+    for i in 0..shape.usize() {
+      let p = shape.delinearize(i as u32);
+      let padding = (0..3).any(|a| p[a] == 0 || p[a] == size[a] - 1);
+      if padding || visited[i] || data.material(i).is_solid() {
+        continue;
+      }
+
+      // faces touched by this pocket of air
+      let mut faces = 0u8;
+      visited[i] = true;
+      stack.push(p);
+      while let Some(p) = stack.pop() {
+        for axis in 0..3 {
+          for (side, step) in [(0, -1i32), (1, 1)] {
+            let next = p[axis] as i32 + step;
+            if next == 0 || next == size[axis] as i32 - 1 {
+              faces |= 1 << (axis * 2 + side);
+              continue;
+            }
+            let mut n = p;
+            n[axis] = next as u32;
+            let j = shape.linearize(n) as usize;
+            if !visited[j] && !data.material(j).is_solid() {
+              visited[j] = true;
+              stack.push(n);
+            }
+          }
+        }
+      }
+
+      for a in 0..6 {
+        for b in 0..6 {
+          if faces & (1 << a) != 0 && faces & (1 << b) != 0 {
+            connectivity.0 |= 1 << (a * 6 + b);
+          }
+        }
+      }
+    }
+    connectivity
+  }
+
+  #[inline]
+  pub fn connects(&self, a: usize, b: usize) -> bool {
+    self.0 & (1 << (a * 6 + b)) != 0
+  }
+}
+
+/// Chunks that can be seen from `start`, walking from chunk to chunk through faces that see each
+/// other and never turning back towards `start`.
+///
+/// `graph` holds every loaded chunk, chunks that aren't in it block the view. Chunks for which
+/// `in_view` returns false are neither visible nor walked through. Returns `None` when the layout's
+/// chunks don't share faces, in which case only `in_view` can hide them.
+pub fn visible_chunks<L: ChunkLayout>(
+  layout: &L,
+  start: ChunkId,
+  graph: &HashMap<ChunkId, ChunkConnectivity>,
+  in_view: impl Fn(&ChunkId) -> bool,
+) -> Option<HashSet<ChunkId>> {
+  let neighbors = layout.chunk_face_neighbors(&start)?;
+  let mut visible = HashSet::from([start]);
+  // the chunk, the face it was entered through and the directions walked to get there
+  let mut queue: VecDeque<_> = neighbors
+    .iter()
+    .enumerate()
+    .map(|(face, chunk)| (*chunk, opposite(face), 1u8 << face))
+    .collect();
+
+  while let Some((chunk, entered, directions)) = queue.pop_front() {
+    let connectivity = match graph.get(&chunk) {
+      Some(connectivity) => connectivity,
+      None => continue,
+    };
+    if visible.contains(&chunk) || !in_view(&chunk) {
+      continue;
+    }
+    visible.insert(chunk);
+
+    let neighbors = layout
+      .chunk_face_neighbors(&chunk)
+      .expect("a layout has face neighbors for every chunk or for none");
+    for (face, neighbor) in neighbors.iter().enumerate() {
+      let backwards = directions & (1 << opposite(face)) != 0;
+      if face == entered || backwards || !connectivity.connects(entered, face) {
+        continue;
+      }
+      if !visible.contains(neighbor) {
+        queue.push_back((*neighbor, opposite(face), directions | 1 << face));
+      }
+    }
+  }
+  Some(visible)
+}
+
+pub fn build_connectivity<L: ChunkLayout>(
+  mut commands: Commands,
+  layout: Res<L>,
+  thread_pool: Res<AsyncComputeTaskPool>,
+  query: Query<
+    (Entity, &Chunk, &ChunkVoxelData),
+    (Without<ChunkConnectivity>, Without<Task<ChunkConnectivity>>),
+  >,
+) {
+  for (entity, chunk, voxel_data) in query.iter() {
+    // only chunks that share faces can hide each other
+    if layout.chunk_face_neighbors(&chunk.id).is_none() {
+      continue;
+    }
+    let voxel_data = voxel_data.clone();
+    let shape = layout.shape().clone();
+    commands
+      .entity(entity)
+      .insert(thread_pool.spawn(async move { ChunkConnectivity::new(&voxel_data, &shape) }));
+  }
+}
+
+pub fn load_connectivity(
+  mut commands: Commands,
+  mut tasks: Query<(Entity, &mut Task<ChunkConnectivity>)>,
+) {
+  for (entity, mut task) in tasks.iter_mut() {
+    if let Some(connectivity) = future::block_on(future::poll_once(&mut *task)) {
+      commands
+        .entity(entity)
+        .remove::<Task<ChunkConnectivity>>()
+        .insert(connectivity);
+    }
+  }
+}
+
+// Hides chunks outside the view of the 3d camera or behind terrain. Bevy only culls against the
+// frustum, and does that per mesh rather than per chunk.
+pub fn cull_chunks<L: ChunkLayout>(
+  layout: Res<L>,
+  cameras: Query<(&GlobalTransform, &Frustum), With<PerspectiveProjection>>,
+  graph: Query<(&Chunk, Option<&ChunkConnectivity>)>,
+  mut chunks: Query<(&Chunk, &mut Visibility, Option<&Children>)>,
+  mut water: Query<&mut Visibility, (With<ChunkWater>, Without<Chunk>)>,
+) {
+  let (camera, frustum) = match cameras.iter().next() {
+    Some(camera) => camera,
+    None => return,
+  };
+  let start = layout.space_to_chunk(&camera.translation);
+  // chunks still being generated don't hide anything yet
+  let graph: HashMap<_, _> = graph
+    .iter()
+    .map(|(chunk, connectivity)| {
+      let connectivity = connectivity.copied().unwrap_or(ChunkConnectivity::OPEN);
+      (chunk.id, connectivity)
+    })
+    .collect();
+  let in_view = |chunk: &ChunkId| {
+    let (min, max) = layout.chunk_bounds(chunk);
+    frustum.intersects_obb(&Aabb::from_min_max(min, max), &Mat4::IDENTITY)
+  };
+  let visible = visible_chunks(&*layout, start, &graph, &in_view);
+
+  for (chunk, mut visibility, children) in chunks.iter_mut() {
+    let is_visible = match &visible {
+      Some(visible) => visible.contains(&chunk.id),
+      None => chunk.id == start || in_view(&chunk.id),
+    };
+    if visibility.is_visible != is_visible {
+      visibility.is_visible = is_visible;
+    }
+    for child in children.into_iter().flat_map(|children| children.iter()) {
+      if let Ok(mut visibility) = water.get_mut(*child) {
+        if visibility.is_visible != is_visible {
+          visibility.is_visible = is_visible;
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{generator::VoxelType, CubicVoxelLayout};
+
+  #[test]
+  fn a_floor_should_split_a_chunk() {
+    let shape = RuntimeShape::<u32, 3>::new([6, 6, 6]);
+    let materials: Vec<_> = (0..shape.size())
+      .map(|i| match shape.delinearize(i)[1] {
+        2 => VoxelType::Dirt,
+        _ => VoxelType::Air,
+      })
+      .collect();
+    let sdf = vec![0.0; materials.len()];
+    let connectivity = ChunkConnectivity::new(&ChunkVoxelData::new(&sdf, &materials), &shape);
+
+    // -y and +y are on opposite sides of the floor, the sides see both
+    assert!(!connectivity.connects(2, 3));
+    assert!(connectivity.connects(0, 1));
+    assert!(connectivity.connects(0, 2));
+    assert!(connectivity.connects(4, 3));
+  }
+
+  #[test]
+  fn solid_chunks_should_hide_what_is_behind_them() {
+    let layout = CubicVoxelLayout::new(ChunkId::default(), 1.0, 2, 4);
+    let mut graph: HashMap<_, _> = (-3..=3)
+      .flat_map(|x| (-3..=3).map(move |y| (ChunkId::new(x, y), ChunkConnectivity::OPEN)))
+      .collect();
+    // a wall along x = 1
+    for y in -3..=3 {
+      graph.insert(ChunkId::new(1, y), ChunkConnectivity::default());
+    }
+
+    let visible = visible_chunks(&layout, ChunkId::default(), &graph, |_| true).unwrap();
+    assert!(visible.contains(&ChunkId::new(-3, 2)));
+    assert!(visible.contains(&ChunkId::new(1, 0)));
+    assert!(!visible.contains(&ChunkId::new(2, 0)));
+    assert!(!visible.contains(&ChunkId::new(3, -3)));
+
+    // nothing is walked through outside the view
+    let visible =
+      visible_chunks(&layout, ChunkId::default(), &graph, |chunk| chunk.x() <= 0).unwrap();
+    assert!(!visible.contains(&ChunkId::new(1, 1)));
+    assert!(visible.contains(&ChunkId::new(0, 3)));
+  }
+}
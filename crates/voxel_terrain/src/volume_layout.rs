@@ -0,0 +1,183 @@
+use super::layout::{box_face_neighbors, box_padding, ChunkId, ChunkLayout, VoxelId};
+use bevy::prelude::*;
+use block_mesh::ndshape::RuntimeShape;
+
+/// Maps between chunks, voxels and world space for a 3D grid of cube chunks, for terrain that
+/// isn't a flat heightfield, like planets.
+///
+/// Each chunk is `2 * chunk_voxel_length + 1` voxels along every axis, centered on its center
+/// voxel. Chunks are stacked vertically by their `layer`, neighbors are streamed in all
+/// directions. World space is relative to the center of the `origin` chunk.
+///
+/// ```
+/// use bevy::math::Vec3;
+/// use voxel_terrain::{ChunkId, ChunkLayout, VolumeVoxelLayout};
+///
+/// let layout = VolumeVoxelLayout::new(ChunkId::new(0, 0), 1.0, 10);
+///
+/// let chunk = layout.space_to_chunk(&Vec3::new(12.0, -12.0, 0.0));
+/// assert_eq!(chunk, ChunkId::new(1, 0).with_layer(-1));
+/// assert_eq!(layout.chunk_to_space(&chunk), Vec3::new(21.0, -21.0, 0.0));
+/// assert_eq!(layout.get_chunk_neighbors(&chunk, 1).len(), 26);
+/// ```
+#[derive(Clone)]
+pub struct VolumeVoxelLayout {
+  origin: ChunkId,
+  voxel_side_length: f32,
+  chunk_voxel_length: u32,
+  shape: RuntimeShape<u32, 3>,
+}
+
+impl VolumeVoxelLayout {
+  pub fn new(origin: ChunkId, voxel_side_length: f32, chunk_voxel_length: u32) -> Self {
+    let side_length = 1 + (chunk_voxel_length * 2);
+    Self {
+      origin,
+      voxel_side_length,
+      chunk_voxel_length,
+      shape: RuntimeShape::<u32, 3>::new([side_length + 2; 3]),
+    }
+  }
+
+  /// Number of voxels between a chunk's center voxel and its faces.
+  #[inline]
+  pub fn chunk_voxel_length(&self) -> u32 {
+    self.chunk_voxel_length
+  }
+
+  /// Width, depth and height of a chunk in voxels.
+  #[inline]
+  pub fn chunk_voxel_full_length(&self) -> u32 {
+    1 + (self.chunk_voxel_length * 2)
+  }
+}
+impl ChunkLayout for VolumeVoxelLayout {
+  #[inline]
+  fn origin(&self) -> ChunkId {
+    self.origin
+  }
+
+  #[inline]
+  fn set_origin(&mut self, origin: ChunkId) {
+    self.origin = origin;
+  }
+
+  #[inline]
+  fn shape(&self) -> &RuntimeShape<u32, 3> {
+    &self.shape
+  }
+
+  #[inline]
+  fn get_origin(&self, chunk: &ChunkId) -> VoxelId {
+    let padding = self.chunk_voxel_length as i32 + 1;
+    self.get_center_voxel(chunk) - VoxelId::new(padding, padding, padding)
+  }
+
+  fn chunk_face_neighbors(&self, chunk: &ChunkId) -> Option<[ChunkId; 6]> {
+    Some(box_face_neighbors(chunk))
+  }
+
+  fn get_padding_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId> {
+    box_padding(self.get_origin(chunk), &self.shape, [true; 3])
+  }
+
+  #[inline]
+  fn voxel_side_length(&self) -> f32 {
+    self.voxel_side_length
+  }
+
+  #[inline]
+  fn chunk_side_length(&self) -> f32 {
+    self.chunk_voxel_full_length() as f32 * self.voxel_side_length
+  }
+
+  #[inline]
+  fn get_center_voxel(&self, chunk: &ChunkId) -> VoxelId {
+    let length = self.chunk_voxel_full_length() as i32;
+    VoxelId::new(
+      chunk.x() * length,
+      chunk.layer() * length,
+      chunk.y() * length,
+    )
+  }
+
+  fn get_chunk_neighbors(&self, chunk: &ChunkId, distance: i32) -> Vec<ChunkId> {
+    // the surface of a cube for every ring
+    (1..=distance)
+      .flat_map(move |ring| {
+        (-ring..=ring).flat_map(move |x| {
+          (-ring..=ring).flat_map(move |y| {
+            (-ring..=ring)
+              .filter(move |layer| x.abs().max(y.abs()).max(layer.abs()) == ring)
+              .map(move |layer| *chunk + ChunkId::new(x, y).with_layer(layer))
+          })
+        })
+      })
+      .collect()
+  }
+
+  fn chunk_ring_distance(&self, a: &ChunkId, b: &ChunkId) -> i32 {
+    let diff = *a - *b;
+    diff.x().abs().max(diff.y().abs()).max(diff.layer().abs())
+  }
+
+  fn get_chunk_voxels(&self, chunk: &ChunkId) -> Vec<VoxelId> {
+    let center = self.get_center_voxel(chunk);
+    let length = self.chunk_voxel_length as i32;
+    (-length..=length)
+      .flat_map(|x| {
+        (-length..=length)
+          .flat_map(move |y| (-length..=length).map(move |z| center + VoxelId::new(x, y, z)))
+      })
+      .collect()
+  }
+
+  fn voxel_to_chunk(&self, voxel: &VoxelId) -> ChunkId {
+    let length = self.chunk_voxel_full_length() as i32;
+    let half = self.chunk_voxel_length as i32;
+    ChunkId::new(
+      (voxel.x() + half).div_euclid(length),
+      (voxel.z() + half).div_euclid(length),
+    )
+    .with_layer((voxel.y() + half).div_euclid(length))
+  }
+
+  fn voxel_to_space(&self, voxel: &VoxelId) -> Vec3 {
+    let transposed = *voxel - self.get_center_voxel(&self.origin);
+    Vec3::new(
+      transposed.x() as f32,
+      transposed.y() as f32,
+      transposed.z() as f32,
+    ) * self.voxel_side_length
+  }
+
+  fn space_to_voxel(&self, space: &Vec3) -> VoxelId {
+    let divisor = self.voxel_side_length;
+    let x = space.x.div_euclid(divisor) as i32;
+    let y = space.y.div_euclid(divisor) as i32;
+    let z = space.z.div_euclid(divisor) as i32;
+    VoxelId::new(x, y, z) + self.get_center_voxel(&self.origin)
+  }
+}
+impl Default for VolumeVoxelLayout {
+  fn default() -> Self {
+    Self::new(ChunkId::default(), 1.0, 16)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::*;
+  use proptest::prelude::*;
+
+  proptest! {
+      #[test]
+      fn volume_layout_should_pass_layout_checks(x1 in -1000i32..=1000, y1 in -1000i32..=1000, layer in -1000i32..=1000, x2 in -10000i32..=10000, y2 in -10000i32..=10000, z2 in -10000i32..=10000, voxel_length in 1u32..=16, distance in 1i32..4) {
+          let layout = VolumeVoxelLayout::new(ChunkId::new(x1, y1).with_layer(layer), 1.0, voxel_length);
+          let voxel = VoxelId::new(x2, y2, z2);
+          check_layout(&layout, voxel, distance);
+          check_neighbor_count(&layout, voxel, distance, ((distance * 2 + 1).pow(3) - 1) as usize);
+      }
+  }
+}
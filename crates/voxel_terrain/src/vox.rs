@@ -0,0 +1,439 @@
+use super::{generator::VoxelType, voxel_data::ChunkVoxelData, VoxelId};
+use block_mesh::ndshape::{RuntimeShape, Shape};
+use std::{collections::HashMap, fmt, path::Path, sync::Arc};
+
+/// A voxel model loaded from a MagicaVoxel `.vox` file.
+///
+/// MagicaVoxel is z-up, models are turned to be y-up like the terrain when they are loaded, so
+/// `size` is width, height and depth. Only the first model of a file is loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxModel {
+  size: [u32; 3],
+  // position and palette index of every voxel, 0 is never used as it means empty
+  voxels: Vec<([u32; 3], u8)>,
+  palette: Vec<[u8; 4]>,
+}
+
+impl VoxModel {
+  pub fn open(path: impl AsRef<Path>) -> Result<Self, VoxError> {
+    let bytes = std::fs::read(path).map_err(|e| VoxError::Open(e.to_string()))?;
+    Self::parse(&bytes)
+  }
+
+  pub fn parse(bytes: &[u8]) -> Result<Self, VoxError> {
+    let mut reader = Reader(bytes);
+    if reader.take(4)? != b"VOX " {
+      return Err(VoxError::NotVox);
+    }
+    let _version = reader.u32()?;
+
+    // everything is in the children of the MAIN chunk
+    let (id, _, children) = reader.chunk()?;
+    if id != b"MAIN" {
+      return Err(VoxError::NotVox);
+    }
+
+    let mut reader = Reader(children);
+    let mut size = None;
+    let mut voxels = None;
+    // files without a palette use MagicaVoxel's default one
+    let mut palette = default_palette();
+    while !reader.0.is_empty() {
+      let (id, content, _) = reader.chunk()?;
+      let mut content = Reader(content);
+      match id {
+        b"SIZE" if size.is_none() => {
+          size = Some([content.u32()?, content.u32()?, content.u32()?]);
+        }
+        b"XYZI" if voxels.is_none() => {
+          let count = content.u32()? as usize;
+          let mut list = Vec::with_capacity(count);
+          for _ in 0..count {
+            let v = content.take(4)?;
+            list.push(([v[0] as u32, v[1] as u32, v[2] as u32], v[3]));
+          }
+          voxels = Some(list);
+        }
+        b"RGBA" => {
+          // entry i of the chunk is the color of palette index i + 1
+          for color in palette.iter_mut().skip(1) {
+            let c = content.take(4)?;
+            *color = [c[0], c[1], c[2], c[3]];
+          }
+        }
+        _ => {}
+      }
+    }
+
+    let [sx, sy, sz] = size.ok_or(VoxError::MissingModel)?;
+    let voxels = voxels.ok_or(VoxError::MissingModel)?;
+    Ok(Self {
+      size: [sx, sz, sy],
+      // z-up to y-up, flipping the old y axis to keep the model from being mirrored
+      voxels: voxels
+        .into_iter()
+        .filter(|([x, y, z], _)| *x < sx && *y < sy && *z < sz)
+        .map(|([x, y, z], index)| ([x, z, sy - 1 - y], index))
+        .collect(),
+      palette,
+    })
+  }
+
+  /// Width, height and depth in voxels.
+  #[inline]
+  pub fn size(&self) -> [u32; 3] {
+    self.size
+  }
+
+  /// Position and palette index of every filled voxel.
+  #[inline]
+  pub fn voxels(&self) -> &[([u32; 3], u8)] {
+    &self.voxels
+  }
+
+  /// RGBA color of a palette index.
+  #[inline]
+  pub fn color(&self, index: u8) -> [u8; 4] {
+    self.palette[index as usize]
+  }
+}
+
+// MagicaVoxel's palette for files without an RGBA chunk: a 6x6x6 color cube without black, then
+// ramps of red, green, blue and gray. Index 0 is empty
+fn default_palette() -> Vec<[u8; 4]> {
+  const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+  const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+  let mut palette = vec![[0; 4]];
+  for r in CUBE {
+    for g in CUBE {
+      for b in CUBE {
+        palette.push([r, g, b, 0xff]);
+      }
+    }
+  }
+  palette.pop();
+  for channel in 0..4 {
+    for v in RAMP {
+      let mut color = [0, 0, 0, 0xff];
+      match channel {
+        3 => color[..3].fill(v),
+        _ => color[channel] = v,
+      }
+      palette.push(color);
+    }
+  }
+  palette
+}
+
+/// A [`VoxModel`] placed in the world, see
+/// [`VoxelGenerator::with_stamp`](super::VoxelGenerator::with_stamp).
+///
+/// The model's voxels replace the terrain's, empty voxels of the model leave the terrain alone.
+/// Palette indices are mapped to materials, then palette colors, anything not mapped becomes
+/// `default_material`. Mapping to `Air` or `Water` carves out the terrain, for doors and hollow
+/// rooms.
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use voxel_terrain::{VoxModel, VoxStamp, VoxelGenerator, VoxelId, VoxelType};
+///
+/// let house = Arc::new(VoxModel::open("assets/house.vox").unwrap());
+/// let generator = VoxelGenerator::default().with_stamp(
+///   VoxStamp::new(house, VoxelId::new(20, 40, -10))
+///     .rotated(1)
+///     .with_material(12, VoxelType::Air)
+///     .with_color([0x66, 0x33, 0x00, 0xff], VoxelType::Dirt),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct VoxStamp {
+  pub model: Arc<VoxModel>,
+  /// Voxel where the model's minimum corner ends up.
+  pub position: VoxelId,
+  /// Quarter turns around the y axis, the model is turned in place so it still starts at
+  /// `position`.
+  pub quarter_turns: u8,
+  pub materials: HashMap<u8, VoxelType>,
+  /// Materials of palette colors, for indices without a material of their own.
+  pub colors: Vec<([u8; 4], VoxelType)>,
+  /// Colors without an exact match in `colors` take the material of the closest one instead of
+  /// `default_material`.
+  pub nearest_color: bool,
+  pub default_material: VoxelType,
+}
+
+impl VoxStamp {
+  pub fn new(model: Arc<VoxModel>, position: VoxelId) -> Self {
+    Self {
+      model,
+      position,
+      quarter_turns: 0,
+      materials: HashMap::new(),
+      colors: Vec::new(),
+      nearest_color: false,
+      default_material: VoxelType::Dirt,
+    }
+  }
+
+  pub fn rotated(mut self, quarter_turns: u8) -> Self {
+    self.quarter_turns = quarter_turns % 4;
+    self
+  }
+
+  pub fn with_material(mut self, index: u8, material: VoxelType) -> Self {
+    self.materials.insert(index, material);
+    self
+  }
+
+  pub fn with_color(mut self, color: [u8; 4], material: VoxelType) -> Self {
+    self.colors.push((color, material));
+    self
+  }
+
+  pub fn with_nearest_color(mut self) -> Self {
+    self.nearest_color = true;
+    self
+  }
+
+  /// Material a palette index is stamped as.
+  pub fn material(&self, index: u8) -> VoxelType {
+    if let Some(material) = self.materials.get(&index) {
+      return *material;
+    }
+    let color = self.model.color(index);
+    let distance = |(other, _): &&([u8; 4], VoxelType)| -> u32 {
+      (0..4)
+        .map(|i| (color[i] as i32 - other[i] as i32).pow(2) as u32)
+        .sum()
+    };
+    let closest = self.colors.iter().min_by_key(distance);
+    match closest {
+      Some(closest) if self.nearest_color || distance(&closest) == 0 => closest.1,
+      _ => self.default_material,
+    }
+  }
+
+  /// Size of the rotated model.
+  pub fn size(&self) -> [u32; 3] {
+    let [width, height, depth] = self.model.size;
+    if self.quarter_turns % 2 == 0 {
+      [width, height, depth]
+    } else {
+      [depth, height, width]
+    }
+  }
+
+  /// First and last voxel covered by the model.
+  pub fn bounds(&self) -> (VoxelId, VoxelId) {
+    let [width, height, depth] = self.size();
+    let max = VoxelId::new(width as i32 - 1, height as i32 - 1, depth as i32 - 1);
+    (self.position, self.position + max)
+  }
+
+  /// World voxel of a voxel of the model.
+  pub fn voxel(&self, [x, y, z]: [u32; 3]) -> VoxelId {
+    let [width, _, depth] = self.model.size;
+    let (x, z) = match self.quarter_turns % 4 {
+      0 => (x, z),
+      1 => (z, width - 1 - x),
+      2 => (width - 1 - x, depth - 1 - z),
+      _ => (depth - 1 - z, x),
+    };
+    self.position + VoxelId::new(x as i32, y as i32, z as i32)
+  }
+
+  /// Writes the model into voxel data that starts at `origin`, returns false if they don't
+  /// overlap.
+  pub fn stamp(
+    &self,
+    data: &mut ChunkVoxelData,
+    origin: VoxelId,
+    shape: &RuntimeShape<u32, 3>,
+  ) -> bool {
+    let [width, height, depth] = shape.as_array();
+    let end = origin + VoxelId::new(width as i32 - 1, height as i32 - 1, depth as i32 - 1);
+    let (min, max) = self.bounds();
+    if max.x() < origin.x()
+      || max.y() < origin.y()
+      || max.z() < origin.z()
+      || min.x() > end.x()
+      || min.y() > end.y()
+      || min.z() > end.z()
+    {
+      return false;
+    }
+
+    // a lookup per index rather than per voxel
+    let materials: Vec<_> = (0..=255).map(|index| self.material(index)).collect();
+    for (voxel, index) in self.model.voxels() {
+      let local = self.voxel(*voxel) - origin;
+      if local.x() < 0
+        || local.y() < 0
+        || local.z() < 0
+        || local.x() >= width as i32
+        || local.y() >= height as i32
+        || local.z() >= depth as i32
+      {
+        continue;
+      }
+      let material = materials[*index as usize];
+      let i = shape.linearize([local.x() as u32, local.y() as u32, local.z() as u32]);
+      data.set(i as usize, placed_sdf(material), material);
+    }
+    true
+  }
+}
+
+// sdf of a voxel that was placed rather than generated, the surface ends up on the voxel faces
+// like in a blocky mesh
+pub(crate) fn placed_sdf(material: VoxelType) -> f32 {
+  if material.is_solid() {
+    -0.5
+  } else {
+    0.5
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VoxError {
+  Open(String),
+  NotVox,
+  Truncated,
+  MissingModel,
+}
+impl fmt::Display for VoxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      VoxError::Open(error) => write!(f, "could not open vox file: {}", error),
+      VoxError::NotVox => write!(f, "not a MagicaVoxel file"),
+      VoxError::Truncated => write!(f, "vox file ends in the middle of a chunk"),
+      VoxError::MissingModel => write!(f, "vox file has no model"),
+    }
+  }
+}
+impl std::error::Error for VoxError {}
+
+// reads the little endian values .vox files are made of
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+    if self.0.len() < len {
+      return Err(VoxError::Truncated);
+    }
+    let (taken, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(taken)
+  }
+
+  fn u32(&mut self) -> Result<u32, VoxError> {
+    let bytes = self.take(4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  }
+
+  // id, content and children of the next chunk
+  fn chunk(&mut self) -> Result<(&'a [u8], &'a [u8], &'a [u8]), VoxError> {
+    let id = self.take(4)?;
+    let content_len = self.u32()? as usize;
+    let children_len = self.u32()? as usize;
+    Ok((id, self.take(content_len)?, self.take(children_len)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  // a .vox file with a single model, voxels in MagicaVoxel's z-up coordinates
+  fn vox_file(size: [u32; 3], voxels: &[[u8; 4]]) -> Vec<u8> {
+    let mut children = Vec::new();
+    let mut chunk = |id: &[u8; 4], content: Vec<u8>| {
+      children.extend_from_slice(id);
+      children.extend((content.len() as u32).to_le_bytes());
+      children.extend(0u32.to_le_bytes());
+      children.extend(content);
+    };
+    chunk(b"SIZE", size.iter().flat_map(|s| s.to_le_bytes()).collect());
+    let mut xyzi = (voxels.len() as u32).to_le_bytes().to_vec();
+    xyzi.extend(voxels.iter().flatten());
+    chunk(b"XYZI", xyzi);
+
+    let mut file = b"VOX ".to_vec();
+    file.extend(150u32.to_le_bytes());
+    file.extend_from_slice(b"MAIN");
+    file.extend(0u32.to_le_bytes());
+    file.extend((children.len() as u32).to_le_bytes());
+    file.extend(children);
+    file
+  }
+
+  #[test]
+  fn models_should_be_turned_y_up() {
+    let model = VoxModel::parse(&vox_file([2, 3, 4], &[[1, 0, 3, 7]])).unwrap();
+    assert_eq!(model.size(), [2, 4, 3]);
+    assert_eq!(model.voxels(), &[([1, 3, 2], 7)]);
+    assert_eq!(VoxModel::parse(b"VOX "), Err(VoxError::Truncated));
+    assert_eq!(VoxModel::parse(b"RIFF0000"), Err(VoxError::NotVox));
+  }
+
+  #[test]
+  fn files_without_a_palette_should_use_the_default_one() {
+    let model = VoxModel::parse(&vox_file([1, 1, 1], &[[0, 0, 0, 1]])).unwrap();
+    assert_eq!(model.color(0), [0, 0, 0, 0]);
+    assert_eq!(model.color(1), [0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(model.color(2), [0xff, 0xff, 0xcc, 0xff]);
+    assert_eq!(model.color(37), [0xcc, 0xff, 0xff, 0xff]);
+    assert_eq!(model.color(215), [0x00, 0x00, 0x33, 0xff]);
+    assert_eq!(model.color(216), [0xee, 0x00, 0x00, 0xff]);
+    assert_eq!(model.color(226), [0x00, 0xee, 0x00, 0xff]);
+    assert_eq!(model.color(236), [0x00, 0x00, 0xee, 0xff]);
+    assert_eq!(model.color(255), [0x11, 0x11, 0x11, 0xff]);
+  }
+
+  #[test]
+  fn colors_should_map_to_materials() {
+    let model = Arc::new(VoxModel::parse(&vox_file([1, 1, 1], &[[0, 0, 0, 1]])).unwrap());
+    let stamp = VoxStamp::new(model, VoxelId::default())
+      .with_material(2, VoxelType::Water)
+      .with_color([0xff, 0xff, 0xcc, 0xff], VoxelType::Air)
+      .with_color([0xee, 0x00, 0x00, 0xff], VoxelType::Air);
+    // indices come before colors
+    assert_eq!(stamp.material(2), VoxelType::Water);
+    assert_eq!(stamp.material(216), VoxelType::Air);
+    assert_eq!(stamp.material(217), VoxelType::Dirt);
+    let stamp = stamp.with_nearest_color();
+    assert_eq!(stamp.material(217), VoxelType::Air);
+  }
+
+  proptest! {
+      #[test]
+      fn stamped_voxels_should_stay_inside_the_bounds(sx in 1u32..8, sy in 1u32..8, sz in 1u32..8, turns in 0u8..4, x in -30i32..2, y in -30i32..2, z in -30i32..2) {
+          let voxels: Vec<[u8; 4]> = (0..sx * sy * sz)
+              .map(|i| [(i % sx) as u8, (i / sx % sy) as u8, (i / sx / sy) as u8, 1])
+              .collect();
+          let model = Arc::new(VoxModel::parse(&vox_file([sx, sy, sz], &voxels)).unwrap());
+          let stamp = VoxStamp::new(model.clone(), VoxelId::new(x, y, z)).rotated(turns);
+          let (min, max) = stamp.bounds();
+
+          let mut stamped: Vec<_> = model.voxels().iter().map(|(v, _)| stamp.voxel(*v)).collect();
+          for voxel in &stamped {
+              assert!(voxel.x() >= min.x() && voxel.y() >= min.y() && voxel.z() >= min.z());
+              assert!(voxel.x() <= max.x() && voxel.y() <= max.y() && voxel.z() <= max.z());
+          }
+          // a full box rotates onto itself, without any two voxels landing on the same spot
+          stamped.sort_by_key(|v| (v.x(), v.y(), v.z()));
+          stamped.dedup();
+          assert_eq!(stamped.len(), (sx * sy * sz) as usize);
+
+          let shape = RuntimeShape::<u32, 3>::new([40, 40, 40]);
+          let origin = VoxelId::new(-30, -30, -30);
+          let mut data = ChunkVoxelData::new(&vec![1.0; 64000], &vec![VoxelType::Air; 64000]);
+          assert!(stamp.stamp(&mut data, origin, &shape));
+          for voxel in stamped {
+              let local = voxel - origin;
+              let i = shape.linearize([local.x() as u32, local.y() as u32, local.z() as u32]);
+              assert_eq!(data.material(i as usize), VoxelType::Dirt);
+          }
+      }
+  }
+}
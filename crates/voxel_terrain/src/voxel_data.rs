@@ -0,0 +1,392 @@
+use super::generator::VoxelType;
+use bevy::prelude::*;
+use std::fmt;
+
+// sdf values are stored as i8 in 1/SDF_SCALE voxel steps. Meshing only cares about values close
+// to the surface, anything further than 127/SDF_SCALE (~7.9) voxels away saturates to that
+// distance, keeping its sign. -128 is never stored so the range is symmetric. Values closer to
+// the surface than half a step round away from zero instead, so air never turns solid
+const SDF_SCALE: f32 = 16.0;
+// voxels are stored in bricks of consecutive indices, bricks with a single value don't store
+// anything but that value
+const BRICK_SIZE: usize = 64;
+
+#[inline]
+fn quantize(sdf: f32) -> i8 {
+  let value = (sdf * SDF_SCALE)
+    .round()
+    .clamp(i8::MIN as f32 + 1.0, i8::MAX as f32) as i8;
+  if value == 0 && sdf != 0.0 {
+    sdf.signum() as i8
+  } else {
+    value
+  }
+}
+
+#[inline]
+fn dequantize(value: i8) -> f32 {
+  value as f32 / SDF_SCALE
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Brick<T> {
+  Uniform(T),
+  Dense(u32),
+}
+
+#[derive(Clone, Debug, Default)]
+struct BrickArray<T> {
+  len: usize,
+  bricks: Vec<Brick<T>>,
+  data: Vec<T>,
+}
+impl<T: Copy + PartialEq> BrickArray<T> {
+  fn new(values: impl ExactSizeIterator<Item = T>) -> Self {
+    let len = values.len();
+    let mut bricks = Vec::with_capacity(len.div_ceil(BRICK_SIZE));
+    let mut data = Vec::new();
+    let mut brick = Vec::with_capacity(BRICK_SIZE);
+
+    let mut values = values.peekable();
+    while values.peek().is_some() {
+      brick.clear();
+      brick.extend(values.by_ref().take(BRICK_SIZE));
+      if brick.iter().all(|v| *v == brick[0]) {
+        bricks.push(Brick::Uniform(brick[0]));
+      } else {
+        bricks.push(Brick::Dense(data.len() as u32));
+        data.extend_from_slice(&brick);
+      }
+    }
+
+    Self { len, bricks, data }
+  }
+
+  #[inline]
+  fn get(&self, i: usize) -> T {
+    match self.bricks[i / BRICK_SIZE] {
+      Brick::Uniform(value) => value,
+      Brick::Dense(offset) => self.data[offset as usize + i % BRICK_SIZE],
+    }
+  }
+
+  fn set(&mut self, i: usize, value: T) {
+    let brick = i / BRICK_SIZE;
+    match self.bricks[brick] {
+      Brick::Uniform(current) if current == value => {}
+      Brick::Uniform(current) => {
+        // expand the brick, the last one may be shorter than the rest
+        let brick_len = BRICK_SIZE.min(self.len - brick * BRICK_SIZE);
+        let offset = self.data.len();
+        self.data.resize(offset + brick_len, current);
+        self.data[offset + i % BRICK_SIZE] = value;
+        self.bricks[brick] = Brick::Dense(offset as u32);
+      }
+      Brick::Dense(offset) => self.data[offset as usize + i % BRICK_SIZE] = value,
+    }
+  }
+
+  fn decompress_into<U>(&self, out: &mut Vec<U>, f: impl Fn(T) -> U) {
+    out.clear();
+    out.reserve(self.len);
+    for (i, brick) in self.bricks.iter().enumerate() {
+      let brick_len = BRICK_SIZE.min(self.len - i * BRICK_SIZE);
+      match *brick {
+        Brick::Uniform(value) => out.extend(std::iter::repeat(f(value)).take(brick_len)),
+        Brick::Dense(offset) => {
+          let offset = offset as usize;
+          out.extend(self.data[offset..offset + brick_len].iter().map(|v| f(*v)))
+        }
+      }
+    }
+  }
+
+  // every stored value, each uniform brick only once
+  fn values(&self) -> impl Iterator<Item = T> + '_ {
+    let uniform = self.bricks.iter().filter_map(|brick| match brick {
+      Brick::Uniform(value) => Some(*value),
+      Brick::Dense(_) => None,
+    });
+    uniform.chain(self.data.iter().copied())
+  }
+
+  fn heap_size(&self) -> usize {
+    self.bricks.capacity() * std::mem::size_of::<Brick<T>>()
+      + self.data.capacity() * std::mem::size_of::<T>()
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoxelDataError {
+  /// A chunk can't hold more than 256 different materials.
+  PaletteFull,
+  /// A voxel refers to a material that isn't in the palette.
+  BadPaletteIndex(u8),
+}
+impl fmt::Display for VoxelDataError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      VoxelDataError::PaletteFull => write!(f, "more than 256 materials in one chunk"),
+      VoxelDataError::BadPaletteIndex(index) => write!(f, "palette index {} out of range", index),
+    }
+  }
+}
+impl std::error::Error for VoxelDataError {}
+
+// index of a material in the palette, adding it if it isn't there yet
+fn palette_index(palette: &mut Vec<VoxelType>, material: VoxelType) -> Result<u8, VoxelDataError> {
+  if let Some(index) = palette.iter().position(|p| *p == material) {
+    return Ok(index as u8);
+  }
+  let index = u8::try_from(palette.len()).map_err(|_| VoxelDataError::PaletteFull)?;
+  palette.push(material);
+  Ok(index)
+}
+
+// Voxel data for a single chunk: a quantized sdf and a material per voxel. Both are stored in
+// bricks so the large uniform regions above and below the surface cost next to nothing. Random
+// access is O(1), meshing decompresses into a dense buffer first.
+#[derive(Debug, Default, Clone, Component)]
+pub struct ChunkVoxelData {
+  sdf: BrickArray<i8>,
+  materials: BrickArray<u8>,
+  palette: Vec<VoxelType>,
+  min_sdf: f32,
+  max_sdf: f32,
+}
+
+impl ChunkVoxelData {
+  pub fn new(sdf: &[f32], materials: &[VoxelType]) -> Self {
+    assert_eq!(sdf.len(), materials.len());
+    let sdf_range = sdf.iter().fold((f32::MAX, f32::MIN), |(min, max), v| {
+      (min.min(*v), max.max(*v))
+    });
+    // VoxelType has far fewer than 256 variants
+    Self::from_quantized(sdf.iter().map(|v| quantize(*v)), materials, sdf_range)
+      .expect("every material fits in the palette")
+  }
+
+  // voxel data exactly as it was stored somewhere else, see replication
+  pub(crate) fn from_quantized(
+    sdf: impl ExactSizeIterator<Item = i8>,
+    materials: &[VoxelType],
+    (min_sdf, max_sdf): (f32, f32),
+  ) -> Result<Self, VoxelDataError> {
+    assert_eq!(sdf.len(), materials.len());
+
+    let mut palette = Vec::new();
+    let materials = materials
+      .iter()
+      .map(|m| palette_index(&mut palette, *m))
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Self {
+      sdf: BrickArray::new(sdf),
+      materials: BrickArray::new(materials.into_iter()),
+      palette,
+      min_sdf,
+      max_sdf,
+    })
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.sdf.len
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  #[inline]
+  pub fn sdf(&self, i: usize) -> f32 {
+    dequantize(self.sdf.get(i))
+  }
+
+  #[inline]
+  pub(crate) fn quantized_sdf(&self, i: usize) -> i8 {
+    self.sdf.get(i)
+  }
+
+  /// Material of a voxel, see [`ChunkVoxelData::try_material`] for data that may be corrupt.
+  #[inline]
+  pub fn material(&self, i: usize) -> VoxelType {
+    // palette_index never hands out an index past the palette
+    self.palette[self.materials.get(i) as usize]
+  }
+
+  /// Material of a voxel, or an error if its palette index is out of range.
+  #[inline]
+  pub fn try_material(&self, i: usize) -> Result<VoxelType, VoxelDataError> {
+    let index = self.materials.get(i);
+    self
+      .palette
+      .get(index as usize)
+      .copied()
+      .ok_or(VoxelDataError::BadPaletteIndex(index))
+  }
+
+  pub fn set(&mut self, i: usize, sdf: f32, material: VoxelType) {
+    // VoxelType has far fewer than 256 variants
+    let index =
+      palette_index(&mut self.palette, material).expect("every material fits in the palette");
+    self.sdf.set(i, quantize(sdf));
+    self.materials.set(i, index);
+    // the range only ever grows, it's a bound rather than the exact range after edits
+    self.min_sdf = self.min_sdf.min(sdf);
+    self.max_sdf = self.max_sdf.max(sdf);
+  }
+
+  #[inline]
+  pub fn sdf_range(&self) -> (f32, f32) {
+    (self.min_sdf, self.max_sdf)
+  }
+
+  // the chunk is entirely above or below the surface, there is no terrain to mesh. Looks at the
+  // stored values, the meshers never see the range before quantizing
+  pub fn is_uniform(&self) -> bool {
+    let mut values = self.sdf.values();
+    match values.next() {
+      Some(first) => values.all(|v| (v > 0) == (first > 0)),
+      None => true,
+    }
+  }
+
+  #[inline]
+  pub fn contains(&self, material: VoxelType) -> bool {
+    self.palette.contains(&material)
+  }
+
+  pub fn decompress_sdf(&self, out: &mut Vec<f32>) {
+    self.sdf.decompress_into(out, dequantize);
+  }
+
+  pub fn decompress_materials(&self, out: &mut Vec<VoxelType>) {
+    self
+      .materials
+      .decompress_into(out, |m| self.palette[m as usize]);
+  }
+
+  /// Like [`ChunkVoxelData::decompress_materials`], checking every palette index.
+  pub fn try_decompress_materials(&self, out: &mut Vec<VoxelType>) -> Result<(), VoxelDataError> {
+    if let Some(index) = self
+      .materials
+      .values()
+      .max()
+      .filter(|i| *i as usize >= self.palette.len())
+    {
+      return Err(VoxelDataError::BadPaletteIndex(index));
+    }
+    self.decompress_materials(out);
+    Ok(())
+  }
+
+  // bytes used on the heap, for comparing against a dense Vec<f32>
+  pub fn memory_footprint(&self) -> usize {
+    self.sdf.heap_size()
+      + self.materials.heap_size()
+      + self.palette.capacity() * std::mem::size_of::<VoxelType>()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  #[test]
+  fn sdf_should_saturate_at_127_steps() {
+    let limit = 127.0 / SDF_SCALE;
+    let data = ChunkVoxelData::new(&[100.0, -100.0, limit, -limit], &[VoxelType::Air; 4]);
+    assert_eq!(data.sdf(0), limit);
+    assert_eq!(data.sdf(1), -limit);
+    assert_eq!(data.sdf(2), limit);
+    assert_eq!(data.sdf(3), -limit);
+    // the range still has the values before quantizing
+    assert_eq!(data.sdf_range(), (-100.0, 100.0));
+  }
+
+  #[test]
+  fn quantizing_should_keep_the_sign() {
+    assert_eq!(quantize(0.0), 0);
+    assert_eq!(quantize(0.001), 1);
+    assert_eq!(quantize(-0.001), -1);
+    assert_eq!(quantize(1.0), SDF_SCALE as i8);
+
+    // the range says there is a surface, the stored values don't
+    let data =
+      ChunkVoxelData::from_quantized([3i8, 5, 1].into_iter(), &[VoxelType::Air; 3], (-1.0, 1.0))
+        .unwrap();
+    assert!(data.is_uniform());
+    let mut data = ChunkVoxelData::new(&[1.0; 100], &[VoxelType::Air; 100]);
+    assert!(data.is_uniform());
+    data.set(80, 0.0, VoxelType::Dirt);
+    assert!(!data.is_uniform());
+    data.set(80, 1.0, VoxelType::Air);
+    assert!(data.is_uniform());
+  }
+
+  #[test]
+  fn bad_palette_indices_should_be_errors() {
+    let mut data = ChunkVoxelData::new(&[1.0; 100], &[VoxelType::Air; 100]);
+    data.set(70, -1.0, VoxelType::Dirt);
+    assert_eq!(data.try_material(70), Ok(VoxelType::Dirt));
+    assert!(data.try_decompress_materials(&mut Vec::new()).is_ok());
+
+    data.materials.set(3, 9);
+    assert_eq!(
+      data.try_material(3),
+      Err(VoxelDataError::BadPaletteIndex(9))
+    );
+    assert_eq!(
+      data.try_decompress_materials(&mut Vec::new()),
+      Err(VoxelDataError::BadPaletteIndex(9))
+    );
+  }
+
+  #[test]
+  fn palette_should_stop_at_256_materials() {
+    let mut palette = vec![VoxelType::Air; 255];
+    assert_eq!(palette_index(&mut palette, VoxelType::Dirt), Ok(255));
+    assert_eq!(palette_index(&mut palette, VoxelType::Dirt), Ok(255));
+    assert_eq!(
+      palette_index(&mut palette, VoxelType::Water),
+      Err(VoxelDataError::PaletteFull)
+    );
+  }
+
+  proptest! {
+      #[test]
+      fn compressed_voxels_should_round_trip(heights in prop::collection::vec(-5.0f32..20.0, 1..40), len in 1usize..2000) {
+          let sdf: Vec<f32> = (0..len).map(|i| (i % 16) as f32 - heights[i % heights.len()]).collect();
+          let materials: Vec<VoxelType> = sdf.iter().map(|v| if *v <= 0.0 { VoxelType::Dirt } else { VoxelType::Air }).collect();
+          let data = ChunkVoxelData::new(&sdf, &materials);
+
+          let mut decompressed = Vec::new();
+          data.decompress_sdf(&mut decompressed);
+          assert_eq!(decompressed.len(), len);
+          for i in 0..len {
+              assert_eq!(data.sdf(i), decompressed[i]);
+              assert!((data.sdf(i) - sdf[i].clamp(-127.0 / SDF_SCALE, 127.0 / SDF_SCALE)).abs() <= 0.5 / SDF_SCALE + 0.001, "index {}: {} vs {}", i, data.sdf(i), sdf[i]);
+              assert_eq!(data.material(i), materials[i]);
+          }
+      }
+
+      #[test]
+      fn edits_should_only_change_the_edited_voxel(len in 1usize..2000, index in 0usize..2000, sdf in -7.0f32..7.0) {
+          let index = index % len;
+          let mut data = ChunkVoxelData::new(&vec![1.0; len], &vec![VoxelType::Air; len]);
+          data.set(index, sdf, VoxelType::Dirt);
+          for i in 0..len {
+              if i == index {
+                  assert_eq!(data.sdf(i), dequantize(quantize(sdf)));
+                  assert_eq!(data.material(i), VoxelType::Dirt);
+              } else {
+                  assert_eq!(data.sdf(i), 1.0);
+                  assert_eq!(data.material(i), VoxelType::Air);
+              }
+          }
+      }
+  }
+}
@@ -0,0 +1,68 @@
+use bevy::prelude::*;
+use voxel_terrain::{
+  ChunkId, ChunkLayout, ChunkMesh, ChunkMeshed, ChunkSpawner, CubicVoxelLayout, ExportTerrain,
+  MesherScratch, VoxelGenerator, VoxelTerrainCorePlugin,
+};
+
+// cargo run --example headless -- terrain.gltf
+// exports the loaded terrain to an .obj or .gltf file once everything is meshed
+fn main() {
+  let export_path = std::env::args().nth(1);
+
+  // plain rust: generate and mesh a single chunk without an app
+  let layout = CubicVoxelLayout::default();
+  let generator = VoxelGenerator::default();
+  let chunk = ChunkId::new(0, 0);
+  let voxels = generator.generate(layout.get_origin(&chunk), layout.shape());
+  let mesh = MesherScratch::with(|scratch| scratch.greedy_mesh(&voxels, layout.shape()));
+  println!(
+    "chunk {:?}: {} vertices, {} triangles",
+    chunk,
+    mesh.positions.len(),
+    mesh.indices.len() / 3
+  );
+
+  // the streaming pipeline, without a window or gpu
+  let mut app = App::new();
+  app
+    .add_plugins(MinimalPlugins)
+    .add_plugin(VoxelTerrainCorePlugin::default())
+    .add_startup_system(setup);
+
+  // 81 chunks for the default load radius of 4, give up after about half a minute
+  for frame in 0..2000 {
+    app.update();
+    std::thread::sleep(std::time::Duration::from_millis(16));
+
+    let world = &mut app.world;
+    let meshed = world.query::<&ChunkMeshed>().iter(world).count();
+    let vertices: usize = world
+      .query::<&ChunkMesh>()
+      .iter(world)
+      .map(|mesh| mesh.0.positions.len())
+      .sum();
+    if frame % 60 == 0 || meshed == 81 {
+      println!(
+        "frame {}: {} chunks meshed, {} vertices",
+        frame, meshed, vertices
+      );
+    }
+    if meshed == 81 {
+      if let Some(path) = &export_path {
+        let exported = ExportTerrain::<CubicVoxelLayout>::new(path)
+          .welded()
+          .run(world)
+          .expect("could not export the terrain");
+        println!("exported {} chunks to {}", exported, path);
+      }
+      break;
+    }
+  }
+}
+
+fn setup(mut commands: Commands) {
+  commands
+    .spawn()
+    .insert(Transform::default())
+    .insert(ChunkSpawner::default());
+}
@@ -0,0 +1,61 @@
+use bevy::prelude::*;
+use voxel_terrain::{
+  ChunkSpawner, LayoutSettings, MeshStyle, PlanetSettings, VolumeVoxelLayout, VoxelGenerator,
+  VoxelTerrainPlugin, VoxelTerrainSettings,
+};
+
+fn main() {
+  let settings = VoxelTerrainSettings {
+    layout: LayoutSettings {
+      chunk_voxel_length: 16,
+      ..Default::default()
+    },
+    load_radius: 5,
+    unload_radius: 10,
+    mesh_style: MeshStyle::Smooth,
+    floating_origin_threshold: Some(8),
+    ..Default::default()
+  };
+
+  App::new()
+    .insert_resource(WindowDescriptor {
+      title: "Voxel Planet".to_string(),
+      width: 1920.,
+      height: 1080.,
+      ..Default::default()
+    })
+    .insert_resource(Msaa { samples: 4 })
+    // the plugin only adds a generator if there isn't one already
+    .insert_resource(VoxelGenerator::default().with_planet(PlanetSettings::default()))
+    .add_plugins(DefaultPlugins)
+    .add_plugin(debug::DebugUIPlugin)
+    .add_plugin(VoxelTerrainPlugin::<VolumeVoxelLayout>::new(settings))
+    .add_plugin(camera::SpectatorCameraPlugin)
+    .add_startup_system(setup)
+    .add_system(add_chunk_spawner)
+    .run();
+}
+
+fn setup(mut commands: Commands) {
+  commands.insert_resource(AmbientLight {
+    color: Color::WHITE,
+    brightness: 0.1,
+  });
+
+  // a sun far away, lighting one side of the planet
+  commands.spawn_bundle(DirectionalLightBundle {
+    transform: Transform::from_xyz(1.0, 1.0, 0.5).looking_at(Vec3::ZERO, Vec3::Y),
+    ..default()
+  });
+}
+
+fn add_chunk_spawner(
+  mut commands: Commands,
+  mut qry: Query<(Entity, &mut Transform), (With<camera::SpectatorCamera>, Without<ChunkSpawner>)>,
+) {
+  for (entity, mut transform) in qry.iter_mut() {
+    // start above the north pole, looking down
+    *transform = Transform::from_xyz(0.0, 300.0, 0.0).looking_at(Vec3::ZERO, Vec3::Z);
+    commands.entity(entity).insert(ChunkSpawner::default());
+  }
+}
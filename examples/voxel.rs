@@ -1,5 +1,5 @@
 use bevy::prelude::*;
-use voxel_terrain::{ChunkSpawner, VoxelTerrainPlugin};
+use voxel_terrain::{ChunkSpawner, CubicVoxelLayout, VoxelTerrainPlugin, VoxelTerrainSettings};
 
 fn main() {
   App::new()
@@ -12,7 +12,15 @@ fn main() {
     .insert_resource(Msaa { samples: 4 })
     .add_plugins(DefaultPlugins)
     .add_plugin(debug::DebugUIPlugin)
-    .add_plugin(VoxelTerrainPlugin)
+    .add_plugin(VoxelTerrainPlugin::<CubicVoxelLayout>::new(
+      VoxelTerrainSettings {
+        // the camera can fly far enough for f32 to get jittery
+        floating_origin_threshold: Some(8),
+        // bake skylight and lamps into the meshes
+        lighting: true,
+        ..Default::default()
+      },
+    ))
     //.add_plugin(camera::RtsCameraPlugin)
     .add_plugin(camera::SpectatorCameraPlugin)
     .add_startup_system(setup)
@@ -35,16 +43,6 @@ fn setup(
     transform: Transform::from_xyz(0.0, 20.5, 0.0),
     ..default()
   });
-  // plane
-  commands.spawn_bundle(PbrBundle {
-    mesh: meshes.add(Mesh::from(shape::Plane { size: 100000.0 })),
-    material: materials.add(StandardMaterial {
-      base_color: Color::BLUE,
-      ..default()
-    }),
-    transform: Transform::from_xyz(0.0, 25.5, 0.0),
-    ..default()
-  });
   // light
   commands.spawn_bundle(PointLightBundle {
     point_light: PointLight {